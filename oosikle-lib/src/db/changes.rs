@@ -0,0 +1,526 @@
+use anyhow::Result;
+use rusqlite::hooks::{Action, PreUpdateCase};
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::mem::take;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::miko::Miko;
+
+type SQMiko = Miko<(Connection, Connection)>;
+
+/// Commits that land within this long of each other are published as one batch.
+const COALESCE_WINDOW: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// Every row of `table` touched by `op`. Each row is identified by its values for
+/// `key_columns`, which are the table's primary key, in the same order. For most
+/// tables that's just the row's own uuid. For link tables like `ObjectsInCollections`
+/// it's every column the key is made of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableChange {
+    pub table: String,
+    pub op: ChangeOp,
+    pub key_columns: Vec<String>,
+    pub keys: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    pub changes: Vec<TableChange>,
+}
+
+impl ChangeBatch {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn touches_table(&self, table: &str) -> bool {
+        self.changes.iter().any(|c| c.table.eq_ignore_ascii_case(table))
+    }
+
+    /// Whether any key column of any changed row has `uuid` in it
+    pub fn touches_uuid(&self, uuid: &str) -> bool {
+        self.changes
+            .iter()
+            .flat_map(|c| c.keys.iter().flatten())
+            .any(|u| u.eq_ignore_ascii_case(uuid))
+    }
+}
+
+type ChangeMap = BTreeMap<(String, ChangeOp), BTreeSet<Vec<String>>>;
+
+fn merge_changes(into: &mut ChangeMap, from: ChangeMap) {
+    for (key, rows) in from {
+        into.entry(key).or_default().extend(rows);
+    }
+}
+
+/// A table's primary key columns as `(index, name)`, keyed by the table's name in
+/// lowercase. Tables without a declared primary key go by their first column.
+type KeyColumns = HashMap<String, Vec<(i32, String)>>;
+
+const GET_TABLE_COLUMNS_SQL: &str = "select M.name, P.cid, P.name, P.pk
+from sqlite_master M join pragma_table_info(M.name) P
+where M.type = 'table'
+order by M.name, P.pk, P.cid;";
+
+fn load_key_columns(conn: &Connection) -> rusqlite::Result<KeyColumns> {
+    let mut columns: HashMap<String, Vec<(i32, String, i32)>> = HashMap::new();
+    let mut stmt = conn.prepare(GET_TABLE_COLUMNS_SQL)?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i32>(1)?, r.get::<_, String>(2)?, r.get::<_, i32>(3)?)))?;
+    for row in rows {
+        let (table, cid, name, pk) = row?;
+        columns.entry(table.to_lowercase()).or_default().push((cid, name, pk));
+    }
+    Ok(columns
+        .into_iter()
+        .map(|(table, columns)| {
+            let mut key: Vec<(i32, String)> = columns
+                .iter()
+                .filter(|(_, _, pk)| *pk > 0)
+                .map(|(cid, name, _)| (*cid, name.clone()))
+                .collect();
+            if key.is_empty() {
+                key = columns
+                    .into_iter()
+                    .filter(|(cid, _, _)| *cid == 0)
+                    .map(|(cid, name, _)| (cid, name))
+                    .collect();
+            }
+            (table, key)
+        })
+        .collect())
+}
+
+/// The indexes of `table`'s key columns. Tables made after the feed was attached
+/// aren't known, so they go by their first column.
+fn key_indexes(key_columns: &KeyColumns, table: &str) -> Vec<i32> {
+    match key_columns.get(&table.to_lowercase()) {
+        Some(key) if !key.is_empty() => key.iter().map(|(cid, _)| *cid).collect(),
+        _ => vec![0],
+    }
+}
+
+thread_local! {
+    // The feed hooked onto the writer of the shrine running on this thread, so
+    // `WriterSession` can tell it about savepoints
+    static SHRINE_FEED: RefCell<Option<Arc<FeedState>>> = const { RefCell::new(None) };
+}
+
+impl ChangeBatch {
+    fn from_changes(map: ChangeMap, key_columns: &KeyColumns) -> Self {
+        ChangeBatch {
+            changes: map
+                .into_iter()
+                .map(|((table, op), rows)| TableChange {
+                    key_columns: key_columns
+                        .get(&table.to_lowercase())
+                        .map(|key| key.iter().map(|(_, name)| name.clone()).collect())
+                        .unwrap_or_default(),
+                    table,
+                    op,
+                    keys: rows.into_iter().collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+enum Subscriber {
+    Channel(mpsc::Sender<ChangeBatch>),
+    Async(tokio::sync::mpsc::UnboundedSender<ChangeBatch>),
+    Forward(Box<dyn Fn(ChangeBatch) -> bool + Send>),
+}
+
+impl fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subscriber::Channel(tx) => f.debug_tuple("Channel").field(tx).finish(),
+            Subscriber::Async(tx) => f.debug_tuple("Async").field(tx).finish(),
+            Subscriber::Forward(_) => f.write_str("Forward"),
+        }
+    }
+}
+
+impl Subscriber {
+    /// False once the other end has gone away.
+    fn send(&self, batch: ChangeBatch) -> bool {
+        match self {
+            Subscriber::Channel(tx) => tx.send(batch).is_ok(),
+            Subscriber::Async(tx) => tx.send(batch).is_ok(),
+            Subscriber::Forward(forward) => forward(batch),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PendingChanges {
+    in_transaction: ChangeMap,
+    /// What's been changed since each open savepoint, innermost last
+    savepoints: Vec<(String, ChangeMap)>,
+    committed: ChangeMap,
+}
+
+impl PendingChanges {
+    fn savepoint_index(&self, name: &str) -> Option<usize> {
+        // SQLite goes for the innermost savepoint with the name, ignoring case
+        self.savepoints
+            .iter()
+            .rposition(|(n, _)| n.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Default)]
+struct FeedState {
+    pending: Mutex<PendingChanges>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    /// Filled in when the hooks are installed
+    key_columns: Mutex<KeyColumns>,
+}
+
+impl FeedState {
+    fn record(&self, table: &str, op: ChangeOp, key: Vec<String>) {
+        let mut pending = self.pending.lock().expect("Change buffer was poisoned");
+        let pending = &mut *pending;
+        let changes = match pending.savepoints.last_mut() {
+            Some((_, changes)) => changes,
+            None => &mut pending.in_transaction,
+        };
+        changes.entry((table.to_string(), op)).or_default().insert(key);
+    }
+
+    fn commit(&self) {
+        let mut pending = self.pending.lock().expect("Change buffer was poisoned");
+        let mut changes = take(&mut pending.in_transaction);
+        for (_, since_savepoint) in take(&mut pending.savepoints) {
+            merge_changes(&mut changes, since_savepoint);
+        }
+        merge_changes(&mut pending.committed, changes);
+    }
+
+    fn rollback(&self) {
+        let mut pending = self.pending.lock().expect("Change buffer was poisoned");
+        pending.in_transaction.clear();
+        pending.savepoints.clear();
+    }
+
+    fn open_savepoint(&self, name: &str) {
+        self.pending
+            .lock()
+            .expect("Change buffer was poisoned")
+            .savepoints
+            .push((name.to_string(), ChangeMap::new()));
+    }
+
+    /// Hands everything since the savepoint (and any inside it) to whatever encloses it
+    fn release_savepoint(&self, name: &str) {
+        let mut pending = self.pending.lock().expect("Change buffer was poisoned");
+        let Some(i) = pending.savepoint_index(name) else {
+            return;
+        };
+        let mut changes = ChangeMap::new();
+        for (_, since_savepoint) in pending.savepoints.drain(i..) {
+            merge_changes(&mut changes, since_savepoint);
+        }
+        let pending = &mut *pending;
+        let outer = match pending.savepoints.last_mut() {
+            Some((_, outer)) => outer,
+            None => &mut pending.in_transaction,
+        };
+        merge_changes(outer, changes);
+    }
+
+    /// Forgets everything since the savepoint, which stays open like it does in SQLite
+    fn rollback_to_savepoint(&self, name: &str) {
+        let mut pending = self.pending.lock().expect("Change buffer was poisoned");
+        let Some(i) = pending.savepoint_index(name) else {
+            return;
+        };
+        pending.savepoints.truncate(i + 1);
+        pending.savepoints[i].1.clear();
+    }
+
+    fn publish(&self) {
+        let batch = ChangeBatch::from_changes(
+            take(&mut self.pending.lock().expect("Change buffer was poisoned").committed),
+            &self.key_columns.lock().expect("Key column list was poisoned"),
+        );
+        if batch.is_empty() {
+            return;
+        }
+        self.subscribers
+            .lock()
+            .expect("Subscriber list was poisoned")
+            .retain(|s| s.send(batch.clone()));
+    }
+}
+
+fn key_to_string(value: rusqlite::Result<ValueRef>) -> Option<String> {
+    match value.ok()? {
+        ValueRef::Text(t) => Some(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Integer(i) => Some(i.to_string()),
+        ValueRef::Blob(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        ValueRef::Real(_) | ValueRef::Null => None,
+    }
+}
+
+fn with_shrine_feed(f: impl FnOnce(&FeedState)) {
+    SHRINE_FEED.with_borrow(|feed| {
+        if let Some(feed) = feed {
+            f(feed)
+        }
+    })
+}
+
+// SQLite's hooks don't say anything about savepoints, so `WriterSession` calls
+// these from the shrine's thread as it opens and closes them
+
+pub(crate) fn savepoint_opened(name: &str) {
+    with_shrine_feed(|feed| feed.open_savepoint(name))
+}
+
+pub(crate) fn savepoint_released(name: &str) {
+    with_shrine_feed(|feed| feed.release_savepoint(name))
+}
+
+pub(crate) fn rolled_back_to_savepoint(name: &str) {
+    with_shrine_feed(|feed| feed.rollback_to_savepoint(name))
+}
+
+fn install_hooks(conn: &Connection, state: Arc<FeedState>, wake: mpsc::Sender<()>) -> Result<()> {
+    let key_columns = load_key_columns(conn)?;
+    *state.key_columns.lock().expect("Key column list was poisoned") = key_columns.clone();
+    SHRINE_FEED.set(Some(state.clone()));
+    let update_state = state.clone();
+    conn.preupdate_hook(Some(
+        move |_action: Action, _db: &str, table: &str, case: &PreUpdateCase| {
+            let indexes = key_indexes(&key_columns, table);
+            match case {
+                PreUpdateCase::Insert(new) => {
+                    let key = indexes.iter().map(|&i| key_to_string(new.get_new_column_value(i)));
+                    if let Some(key) = key.collect::<Option<Vec<String>>>() {
+                        update_state.record(table, ChangeOp::Insert, key);
+                    }
+                }
+                PreUpdateCase::Delete(old) => {
+                    let key = indexes.iter().map(|&i| key_to_string(old.get_old_column_value(i)));
+                    if let Some(key) = key.collect::<Option<Vec<String>>>() {
+                        update_state.record(table, ChangeOp::Delete, key);
+                    }
+                }
+                PreUpdateCase::Update {
+                    old_value_accessor,
+                    new_value_accessor,
+                } => {
+                    // Both ends, in case the key itself was changed
+                    let old_key = indexes
+                        .iter()
+                        .map(|&i| key_to_string(old_value_accessor.get_old_column_value(i)))
+                        .collect::<Option<Vec<String>>>();
+                    let new_key = indexes
+                        .iter()
+                        .map(|&i| key_to_string(new_value_accessor.get_new_column_value(i)))
+                        .collect::<Option<Vec<String>>>();
+                    for key in [old_key, new_key].into_iter().flatten() {
+                        update_state.record(table, ChangeOp::Update, key);
+                    }
+                }
+                PreUpdateCase::Unknown => {}
+            }
+        },
+    ));
+    let commit_state = state.clone();
+    conn.commit_hook(Some(move || {
+        commit_state.commit();
+        let _ = wake.send(());
+        // Returning true would turn the commit into a rollback
+        false
+    }));
+    conn.rollback_hook(Some(move || state.rollback()));
+    Ok(())
+}
+
+/// Publishes what the writer connection changes, once it's committed. Rows undone
+/// by rolling back to a savepoint are left out, as long as the savepoint went
+/// through `WriterSession`. SQLite doesn't tell us about ones made with raw SQL.
+///
+/// A connection only has one set of hooks, so attach one feed per shrine and
+/// clone it for everyone who needs to subscribe.
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    state: Arc<FeedState>,
+}
+
+impl ChangeFeed {
+    pub fn attach(miko: &SQMiko) -> Result<ChangeFeed> {
+        let state = Arc::new(FeedState::default());
+        let (wake_tx, wake_rx) = mpsc::channel::<()>();
+        let hook_state = state.clone();
+        miko.send_messenger(move |(_, conn)| install_hooks(conn, hook_state, wake_tx))?;
+        // Lives as long as the hooks do, since they hold the other end of wake_rx
+        let flush_state = state.clone();
+        thread::Builder::new()
+            .name("oosikle_change_feed".into())
+            .spawn(move || {
+                for () in wake_rx.iter() {
+                    while wake_rx.recv_timeout(COALESCE_WINDOW).is_ok() {}
+                    flush_state.publish();
+                }
+            })?;
+        Ok(ChangeFeed { state })
+    }
+
+    pub fn subscribe(&self) -> mpsc::Receiver<ChangeBatch> {
+        let (tx, rx) = mpsc::channel();
+        self.state
+            .subscribers
+            .lock()
+            .expect("Subscriber list was poisoned")
+            .push(Subscriber::Channel(tx));
+        rx
+    }
+
+    /// Hands each batch to `forward`, which says whether whoever it passes them on
+    /// to is still listening. For mixing batches in with other messages.
+    pub fn subscribe_with(&self, forward: impl Fn(ChangeBatch) -> bool + Send + 'static) {
+        self.state
+            .subscribers
+            .lock()
+            .expect("Subscriber list was poisoned")
+            .push(Subscriber::Forward(Box::new(forward)));
+    }
+
+    /// For async consumers. `recv().await` on the receiver yields each batch.
+    pub fn subscribe_async(&self) -> tokio::sync::mpsc::UnboundedReceiver<ChangeBatch> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        self.state
+            .subscribers
+            .lock()
+            .expect("Subscriber list was poisoned")
+            .push(Subscriber::Async(tx));
+        rx
+    }
+}
+
+#[cfg(test)]
+mod change_feed_tests {
+    use super::*;
+    use crate::db::transaction::with_transaction;
+    use crate::db::{MediaCategoryRecord, ObjectInCollection};
+    use crate::miko::ShrineDestroyer;
+    use exemplar::Model;
+
+    static TESTING_VALUES: &'static str = include_str!("../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("./init_db.sql");
+
+    fn init(dbname: &str) -> Result<(SQMiko, ChangeFeed, ShrineDestroyer)> {
+        let (miko, destroyer) = Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let feed = ChangeFeed::attach(&miko)?;
+        Ok((miko, feed, destroyer))
+    }
+
+    fn category(id: &str) -> MediaCategoryRecord {
+        MediaCategoryRecord {
+            media_category_id: id.into(),
+            media_category_string_key: format!("media_category_{}", id),
+        }
+    }
+
+    #[test]
+    fn separate_commits_coalesce_into_one_batch() -> Result<()> {
+        let (miko, feed, _d) = init("changes_coalesce")?;
+        let rx = feed.subscribe();
+        miko.send_messenger(|(_, conn)| {
+            category("first").insert(conn)?;
+            category("second").insert(conn)?;
+            Ok(())
+        })?;
+        let batch = rx.recv_timeout(Duration::from_secs(5))?;
+        assert_eq!(
+            batch.changes,
+            vec![TableChange {
+                table: "MediaCategories".into(),
+                op: ChangeOp::Insert,
+                key_columns: vec!["media_category_id".into()],
+                keys: vec![vec!["first".into()], vec!["second".into()]],
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn collection_edits_name_the_collection() -> Result<()> {
+        let (miko, feed, _d) = init("changes_collection")?;
+        let rx = feed.subscribe();
+        miko.send_messenger(|(_, conn)| {
+            ObjectInCollection {
+                collection_uuid: "BADC0FFEE0DDF00DBADC0FFEE0DDF00D".into(),
+                index_in_collection: 1,
+                object_uuid: "DEADBEEF100000000000000000000001".into(),
+            }
+            .insert(conn)?;
+            Ok(())
+        })?;
+        let batch = rx.recv_timeout(Duration::from_secs(5))?;
+        assert!(batch.touches_table("objectsincollections"));
+        assert!(batch.touches_uuid("BADC0FFEE0DDF00DBADC0FFEE0DDF00D"));
+        let change = &batch.changes[0];
+        assert_eq!(change.key_columns, vec!["collection_uuid", "index_in_collection"]);
+        assert_eq!(change.keys, vec![vec!["BADC0FFEE0DDF00DBADC0FFEE0DDF00D".to_string(), "1".into()]]);
+        Ok(())
+    }
+
+    #[test]
+    fn rolled_back_changes_are_not_published() -> Result<()> {
+        let (miko, feed, _d) = init("changes_rollback")?;
+        let rx = feed.subscribe();
+        let _ = with_transaction(&miko, |session| {
+            session.run(|conn| Ok(category("never").insert(conn)?))?;
+            Err::<(), _>(anyhow::anyhow!("Abandon the transaction"))
+        });
+        miko.send_messenger(|(_, conn)| Ok(category("later").insert(conn)?))?;
+        let batch = rx.recv_timeout(Duration::from_secs(5))?;
+        assert!(batch.touches_uuid("later"));
+        assert!(!batch.touches_uuid("never"));
+        Ok(())
+    }
+
+    #[test]
+    fn savepoint_rollbacks_are_not_published() -> Result<()> {
+        let (miko, feed, _d) = init("changes_savepoints")?;
+        let rx = feed.subscribe();
+        with_transaction(&miko, |session| {
+            session.run(|conn| Ok(category("kept").insert(conn)?))?;
+            session.savepoint("outer")?;
+            session.run(|conn| Ok(category("released").insert(conn)?))?;
+            session.savepoint("inner")?;
+            session.run(|conn| Ok(category("undone").insert(conn)?))?;
+            session.rollback_to_savepoint("inner")?;
+            session.release_savepoint("inner")?;
+            session.release_savepoint("outer")?;
+            Ok(())
+        })?;
+        let batch = rx.recv_timeout(Duration::from_secs(5))?;
+        assert!(batch.touches_uuid("kept"));
+        assert!(batch.touches_uuid("released"));
+        assert!(!batch.touches_uuid("undone"));
+        Ok(())
+    }
+}
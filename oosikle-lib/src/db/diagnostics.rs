@@ -0,0 +1,181 @@
+use rusqlite::trace::{TraceEvent, TraceEventCodes};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::mem::take;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many slow statements `recent_slow_statements` remembers, and how many a
+/// single messenger can queue up for explaining.
+const SLOW_STATEMENT_HISTORY: usize = 64;
+
+// SQLite's trace callback is a plain fn pointer, so the default has to be global.
+// Each shrine runs on a thread of its own, which is where it can override it.
+static SLOW_STATEMENT_THRESHOLD_MICROS: AtomicU64 = AtomicU64::new(100_000);
+static RECENT_SLOW_STATEMENTS: Mutex<VecDeque<SlowStatement>> = Mutex::new(VecDeque::new());
+
+thread_local! {
+    // Filled from inside SQLite's callback, where we can't run queries ourselves
+    static UNEXPLAINED: RefCell<Vec<(String, Duration)>> = const { RefCell::new(Vec::new()) };
+    static SHRINE_THRESHOLD: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlowStatement {
+    pub sql: String,
+    pub elapsed: Duration,
+    /// The `detail` column of `explain query plan`, one entry per step
+    pub query_plan: Vec<String>,
+}
+
+/// The threshold for every shrine that hasn't set its own
+pub fn set_slow_statement_threshold(threshold: Duration) {
+    SLOW_STATEMENT_THRESHOLD_MICROS.store(threshold.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Overrides the threshold for the shrine running on this thread, so call it from
+/// a messenger. `None` goes back to the default.
+pub fn set_shrine_slow_statement_threshold(threshold: Option<Duration>) {
+    SHRINE_THRESHOLD.set(threshold);
+}
+
+/// What counts as slow on this thread
+pub fn slow_statement_threshold() -> Duration {
+    SHRINE_THRESHOLD.get().unwrap_or_else(|| {
+        Duration::from_micros(SLOW_STATEMENT_THRESHOLD_MICROS.load(Ordering::Relaxed))
+    })
+}
+
+pub fn recent_slow_statements() -> Vec<SlowStatement> {
+    RECENT_SLOW_STATEMENTS
+        .lock()
+        .expect("Slow statement log was poisoned")
+        .iter()
+        .cloned()
+        .collect()
+}
+
+fn note_statement(event: TraceEvent<'_>) {
+    if let TraceEvent::Profile(stmt, elapsed) = event {
+        if elapsed < slow_statement_threshold() {
+            return;
+        }
+        let sql = stmt.sql();
+        // Our own explains would otherwise get explained in turn
+        if sql.trim_start().get(..7).is_some_and(|s| s.eq_ignore_ascii_case("explain")) {
+            return;
+        }
+        UNEXPLAINED.with_borrow_mut(|pending| {
+            if pending.len() < SLOW_STATEMENT_HISTORY {
+                pending.push((sql.into_owned(), elapsed));
+            }
+        });
+    }
+}
+
+pub fn watch_for_slow_statements(conn: &Connection) {
+    conn.trace_v2(TraceEventCodes::SQLITE_TRACE_PROFILE, Some(note_statement));
+}
+
+fn explain_query_plan(conn: &Connection, sql: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("explain query plan {}", sql))?;
+    // Any parameters stay unbound (null), which is fine for planning
+    let mut rows = stmt.raw_query();
+    let mut plan = vec![];
+    while let Some(row) = rows.next()? {
+        plan.push(row.get::<&str, String>("detail")?);
+    }
+    Ok(plan)
+}
+
+/// Explains and logs whatever `watch_for_slow_statements` caught on this thread.
+/// Run it between messengers, once the connection is free again.
+pub fn explain_slow_statements(conn: &Connection) {
+    let pending = UNEXPLAINED.with_borrow_mut(take);
+    for (sql, elapsed) in pending {
+        let query_plan = explain_query_plan(conn, &sql)
+            .unwrap_or_else(|e| vec![format!("(couldn't explain: {})", e)]);
+        tracing::warn!(
+            target: "oosikle::slow_sql",
+            sql = %sql,
+            ?elapsed,
+            plan = %query_plan.join("; "),
+            "Slow statement"
+        );
+        let mut recent = RECENT_SLOW_STATEMENTS
+            .lock()
+            .expect("Slow statement log was poisoned");
+        if recent.len() == SLOW_STATEMENT_HISTORY {
+            recent.pop_front();
+        }
+        recent.push_back(SlowStatement {
+            sql,
+            elapsed,
+            query_plan,
+        });
+    }
+}
+
+#[cfg(test)]
+mod diagnostics_tests {
+    use super::*;
+    use crate::miko::{Miko, ShrineDestroyer};
+    use anyhow::Result;
+
+    static TESTING_VALUES: &'static str = include_str!("../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("./init_db.sql");
+
+    fn init(dbname: &str) -> Result<(Miko<(Connection, Connection)>, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )
+    }
+
+    #[test]
+    fn shrine_stats_count_labelled_messengers() -> Result<()> {
+        let (miko, _d) = init("stats_labels")?;
+        let labelled = miko.with_label("count_files");
+        for _ in 0..3 {
+            labelled.send_messenger(|(_, conn)| {
+                Ok(conn.query_row("select count(*) from Files", [], |r| r.get::<_, i64>(0))?)
+            })?;
+        }
+        let _ = labelled.send_messenger(|_| Err::<(), _>(anyhow::anyhow!("Meant to fail")));
+        // Stats are recorded after the reply goes out, so wait on one more round trip
+        miko.send_messenger(|_| Ok(()))?;
+        let stats = miko.stats();
+        assert!(stats.shrine == "sqlite_prime");
+        let counted = stats.by_label.get("count_files").expect("Label wasn't recorded");
+        assert!(counted.completed == 3);
+        assert!(counted.failed == 1);
+        assert!(stats.overall.count() >= 4);
+        Ok(())
+    }
+
+    #[test]
+    fn slow_statements_get_explained() -> Result<()> {
+        let (miko, _d) = init("slow_statements")?;
+        // Only this shrine's thread sees it, so other tests aren't thrown off
+        miko.send_messenger(|(_, conn)| {
+            set_shrine_slow_statement_threshold(Some(Duration::ZERO));
+            Ok(conn.query_row(
+                "select count(*) from Files F where F.file_name like 'slow_statement_probe%'",
+                [],
+                |r| r.get::<_, i64>(0),
+            )?)
+        })?;
+        // Aftercare runs after the reply is sent, so give it a round trip to finish
+        miko.send_messenger(|_| Ok(()))?;
+        let caught = recent_slow_statements()
+            .into_iter()
+            .find(|s| s.sql.contains("slow_statement_probe"))
+            .expect("The statement wasn't caught");
+        assert!(!caught.query_plan.is_empty());
+        assert_eq!(slow_statement_threshold(), Duration::from_millis(100));
+        Ok(())
+    }
+}
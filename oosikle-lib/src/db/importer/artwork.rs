@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::db::{FileArtworkRecord, FileRecord};
+
+/// What images count as when their media type hasn't been worked out
+pub const IMAGE_EXTENSIONS: [&str; 10] = ["png", "jpg", "jpeg", "gif", "webp", "bmp", "tif", "tiff", "avif", "jxl"];
+
+/// The media category images are in
+const IMAGE_CATEGORY: &str = "IMAGE";
+
+/// Where to look for an image that goes with a file. Names are compared without
+/// caring about case, and without the image's extension.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ArtworkPattern {
+    /// An image in the same dir with one of these names, like `cover.jpg`. It goes
+    /// with everything in the dir.
+    DirFile { names: Vec<String> },
+    /// An image named after the file, so `game.png` or `game.p8.png` for `game.p8`
+    SameStem,
+    /// An image named after the file in a subdir of its dir, like `boxart/game.png`
+    Subdir { dir: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ArtworkRule {
+    pub pattern: ArtworkPattern,
+    pub role: String,
+}
+
+impl ArtworkRule {
+    pub fn new(pattern: ArtworkPattern, role: &str) -> Self {
+        Self {
+            pattern,
+            role: role.to_string(),
+        }
+    }
+}
+
+/// Which images become which kind of art. Each role gets filled by the first rule
+/// for it that finds an image, so earlier rules win.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ArtworkRules {
+    /// For files without rules of their own category
+    pub default: Vec<ArtworkRule>,
+    /// Keyed by media category id. These replace the defaults rather than adding to them.
+    #[serde(default)]
+    pub by_category: HashMap<String, Vec<ArtworkRule>>,
+}
+
+impl Default for ArtworkRules {
+    fn default() -> Self {
+        Self {
+            default: vec![
+                ArtworkRule::new(ArtworkPattern::SameStem, "cover"),
+                ArtworkRule::new(
+                    ArtworkPattern::DirFile {
+                        names: vec!["cover".into(), "folder".into(), "front".into(), "albumart".into()],
+                    },
+                    "cover",
+                ),
+                ArtworkRule::new(ArtworkPattern::Subdir { dir: "boxart".into() }, "boxart"),
+            ],
+            by_category: HashMap::new(),
+        }
+    }
+}
+
+impl ArtworkRules {
+    pub fn with_category_rules(mut self, category: &str, rules: Vec<ArtworkRule>) -> Self {
+        self.by_category.insert(category.to_uppercase(), rules);
+        self
+    }
+
+    fn rules_for(&self, category: Option<&str>) -> &[ArtworkRule] {
+        category
+            .and_then(|c| self.by_category.get(&c.to_uppercase()))
+            .unwrap_or(&self.default)
+    }
+}
+
+fn category_of<'a>(record: &FileRecord, categories: &'a HashMap<String, String>) -> Option<&'a str> {
+    record
+        .media_type_id()
+        .and_then(|t| categories.get(&t.to_uppercase()))
+        .map(|c| c.as_str())
+}
+
+fn is_image(record: &FileRecord, categories: &HashMap<String, String>) -> bool {
+    match category_of(record, categories) {
+        Some(category) => category.eq_ignore_ascii_case(IMAGE_CATEGORY),
+        None => record
+            .file_name
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str())),
+    }
+}
+
+/// The image's name minus its extension
+fn image_stem(image: &FileRecord) -> String {
+    let name = image.file_name.to_lowercase();
+    match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+        _ => name,
+    }
+}
+
+/// Every way the file's name can be cut short at a dot, so `game.p8` gives
+/// `game` and `game.p8`
+fn target_stems(target: &FileRecord) -> Vec<String> {
+    let name = target.file_name.to_lowercase();
+    let mut stems: Vec<String> = name
+        .match_indices('.')
+        .filter(|(i, _)| *i > 0)
+        .map(|(i, _)| name[..i].to_string())
+        .collect();
+    stems.push(name);
+    stems
+}
+
+fn matches(pattern: &ArtworkPattern, target: &FileRecord, image: &FileRecord) -> bool {
+    let target_dir = target.file_vfs_path.to_lowercase();
+    let image_dir = image.file_vfs_path.to_lowercase();
+    match pattern {
+        ArtworkPattern::DirFile { names } => {
+            image_dir == target_dir && names.iter().any(|n| n.to_lowercase() == image_stem(image))
+        }
+        ArtworkPattern::SameStem => image_dir == target_dir && target_stems(target).contains(&image_stem(image)),
+        ArtworkPattern::Subdir { dir } => {
+            image_dir == format!("{}{}/", target_dir, dir.trim_matches('/').to_lowercase())
+                && target_stems(target).contains(&image_stem(image))
+        }
+    }
+}
+
+/// Links the images among `records` to the other files they're art for, going by
+/// `rules`. `categories` maps media types to their categories, like
+/// `load_media_categories` gives. Images never get art of their own.
+pub fn associate_artwork(
+    records: &[FileRecord],
+    rules: &ArtworkRules,
+    categories: &HashMap<String, String>,
+) -> Vec<FileArtworkRecord> {
+    let (mut images, targets): (Vec<&FileRecord>, Vec<&FileRecord>) =
+        records.iter().partition(|r| is_image(r, categories));
+    // So the same tree always picks the same image when a few would do
+    images.sort_by(|a, b| (&a.file_vfs_path, &a.file_name).cmp(&(&b.file_vfs_path, &b.file_name)));
+
+    let mut artwork = vec![];
+    for target in targets {
+        let mut filled: Vec<&str> = vec![];
+        for rule in rules.rules_for(category_of(target, categories)) {
+            if filled.contains(&rule.role.as_str()) {
+                continue;
+            }
+            if let Some(image) = images.iter().find(|i| matches(&rule.pattern, target, i)) {
+                filled.push(&rule.role);
+                artwork.push(FileArtworkRecord {
+                    file_uuid: target.file_uuid.clone(),
+                    artwork_file_uuid: image.file_uuid.clone(),
+                    artwork_role: rule.role.clone(),
+                });
+            }
+        }
+    }
+    artwork
+}
+
+#[cfg(test)]
+mod artwork_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use crate::db::FileRecord;
+    use crate::miko::Miko;
+    use anyhow::Result;
+    use rusqlite::Connection;
+    use std::fs;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+
+    #[test]
+    fn art_gets_found_by_convention() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for file in [
+            "pico8/celeste.p8",
+            "pico8/celeste.png",
+            "pico8/jelpi.p8",
+            "snes/earthbound.sfc",
+            "snes/boxart/earthbound.png",
+            "snes/Cover.JPG",
+            "music/track1.flac",
+            "music/folder.jpg",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, file)?;
+        }
+        let (miko, _d) = Miko::construct_connection_shrine(
+            "file:artwork_by_convention?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        let uuid_of = |records: &[FileRecord], name: &str| {
+            records.iter().find(|r| r.file_name == name).unwrap().file_uuid.clone()
+        };
+        let records = container.records().to_vec();
+        let artwork = container.associate_artwork(&ArtworkRules::default(), &HashMap::new()).to_vec();
+        let art_for = |name: &str| {
+            let uuid = uuid_of(&records, name);
+            let mut art: Vec<(String, String)> = artwork
+                .iter()
+                .filter(|a| a.file_uuid == uuid)
+                .map(|a| {
+                    let image = records.iter().find(|r| r.file_uuid == a.artwork_file_uuid).unwrap();
+                    (a.artwork_role.clone(), image.file_name.clone())
+                })
+                .collect();
+            art.sort();
+            art
+        };
+        assert!(art_for("celeste.p8") == vec![("cover".into(), "celeste.png".into())]);
+        assert!(art_for("jelpi.p8").is_empty());
+        assert!(
+            art_for("earthbound.sfc")
+                == vec![("boxart".into(), "earthbound.png".into()), ("cover".into(), "Cover.JPG".into())]
+        );
+        assert!(art_for("track1.flac") == vec![("cover".into(), "folder.jpg".into())]);
+        assert!(art_for("celeste.png").is_empty());
+
+        container.commit_to_db(miko.clone())?;
+        let track = uuid_of(&records, "track1.flac");
+        let cover = miko.send_messenger(move |(conn, _)| {
+            FileRecord::get_from_id(conn, &track)?.unwrap().get_cover_art(conn)
+        })?;
+        assert!(cover.is_some_and(|c| c.artwork_file_uuid == uuid_of(&records, "folder.jpg")));
+        Ok(())
+    }
+
+    #[test]
+    fn categories_can_bring_their_own_rules() {
+        let record = |uuid: &str, name: &str, media_type: Option<&str>| FileRecord {
+            file_uuid: uuid.into(),
+            file_name: name.into(),
+            file_size_bytes: 1,
+            file_hash: String::new(),
+            file_dir_path: "/music/".into(),
+            file_extension_tag: String::new(),
+            file_encoding: String::new(),
+            media_type_override_id: None,
+            file_media_type_id: media_type.map(|t| t.into()),
+            file_deleted: false,
+            file_read_only: false,
+            file_vfs_path: "import/music/".into(),
+        };
+        let records = vec![
+            record("song", "song.flac", Some("FLAC")),
+            record("book", "book.pdf", None),
+            record("back", "back.jpg", None),
+            record("cover", "cover.jpg", None),
+        ];
+        let categories = HashMap::from([("FLAC".to_string(), "AUDIO".to_string())]);
+        let rules = ArtworkRules::default().with_category_rules(
+            "audio",
+            vec![ArtworkRule::new(ArtworkPattern::DirFile { names: vec!["back".into()] }, "back")],
+        );
+        let artwork = associate_artwork(&records, &rules, &categories);
+        let roles = |uuid: &str| -> Vec<(String, String)> {
+            artwork
+                .iter()
+                .filter(|a| a.file_uuid == uuid)
+                .map(|a| (a.artwork_role.clone(), a.artwork_file_uuid.clone()))
+                .collect()
+        };
+        assert!(roles("song") == vec![("back".into(), "back".into())]);
+        assert!(roles("book") == vec![("cover".into(), "cover".into())]);
+    }
+}
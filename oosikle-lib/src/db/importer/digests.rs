@@ -0,0 +1,184 @@
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::hash_file_on_disk;
+
+/// Digests that can be worked out on import alongside blake3
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    Crc32,
+    Md5,
+    Sha1,
+}
+
+impl DigestAlgorithm {
+    pub const ALL: [DigestAlgorithm; 3] = [Self::Crc32, Self::Md5, Self::Sha1];
+
+    /// What goes in `FileDigests.digest_algorithm`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Crc32 => "crc32",
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A digest worked out for a file, in lowercase hex
+pub type FileDigest = (DigestAlgorithm, String);
+
+enum DigestState {
+    Crc32(crc32fast::Hasher),
+    Md5(Md5),
+    Sha1(Sha1),
+}
+
+impl DigestState {
+    fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+            DigestAlgorithm::Md5 => Self::Md5(Md5::new()),
+            DigestAlgorithm::Sha1 => Self::Sha1(Sha1::new()),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        match self {
+            Self::Crc32(h) => h.update(buf),
+            Self::Md5(h) => h.update(buf),
+            Self::Sha1(h) => h.update(buf),
+        }
+    }
+
+    fn finish(self) -> FileDigest {
+        match self {
+            Self::Crc32(h) => (DigestAlgorithm::Crc32, format!("{:08x}", h.finalize())),
+            Self::Md5(h) => (DigestAlgorithm::Md5, format!("{:x}", h.finalize())),
+            Self::Sha1(h) => (DigestAlgorithm::Sha1, format!("{:x}", h.finalize())),
+        }
+    }
+}
+
+/// Feeds everything written to it to blake3 and each of the extra digests, so
+/// they all come out of one read
+pub(super) struct MultiHasher {
+    blake3: blake3::Hasher,
+    extra: Vec<DigestState>,
+}
+
+impl MultiHasher {
+    pub(super) fn new(extra: &[DigestAlgorithm]) -> Self {
+        let mut algorithms = extra.to_vec();
+        algorithms.sort_by_key(|a| a.name());
+        algorithms.dedup();
+        Self {
+            blake3: blake3::Hasher::new(),
+            extra: algorithms.into_iter().map(DigestState::new).collect(),
+        }
+    }
+
+    pub(super) fn finish(self) -> (String, Vec<FileDigest>) {
+        (
+            self.blake3.finalize().to_string(),
+            self.extra.into_iter().map(|d| d.finish()).collect(),
+        )
+    }
+}
+
+impl Write for MultiHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.blake3.update(buf);
+        for digest in &mut self.extra {
+            digest.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The blake3 hash of the file and its `extra` digests. Without any extras this
+/// is just `hash_file_on_disk`, which is quicker at blake3 on its own. The hash
+/// is blank and there are no digests if the file couldn't be read.
+pub(super) fn hash_file_with_digests(path: &Path, extra: &[DigestAlgorithm]) -> (String, Vec<FileDigest>) {
+    if extra.is_empty() {
+        return (hash_file_on_disk(path), vec![]);
+    }
+    let mut hasher = MultiHasher::new(extra);
+    match File::open(path).and_then(|mut f| io::copy(&mut f, &mut hasher)) {
+        Ok(_) => hasher.finish(),
+        Err(_) => ("".to_string(), vec![]),
+    }
+}
+
+#[cfg(test)]
+mod digest_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use crate::db::{FileDigestRecord, FileRecord};
+    use crate::miko::Miko;
+    use anyhow::Result;
+    use std::fs;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+
+    const QUICK_BROWN_FOX: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn digests_match_the_usual_test_vectors() {
+        let mut hasher = MultiHasher::new(&DigestAlgorithm::ALL);
+        hasher.write_all(QUICK_BROWN_FOX).unwrap();
+        let (blake3, digests) = hasher.finish();
+        assert!(blake3 == blake3::hash(QUICK_BROWN_FOX).to_string());
+        assert!(digests.contains(&(DigestAlgorithm::Crc32, "414fa339".to_string())));
+        assert!(digests.contains(&(DigestAlgorithm::Md5, "9e107d9d372bb6826bd81d3542a419d6".to_string())));
+        assert!(digests.contains(&(DigestAlgorithm::Sha1, "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12".to_string())));
+    }
+
+    #[test]
+    fn imported_digests_can_be_matched() -> Result<()> {
+        let (miko, _d) = Miko::construct_connection_shrine(
+            "file:import_digests?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("fox.gb"), QUICK_BROWN_FOX)?;
+        let zip_path = dir.path().join("foxes.zip");
+        let mut zipped = zip::ZipWriter::new(fs::File::create(&zip_path)?);
+        zipped.start_file("inner/fox.gbc", zip::write::SimpleFileOptions::default())?;
+        zipped.write_all(QUICK_BROWN_FOX)?;
+        zipped.finish()?;
+
+        let mut container = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .expanding_archives(true)
+            .with_digests(&[DigestAlgorithm::Crc32, DigestAlgorithm::Sha1])
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        container.commit_to_db(miko.clone())?;
+
+        let (matches, fox_digests) = miko.send_messenger(|(conn, _)| {
+            // DATs have their CRCs in uppercase
+            let matches = FileDigestRecord::find_files_with_digest(conn, "crc32", "414FA339")?;
+            let fox = matches.iter().find(|f| f.file_name == "fox.gb").unwrap();
+            Ok((matches.clone(), fox.get_digests(conn)?))
+        })?;
+        let mut names: Vec<&str> = matches.iter().map(|f: &FileRecord| f.file_name.as_str()).collect();
+        names.sort();
+        assert!(names == vec!["fox.gb", "fox.gbc"]);
+        assert!(fox_digests.len() == 2);
+        assert!(fox_digests.iter().all(|d| d.digest_algorithm != "md5"));
+        Ok(())
+    }
+}
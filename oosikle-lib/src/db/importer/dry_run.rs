@@ -0,0 +1,240 @@
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+
+use super::{resolve_media_types, ExtensionRegistry, InboundFileRecordContainer, SkippedPath, UnresolvedFile};
+use crate::db::FileRecord;
+use crate::miko::Miko;
+use crate::vfs_path::VfsDirPath;
+
+const FIND_FILE_BY_HASH_SQL: &str = "select F.file_uuid, F.file_vfs_path, F.file_name from Files F
+where F.file_hash = ?1 and F.file_deleted = 0 limit 1;";
+
+// Not a `like`, since vfs paths can have `%` and `_` in them. Compared the same
+// way `FacadeFS` does, so a dir only differing in case is a different dir.
+const VFS_DIR_EXISTS_SQL: &str = "select 1 from (
+    select file_vfs_path as vfs_path from Files where file_deleted = 0
+    union all select vfs_dir_path from VfsDirs
+) where substr(vfs_path, 1, length(?1)) = ?1 limit 1;";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PlannedFile {
+    pub file_name: String,
+    pub file_vfs_path: String,
+    pub file_size_bytes: u64,
+    pub file_hash: String,
+    /// Blank when the type couldn't be pinned down, see `unresolved`
+    pub media_type_id: Option<String>,
+}
+
+/// A file in the import with the same contents as one the library already has
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct KnownDuplicate {
+    pub file: PlannedFile,
+    pub existing_file_uuid: String,
+    pub existing_vfs_path: String,
+    pub existing_file_name: String,
+}
+
+/// Everything an import would do, worked out without writing anything. New files
+/// and duplicates between them cover every file in the import.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct DryRunReport {
+    pub import_session_id: String,
+    pub root_dir: PathBuf,
+    pub new_files: Vec<PlannedFile>,
+    pub duplicates: Vec<KnownDuplicate>,
+    pub unresolved: Vec<UnresolvedFile>,
+    pub skipped: Vec<SkippedPath>,
+    /// VFS dirs that nothing in the library is under yet, parents before children
+    pub created_vfs_dirs: Vec<String>,
+    pub total_bytes: u64,
+}
+
+/// Every dir a file's vfs path is under, itself included, in `VfsDirPath` form
+fn vfs_dirs_of(file_vfs_path: &str) -> Vec<String> {
+    let mut dirs = vec![];
+    let mut current = VfsDirPath::parse(file_vfs_path).ok();
+    while let Some(dir) = current.filter(|d| !d.is_root()) {
+        current = dir.parent();
+        dirs.push(dir.into());
+    }
+    dirs
+}
+
+fn planned(record: &FileRecord) -> PlannedFile {
+    PlannedFile {
+        file_name: record.file_name.clone(),
+        file_vfs_path: record.file_vfs_path.clone(),
+        file_size_bytes: record.file_size_bytes,
+        file_hash: record.file_hash.clone(),
+        media_type_id: record.media_type_id().map(str::to_string),
+    }
+}
+
+struct LibraryState {
+    registry: ExtensionRegistry,
+    /// Hash to (uuid, vfs path, name) of a file that already has it
+    known_hashes: HashMap<String, (String, String, String)>,
+    existing_dirs: BTreeSet<String>,
+}
+
+fn load_library_state(conn: &Connection, hashes: Vec<String>, dirs: BTreeSet<String>) -> Result<LibraryState> {
+    let mut by_hash = conn.prepare_cached(FIND_FILE_BY_HASH_SQL)?;
+    let mut known_hashes = HashMap::new();
+    for hash in hashes {
+        if let Some(found) = by_hash
+            .query_row([&hash], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .optional()?
+        {
+            known_hashes.insert(hash, found);
+        }
+    }
+    let mut dir_exists = conn.prepare_cached(VFS_DIR_EXISTS_SQL)?;
+    let mut existing_dirs = BTreeSet::new();
+    for dir in dirs {
+        if dir_exists.exists([&dir])? {
+            existing_dirs.insert(dir);
+        }
+    }
+    Ok(LibraryState {
+        registry: ExtensionRegistry::load(conn)?,
+        known_hashes,
+        existing_dirs,
+    })
+}
+
+impl InboundFileRecordContainer {
+    /// Works out what committing this container would do, media types included:
+    /// whatever `resolve_media_types` came to if it was run, and otherwise what
+    /// committing resolves them to. Only reads from the db, and leaves the
+    /// container as it was, so it can still be adjusted and committed.
+    pub fn dry_run(&self, miko: &Miko<(Connection, Connection)>) -> Result<DryRunReport> {
+        let hashes: Vec<String> = self
+            .records
+            .iter()
+            .filter(|r| !r.file_hash.is_empty())
+            .map(|r| r.file_hash.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let dirs: BTreeSet<String> = self.records.iter().flat_map(|r| vfs_dirs_of(&r.file_vfs_path)).collect();
+        let state = miko.send_messenger(move |(conn, _)| load_library_state(conn, hashes, dirs))?;
+
+        let mut records = self.records.clone();
+        let resolution = match &self.type_resolution {
+            Some(resolution) => resolution.clone(),
+            // Sniffing, same as `commit_with_objects_in`
+            None => resolve_media_types(&mut records, &state.registry, true),
+        };
+        let mut report = DryRunReport {
+            import_session_id: self.import_session_id.clone(),
+            root_dir: self.root_dir.clone(),
+            unresolved: resolution.unresolved,
+            skipped: self.skipped.clone(),
+            ..Default::default()
+        };
+        let mut created = BTreeSet::new();
+        for record in &records {
+            report.total_bytes += record.file_size_bytes;
+            created.extend(
+                vfs_dirs_of(&record.file_vfs_path)
+                    .into_iter()
+                    .filter(|d| !state.existing_dirs.contains(d)),
+            );
+            match state.known_hashes.get(&record.file_hash) {
+                Some((uuid, vfs_path, name)) => report.duplicates.push(KnownDuplicate {
+                    file: planned(record),
+                    existing_file_uuid: uuid.clone(),
+                    existing_vfs_path: vfs_path.clone(),
+                    existing_file_name: name.clone(),
+                }),
+                None => report.new_files.push(planned(record)),
+            }
+        }
+        // Sorted, a parent always comes before what's in it
+        report.created_vfs_dirs = created.into_iter().collect();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod dry_run_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use crate::db::ImportSessionRecord;
+    use std::fs;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+
+    #[test]
+    fn dry_runs_report_without_writing() -> Result<()> {
+        let (miko, _d) = Miko::construct_connection_shrine(
+            "file:import_dry_run?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let first = tempfile::tempdir()?;
+        fs::write(first.path().join("known.foo.txt"), "already in the library")?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(first.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        container.commit_to_db(miko.clone())?;
+
+        let second = tempfile::tempdir()?;
+        fs::create_dir(second.path().join("sub"))?;
+        fs::write(second.path().join("sub/copy.foo.txt"), "already in the library")?;
+        fs::write(second.path().join("new.foo.txt"), "brand new")?;
+        fs::write(second.path().join("mystery.qqq"), "who knows")?;
+        fs::write(second.path().join("Thumbs.db"), "clutter")?;
+        let import_id = make_import_id_with_time()?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(second.path().into())?
+            .construct_container(&import_id)?;
+        container.give_ids_to_records();
+        let before = container.records().to_vec();
+        let report = container.dry_run(&miko)?;
+
+        assert!(container.records() == before.as_slice());
+        let sessions = miko.send_messenger(|(conn, _)| ImportSessionRecord::list_all(conn))?;
+        assert!(sessions.len() == 1);
+
+        let new_names: Vec<&str> = report.new_files.iter().map(|f| f.file_name.as_str()).collect();
+        assert!(new_names.len() == 2);
+        assert!(new_names.contains(&"new.foo.txt") && new_names.contains(&"mystery.qqq"));
+        assert!(report.new_files.iter().any(|f| f.media_type_id == Some("PLAINTEXT".into())));
+        assert!(report.duplicates.len() == 1);
+        assert!(report.duplicates[0].file.file_name == "copy.foo.txt");
+        assert!(report.duplicates[0].existing_file_name == "known.foo.txt");
+        assert!(report.unresolved.len() == 1 && report.unresolved[0].file_name == "mystery.qqq");
+        assert!(report.skipped.len() == 1 && report.skipped[0].path == "Thumbs.db");
+        assert!(report.created_vfs_dirs == vec![format!("{}/", import_id), format!("{}/sub/", import_id)]);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_vfs_dirs_count_and_case_matters() -> Result<()> {
+        let (miko, _d) = Miko::construct_connection_shrine(
+            "file:import_dry_run_vfs_dirs?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(dir.path().join("sub/book.foo.txt"), "a book")?;
+        let import_id = make_import_id_with_time()?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .construct_container(&import_id)?;
+        container.give_ids_to_records();
+        let made = [format!("{}/", import_id), format!("{}/SUB/", import_id)];
+        miko.send_mutating_messenger(move |(_, conn)| {
+            for dir in made {
+                conn.execute("insert into VfsDirs values (?1);", [dir])?;
+            }
+            Ok(())
+        })?;
+        let report = container.dry_run(&miko)?;
+        assert!(report.created_vfs_dirs == vec![format!("{}/sub/", import_id)]);
+        Ok(())
+    }
+}
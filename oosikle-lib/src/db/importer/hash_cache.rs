@@ -0,0 +1,196 @@
+use anyhow::Result;
+use exemplar::Model;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::digests::{hash_file_with_digests, DigestAlgorithm, FileDigest};
+use crate::db::HashCacheRecord;
+
+const LOAD_HASH_CACHE_SQL: &str = "select * from HashCache;";
+
+/// The parts of a file's metadata that change whenever its contents do, near enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    size: u64,
+    modified_nanos: i64,
+    inode: i64,
+}
+
+#[cfg(unix)]
+fn inode_of(metadata: &fs::Metadata) -> i64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino() as i64
+}
+
+#[cfg(not(unix))]
+fn inode_of(_: &fs::Metadata) -> i64 {
+    0
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let since_epoch = metadata.modified().ok()?.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified_nanos: i64::try_from(since_epoch.as_nanos()).ok()?,
+            inode: inode_of(&metadata),
+        })
+    }
+
+    fn matches(&self, record: &HashCacheRecord) -> bool {
+        self.size == record.file_size_bytes
+            && self.modified_nanos == record.file_modified_nanos
+            && self.inode == record.file_inode
+    }
+}
+
+/// Hashes from earlier imports, so files that haven't changed since don't get
+/// read all over again. Loaded once per import, like `ExtensionRegistry`. An empty
+/// one still collects what gets hashed, so it can be saved with the import.
+#[derive(Debug, Clone, Default)]
+pub struct HashCache {
+    // Keyed by canonical path
+    entries: HashMap<String, HashCacheRecord>,
+    verify: bool,
+}
+
+/// How a file's hash came about
+pub(super) struct CachedHash {
+    /// Blank if the file couldn't be read
+    pub(super) hash: String,
+    pub(super) size: u64,
+    pub(super) from_cache: bool,
+    /// Only there when the file was actually read
+    pub(super) digests: Vec<FileDigest>,
+    /// What to save back to the cache, if anything changed
+    pub(super) fresh: Option<HashCacheRecord>,
+}
+
+impl HashCache {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut stmt = conn.prepare_cached(LOAD_HASH_CACHE_SQL)?;
+        let entries = stmt
+            .query_map([], HashCacheRecord::from_row)?
+            .map(|r| r.map(|e| (e.canonical_path.clone(), e)))
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        Ok(Self { entries, verify: false })
+    }
+
+    /// Rehash every file anyway, and warn about any that don't match what was
+    /// cached. For when bit rot or a sneaky edit that kept the mtime is a worry.
+    pub fn verifying(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// `path` should already be canonical. Asking for `extra` digests means reading
+    /// the file whatever the cache says, since only blake3 hashes get cached.
+    pub(super) fn hash(&self, path: &Path, extra: &[DigestAlgorithm]) -> CachedHash {
+        let Some(stamp) = FileStamp::of(path) else {
+            let (hash, digests) = hash_file_with_digests(path, extra);
+            return CachedHash {
+                hash,
+                size: 0,
+                from_cache: false,
+                digests,
+                fresh: None,
+            };
+        };
+        let canonical_path = path.to_string_lossy().to_string();
+        let cached = self.entries.get(&canonical_path).filter(|c| stamp.matches(c));
+        if let Some(cached) = cached {
+            if !self.verify && extra.is_empty() {
+                return CachedHash {
+                    hash: cached.file_hash.clone(),
+                    size: stamp.size,
+                    from_cache: true,
+                    digests: vec![],
+                    fresh: None,
+                };
+            }
+        }
+        let (hash, digests) = hash_file_with_digests(path, extra);
+        if let Some(cached) = cached {
+            if cached.file_hash != hash && !hash.is_empty() {
+                tracing::warn!(file = %canonical_path, "File changed without its size or mtime changing");
+            }
+        }
+        let fresh = (!hash.is_empty() && cached.is_none_or(|c| c.file_hash != hash)).then(|| HashCacheRecord {
+            canonical_path,
+            file_size_bytes: stamp.size,
+            file_modified_nanos: stamp.modified_nanos,
+            file_inode: stamp.inode,
+            file_hash: hash.clone(),
+        });
+        CachedHash {
+            hash,
+            size: stamp.size,
+            from_cache: false,
+            digests,
+            fresh,
+        }
+    }
+}
+
+#[cfg(test)]
+mod hash_cache_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest, ImportJob};
+    use crate::miko::Miko;
+    use std::fs;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+
+    #[test]
+    fn unchanged_files_skip_hashing() -> Result<()> {
+        let (miko, _d) = Miko::construct_connection_shrine(
+            "file:import_hash_cache?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.txt"), "first")?;
+        fs::write(dir.path().join("b.txt"), "second")?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        container.commit_to_db(miko.clone())?;
+
+        // Scribble over the cached hashes, so it's obvious when they get used
+        let cache = miko.send_mutating_messenger(|(_, conn)| {
+            conn.execute("update HashCache set file_hash = 'cached';", [])?;
+            HashCache::load(conn)
+        })?;
+        assert!(cache.len() == 2);
+        let hashes = |cache: &HashCache| -> Result<(Vec<String>, usize)> {
+            let job = ImportJob::default();
+            let container = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+                .construct_container_cached(&make_import_id_with_time()?, &job, cache)?;
+            let hashes = container.records().iter().map(|r| r.file_hash.clone()).collect();
+            Ok((hashes, job.progress().files_from_cache))
+        };
+        assert!(hashes(&cache)? == (vec!["cached".to_string(), "cached".to_string()], 2));
+
+        let (verified, from_cache) = hashes(&cache.clone().verifying(true))?;
+        assert!(from_cache == 0);
+        assert!(verified[0] == blake3::hash(b"first").to_string());
+
+        fs::write(dir.path().join("b.txt"), "changed, and longer")?;
+        let (changed, from_cache) = hashes(&cache)?;
+        assert!(from_cache == 1);
+        assert!(changed[1] == blake3::hash(b"changed, and longer").to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use relative_path::RelativePathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::traversal::{FileIdentity, SymlinkPolicy, TraversalOptions};
+
+/// Same syntax as a `.gitignore`, and like one it only covers its own dir and below
+pub const IGNORE_FILE_NAME: &str = ".oosikleignore";
+
+/// OS clutter, version control and emulator save states, none of which
+/// anybody means to have in their library
+pub const DEFAULT_IGNORE_RULES: [&str; 12] = [
+    IGNORE_FILE_NAME,
+    ".DS_Store",
+    "._*",
+    "Thumbs.db",
+    "desktop.ini",
+    ".git/",
+    ".svn/",
+    ".hg/",
+    "*.state",
+    "*.state[0-9]*",
+    "*.state.auto",
+    "*.ss[0-9]",
+];
+
+/// What decides which files a directory import leaves out. Rules from
+/// `.oosikleignore` files in the tree always apply.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct IgnoreRules {
+    /// Whether `DEFAULT_IGNORE_RULES` apply
+    pub use_defaults: bool,
+    /// Lines for just this import, in the same syntax. They win over every
+    /// other rule, so `!*.state` brings save states back in.
+    pub overrides: Vec<String>,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self {
+            use_defaults: true,
+            overrides: vec![],
+        }
+    }
+}
+
+impl IgnoreRules {
+    pub fn without_defaults(mut self) -> Self {
+        self.use_defaults = false;
+        self
+    }
+
+    pub fn with_override(mut self, line: &str) -> Self {
+        self.overrides.push(line.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SkipReason {
+    #[default]
+    IgnoreRule,
+    /// A link the `SymlinkPolicy` said not to follow
+    Symlink,
+    /// A dir that leads back to one it's inside of
+    Cycle,
+    /// Somewhere already listed under another path
+    DuplicateLink,
+    /// On another filesystem than the root, with `same_filesystem` set
+    OtherFilesystem,
+    /// Couldn't be read at all. `rule` has the error.
+    Unreadable,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SkippedPath {
+    /// Relative to the import's root. Nothing under a skipped dir gets listed.
+    pub path: RelativePathBuf,
+    pub is_dir: bool,
+    #[serde(default)]
+    pub reason: SkipReason,
+    /// The line that matched, as it was written. For anything but an ignore rule,
+    /// what happened in words.
+    pub rule: String,
+    /// The ignore file the rule came from, if it came from one
+    pub rule_file: Option<PathBuf>,
+}
+
+/// Everything `walk_dir` found under a root
+#[derive(Debug, Default)]
+pub(super) struct WalkedDir {
+    pub(super) files: Vec<RelativePathBuf>,
+    pub(super) skipped: Vec<SkippedPath>,
+}
+
+fn build_from_lines<'a>(root: &Path, lines: impl IntoIterator<Item = &'a str>) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for line in lines {
+        builder
+            .add_line(None, line)
+            .map_err(|e| anyhow!("Bad ignore rule {:?}: {}", line, e))?;
+    }
+    Ok(builder.build()?)
+}
+
+/// A broken line in somebody's ignore file shouldn't stop the import, so the
+/// rest of the file still applies and the problem just gets logged
+fn load_ignore_file(dir: &Path) -> Option<Gitignore> {
+    let path = dir.join(IGNORE_FILE_NAME);
+    if !path.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&path) {
+        tracing::warn!(file = %path.display(), "Problem in ignore file: {}", e);
+    }
+    builder
+        .build()
+        .inspect_err(|e| tracing::warn!(file = %path.display(), "Couldn't use ignore file: {}", e))
+        .ok()
+}
+
+struct Walker {
+    root: PathBuf,
+    overrides: Gitignore,
+    defaults: Gitignore,
+    /// One per `.oosikleignore` between the root and the dir being walked, outermost first
+    nested: Vec<Gitignore>,
+    options: TraversalOptions,
+    root_device: Option<u64>,
+    /// The dirs being walked right now, outermost first
+    ancestors: Vec<(FileIdentity, RelativePathBuf)>,
+    walked_dirs: HashMap<FileIdentity, RelativePathBuf>,
+    seen_files: HashMap<FileIdentity, RelativePathBuf>,
+    found: WalkedDir,
+}
+
+fn shown(path: &RelativePathBuf) -> &str {
+    if path.as_str().is_empty() {
+        "."
+    } else {
+        path.as_str()
+    }
+}
+
+impl Walker {
+    /// Overrides first, then the closest ignore file out to the furthest, then the
+    /// defaults. The first set of rules with an opinion decides, so a `!` line in
+    /// a subdir can take back what a parent dir's file ignored.
+    fn skipping_rule(&self, path: &Path, is_dir: bool) -> Option<(String, Option<PathBuf>)> {
+        let layers = std::iter::once(&self.overrides)
+            .chain(self.nested.iter().rev())
+            .chain(std::iter::once(&self.defaults));
+        for layer in layers {
+            match layer.matched(path, is_dir) {
+                Match::None => continue,
+                Match::Whitelist(_) => return None,
+                Match::Ignore(glob) => {
+                    return Some((glob.original().to_string(), glob.from().map(|p| p.to_path_buf())));
+                }
+            }
+        }
+        None
+    }
+
+    fn skip(&mut self, path: RelativePathBuf, is_dir: bool, reason: SkipReason, rule: String) {
+        self.found.skipped.push(SkippedPath {
+            path,
+            is_dir,
+            reason,
+            rule,
+            rule_file: None,
+        });
+    }
+
+    /// Why the links, mounts and inodes involved mean `path` shouldn't be listed
+    fn traversal_problem(
+        &self,
+        identity: Option<FileIdentity>,
+        is_link: bool,
+        is_dir: bool,
+    ) -> Option<(SkipReason, String)> {
+        match self.options.symlinks {
+            SymlinkPolicy::Skip if is_link => {
+                return Some((SkipReason::Symlink, "symlinks are skipped".into()));
+            }
+            SymlinkPolicy::FollowFiles if is_link && is_dir => {
+                return Some((SkipReason::Symlink, "linked dirs aren't followed".into()));
+            }
+            _ => {}
+        }
+        let identity = identity?;
+        if self.options.same_filesystem && self.root_device.is_some_and(|d| d != identity.device) {
+            return Some((SkipReason::OtherFilesystem, "on another filesystem".into()));
+        }
+        if is_dir {
+            if let Some((_, ancestor)) = self.ancestors.iter().find(|(a, _)| *a == identity) {
+                return Some((SkipReason::Cycle, format!("loops back to {}", shown(ancestor))));
+            }
+            if let Some(first) = self.walked_dirs.get(&identity) {
+                return Some((SkipReason::DuplicateLink, format!("same dir as {}", shown(first))));
+            }
+        } else if self.options.collapse_hardlinks {
+            if let Some(first) = self.seen_files.get(&identity) {
+                return Some((SkipReason::DuplicateLink, format!("same file as {}", shown(first))));
+            }
+        }
+        None
+    }
+
+    fn walk(&mut self, dir: &Path, relative_dir: RelativePathBuf) -> Result<()> {
+        let listing = match fs::read_dir(dir) {
+            Ok(listing) => listing,
+            // Without the root there's nothing to import at all
+            Err(e) if relative_dir.as_str().is_empty() => return Err(e.into()),
+            Err(e) => {
+                self.skip(relative_dir, true, SkipReason::Unreadable, e.to_string());
+                return Ok(());
+            }
+        };
+        let mut entries = vec![];
+        for entry in listing {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.skip(relative_dir.clone(), true, SkipReason::Unreadable, e.to_string());
+                    continue;
+                }
+            };
+            match entry.file_type() {
+                Ok(file_type) => entries.push((file_type, entry)),
+                Err(e) => {
+                    let relative = RelativePathBuf::from_path(entry.path().strip_prefix(&self.root)?)?;
+                    self.skip(relative, false, SkipReason::Unreadable, e.to_string());
+                }
+            }
+        }
+        let own_rules = load_ignore_file(dir);
+        let pushed = own_rules.is_some();
+        if let Some(rules) = own_rules {
+            self.nested.push(rules);
+        }
+        let identity = FileIdentity::of(dir);
+        if let Some(identity) = identity {
+            self.ancestors.push((identity, relative_dir.clone()));
+            self.walked_dirs.insert(identity, relative_dir);
+        }
+        // Real paths go first, so they're the ones that get listed when a link
+        // leads somewhere the walk would have reached anyway
+        entries.sort_by_key(|(file_type, e)| (file_type.is_symlink(), e.file_name()));
+        for (file_type, entry) in entries {
+            let path = entry.path();
+            let is_link = file_type.is_symlink();
+            let (is_dir, is_file) = if is_link {
+                (path.is_dir(), path.is_file())
+            } else {
+                (file_type.is_dir(), file_type.is_file())
+            };
+            // Broken links, sockets and the like
+            if !is_dir && !is_file {
+                continue;
+            }
+            let relative = RelativePathBuf::from_path(path.strip_prefix(&self.root)?)?;
+            if let Some((rule, rule_file)) = self.skipping_rule(&path, is_dir) {
+                self.found.skipped.push(SkippedPath {
+                    path: relative,
+                    is_dir,
+                    reason: SkipReason::IgnoreRule,
+                    rule,
+                    rule_file,
+                });
+                continue;
+            }
+            let entry_identity = FileIdentity::of(&path);
+            if let Some((reason, rule)) = self.traversal_problem(entry_identity, is_link, is_dir) {
+                self.skip(relative, is_dir, reason, rule);
+            } else if is_dir {
+                self.walk(&path, relative)?;
+            } else {
+                if let Some(entry_identity) = entry_identity {
+                    self.seen_files.insert(entry_identity, relative.clone());
+                }
+                self.found.files.push(relative);
+            }
+        }
+        if identity.is_some() {
+            self.ancestors.pop();
+        }
+        if pushed {
+            self.nested.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Lists every file under `root` that the rules and `options` let through, along
+/// with whatever they kept out
+pub(super) fn walk_dir(root: &Path, rules: &IgnoreRules, options: &TraversalOptions) -> Result<WalkedDir> {
+    let defaults = if rules.use_defaults {
+        build_from_lines(root, DEFAULT_IGNORE_RULES)?
+    } else {
+        Gitignore::empty()
+    };
+    let mut walker = Walker {
+        root: root.to_path_buf(),
+        overrides: build_from_lines(root, rules.overrides.iter().map(|l| l.as_str()))?,
+        defaults,
+        nested: vec![],
+        options: *options,
+        root_device: FileIdentity::of(root).map(|i| i.device),
+        ancestors: vec![],
+        walked_dirs: HashMap::new(),
+        seen_files: HashMap::new(),
+        found: WalkedDir::default(),
+    };
+    walker.walk(root, RelativePathBuf::new())?;
+    Ok(walker.found)
+}
+
+#[cfg(test)]
+mod ignore_rule_tests {
+    use super::super::DirImportManifest;
+    use super::*;
+
+    fn make_tree(root: &Path, files: &[&str]) -> Result<()> {
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, file)?;
+        }
+        Ok(())
+    }
+
+    fn names(paths: &[RelativePathBuf]) -> Vec<&str> {
+        paths.iter().map(|p| p.as_str()).collect()
+    }
+
+    #[test]
+    fn defaults_and_ignore_files_keep_clutter_out() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        make_tree(
+            dir.path(),
+            &[
+                ".DS_Store",
+                ".git/HEAD",
+                "snes/Thumbs.db",
+                "snes/earthbound.sfc",
+                "snes/earthbound.state1",
+                "snes/scans/box.png",
+                "snes/scans/keep.png",
+            ],
+        )?;
+        fs::write(dir.path().join("snes").join(IGNORE_FILE_NAME), "scans/\n")?;
+        fs::write(dir.path().join("snes/scans").join(IGNORE_FILE_NAME), "!keep.png\n")?;
+
+        let manifest = DirImportManifest::create_from_dir_on_disk(dir.path().into())?;
+        assert!(names(&manifest.items) == vec!["snes/earthbound.sfc"]);
+        let skipped: Vec<&str> = manifest.skipped.iter().map(|s| s.path.as_str()).collect();
+        assert!(skipped.contains(&".git"));
+        assert!(skipped.contains(&"snes/Thumbs.db"));
+        assert!(skipped.contains(&"snes/earthbound.state1"));
+        // Ignored dirs aren't walked, so their own ignore files never get a say
+        let scans = manifest.skipped.iter().find(|s| s.path == "snes/scans").unwrap();
+        assert!(scans.is_dir);
+        assert!(scans.rule == "scans/");
+        assert!(scans.rule_file == Some(dir.path().join("snes").join(IGNORE_FILE_NAME)));
+        Ok(())
+    }
+
+    #[test]
+    fn overrides_win_over_everything() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        make_tree(dir.path(), &["game.sfc", "game.state", "notes.txt"])?;
+        fs::write(dir.path().join(IGNORE_FILE_NAME), "*.txt\n")?;
+        let rules = IgnoreRules::default()
+            .with_override("!*.state")
+            .with_override("*.sfc");
+        let manifest = DirImportManifest::create_from_dir_on_disk_with(dir.path().into(), &rules)?;
+        assert!(names(&manifest.items) == vec!["game.state"]);
+
+        let everything = DirImportManifest::create_from_dir_on_disk_with(
+            dir.path().into(),
+            &IgnoreRules::default().without_defaults().with_override("!*"),
+        )?;
+        assert!(everything.items.len() == 4);
+        assert!(everything.skipped.is_empty());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs to run as a user that can't read a chmod 000 dir, which root always can"]
+    fn unreadable_dirs_get_skipped() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir()?;
+        make_tree(dir.path(), &["fine.txt", "locked/hidden.txt"])?;
+        let locked = dir.path().join("locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000))?;
+        let readable = fs::read_dir(&locked).is_ok();
+        let manifest = DirImportManifest::create_from_dir_on_disk(dir.path().into());
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755))?;
+        assert!(!readable, "This user can read the locked dir anyway");
+        let manifest = manifest?;
+        assert!(names(&manifest.items).contains(&"fine.txt"));
+        assert!(!names(&manifest.items).contains(&"locked/hidden.txt"));
+        let skipped = manifest
+            .skipped
+            .iter()
+            .find(|s| s.path.as_str() == "locked")
+            .expect("The locked dir wasn't reported");
+        assert!(skipped.is_dir && skipped.reason == SkipReason::Unreadable);
+        assert!(!skipped.rule.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use chardetng::EncodingDetector;
+use infer::MatcherType;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::Path;
+
+use super::FileSource;
+use crate::db::FileRecord;
+
+/// Plenty for magic bytes, and enough text for the encoding guess to settle
+const INSPECT_LENGTH: u64 = 64 * 1024;
+
+/// Extensions that are honest about a file even though they aren't the one
+/// `infer` reports, mostly because the format is a zip or mp4 underneath
+const EQUIVALENT_EXTENSIONS: [(&str, &[&str]); 15] = [
+    ("zip", &["cbz", "jar", "apk", "xpi", "epub", "docx", "xlsx", "pptx", "odt", "ods", "odp", "pk3", "love"]),
+    ("rar", &["cbr"]),
+    ("7z", &["cb7"]),
+    ("gz", &["tgz"]),
+    ("bz2", &["tbz", "tbz2"]),
+    ("xz", &["txz"]),
+    ("jpg", &["jpeg", "jpe", "jfif"]),
+    ("tif", &["tiff"]),
+    ("mp4", &["m4v", "m4a", "m4b", "m4p"]),
+    ("m4a", &["m4b", "mp4"]),
+    ("m4v", &["mp4"]),
+    ("ogg", &["oga", "ogv", "opus", "ogx"]),
+    ("mkv", &["mka", "mks"]),
+    ("mid", &["midi"]),
+    ("aif", &["aiff", "aifc"]),
+];
+
+/// A file whose name says one thing and whose first bytes say another
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ContentMismatch {
+    /// Blank for files that didn't have an id yet when they were inspected
+    pub file_uuid: String,
+    pub file_name: String,
+    pub file_vfs_path: String,
+    pub claimed_extension: String,
+    pub detected_extension: String,
+    pub detected_mime_type: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct InspectionReport {
+    pub inspected: usize,
+    /// Files `infer` recognized the format of
+    pub recognized: usize,
+    /// Files that read as text, and so got a `file_encoding`
+    pub text: usize,
+    pub mismatches: Vec<ContentMismatch>,
+    /// The VFS paths of files that couldn't be opened. Everything else about them
+    /// is left as it was.
+    pub unreadable: Vec<String>,
+}
+
+struct Inspection {
+    format: Option<infer::Type>,
+    encoding: Option<&'static str>,
+}
+
+fn read_head(record: &FileRecord) -> anyhow::Result<(Vec<u8>, bool)> {
+    FileSource::of(record).with_reader(|r| {
+        let mut head = vec![];
+        r.take(INSPECT_LENGTH).read_to_end(&mut head)?;
+        let whole_file = (head.len() as u64) < INSPECT_LENGTH;
+        Ok((head, whole_file))
+    })
+}
+
+/// The name of the encoding as the WHATWG Encoding Standard has it, so it can go
+/// straight back into `encoding_rs::Encoding::for_label`. That standard treats
+/// Latin-1 as windows-1252, which is a superset of it.
+fn detect_encoding(head: &[u8], whole_file: bool) -> Option<&'static str> {
+    if head.is_empty() {
+        return None;
+    }
+    if head.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return Some("UTF-8");
+    }
+    if head.starts_with(&[0xFF, 0xFE]) {
+        return Some("UTF-16LE");
+    }
+    if head.starts_with(&[0xFE, 0xFF]) {
+        return Some("UTF-16BE");
+    }
+    // Nothing else we'd call text has nulls in it
+    if head.contains(&0) {
+        return None;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => return Some("UTF-8"),
+        // Only a character cut in half by the end of the buffer
+        Err(e) if e.error_len().is_none() && !whole_file => return Some("UTF-8"),
+        Err(_) => {}
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(head, whole_file);
+    Some(detector.guess(None, false).name())
+}
+
+fn claimed_extension(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+}
+
+fn extension_fits(claimed: &str, detected: &str) -> bool {
+    claimed == detected
+        || EQUIVALENT_EXTENSIONS
+            .iter()
+            .any(|(ext, equivalents)| *ext == detected && equivalents.contains(&claimed))
+}
+
+fn inspect(head: &[u8], whole_file: bool) -> Inspection {
+    let format = infer::get(head);
+    // infer's text matchers are just looking for `<html` or `#!`, which doesn't stop it being text
+    let could_be_text = format
+        .as_ref()
+        .is_none_or(|f| f.matcher_type() == MatcherType::Text);
+    Inspection {
+        encoding: if could_be_text { detect_encoding(head, whole_file) } else { None },
+        format,
+    }
+}
+
+/// Reads the start of every file to fill in `file_encoding` for the ones that
+/// are text, and to catch the ones whose extension doesn't match what's in them.
+/// Binary files get a blank encoding.
+pub fn inspect_contents(records: &mut [FileRecord]) -> InspectionReport {
+    let mut report = InspectionReport::default();
+    for record in records.iter_mut() {
+        let (head, whole_file) = match read_head(record) {
+            Ok(read) => read,
+            Err(e) => {
+                tracing::warn!(file = %record.file_name, "Couldn't inspect file: {}", e);
+                report.unreadable.push(format!("{}{}", record.file_vfs_path, record.file_name));
+                continue;
+            }
+        };
+        report.inspected += 1;
+        let inspection = inspect(&head, whole_file);
+        record.file_encoding = inspection.encoding.unwrap_or_default().to_string();
+        if inspection.encoding.is_some() {
+            report.text += 1;
+        }
+        let Some(format) = inspection.format else {
+            continue;
+        };
+        report.recognized += 1;
+        if format.matcher_type() == MatcherType::Text {
+            continue;
+        }
+        // Without an extension, the name isn't claiming anything
+        if let Some(claimed) = claimed_extension(&record.file_name) {
+            if !extension_fits(&claimed, format.extension()) {
+                report.mismatches.push(ContentMismatch {
+                    file_uuid: record.file_uuid.clone(),
+                    file_name: record.file_name.clone(),
+                    file_vfs_path: record.file_vfs_path.clone(),
+                    claimed_extension: claimed,
+                    detected_extension: format.extension().to_string(),
+                    detected_mime_type: format.mime_type().to_string(),
+                });
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod inspection_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use anyhow::Result;
+
+    const ENCODINGS_PATH_STR: &str = "./src/testing_data/encodings";
+
+    #[test]
+    fn encodings_get_detected() -> Result<()> {
+        let container = DirImportManifest::create_from_dir_on_disk(ENCODINGS_PATH_STR.into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        // Making the container already inspected everything
+        let report = container.inspection().clone();
+        let encoding_of = |name: &str| {
+            container
+                .records()
+                .iter()
+                .find(|r| r.file_name == name)
+                .map(|r| r.file_encoding.clone())
+                .unwrap()
+        };
+        assert!(encoding_of("utf8.txt") == "UTF-8");
+        assert!(encoding_of("utf16.txt") == "UTF-16LE");
+        assert!(encoding_of("latin1.txt") == "windows-1252");
+        assert!(encoding_of("sjis.txt") == "Shift_JIS");
+        assert!(encoding_of("comic.cbz") == "");
+        assert!(encoding_of("not_a_picture.png") == "");
+        assert!(report.inspected == 6);
+        assert!(report.text == 4);
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_extensions_get_flagged() -> Result<()> {
+        let container = DirImportManifest::create_from_dir_on_disk(ENCODINGS_PATH_STR.into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        let report = container.inspection().clone();
+        // comic.cbz is a zip, but that is what a cbz is supposed to be
+        assert!(report.mismatches.len() == 1);
+        let mismatch = &report.mismatches[0];
+        assert!(mismatch.file_name == "not_a_picture.png");
+        assert!(mismatch.claimed_extension == "png");
+        assert!(mismatch.detected_extension == "pdf");
+        Ok(())
+    }
+}
@@ -0,0 +1,654 @@
+use anyhow::{anyhow, Result};
+use exemplar::Model;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{
+    hash_file_on_disk, load_media_categories, FileSource, ImportJob, InboundFileRecordContainer, NewObject,
+    RejectedObject, ARCHIVE_ENTRY_MARKER,
+};
+use crate::db::{Fetchable1, FileRecord, LibraryLayoutRecord, ObjectRecord};
+use crate::miko::Miko;
+
+type SQMiko = Miko<(Connection, Connection)>;
+
+pub const TEMPLATE_FIELDS: [&str; 9] = ["category", "type", "artist", "album", "genre", "name", "ext", "uuid", "hash"];
+/// Stands in for metadata nobody has filled in yet
+const UNKNOWN_FIELD: &str = "Unknown";
+/// Copies are written under this suffix and only renamed into place once verified
+const PARTIAL_SUFFIX: &str = ".oosikle-partial";
+
+/// ?1 is the library root, and ?2 the same with a separator on the end, so
+/// files sitting right in the root count but `/library-old` doesn't
+const GET_LIBRARY_FILES_SQL: &str = "select F.* from Files F
+where (F.file_dir_path = ?1 or substr(F.file_dir_path, 1, length(?2)) = ?2)
+and instr(F.file_dir_path, ?3) = 0 and F.file_deleted = 0;";
+const UPDATE_FILE_LOCATION_SQL: &str = "update Files set file_dir_path = ?2, file_name = ?3 where file_uuid = ?1;";
+/// Points the entries of a moved archive at its new path. ?1 and ?2 end in the archive marker.
+const UPDATE_ARCHIVE_ENTRIES_SQL: &str = "update Files set file_dir_path = ?2 || substr(file_dir_path, length(?1) + 1)
+where substr(file_dir_path, 1, length(?1)) = ?1;";
+const UPDATE_TEMPLATE_SQL: &str = "update LibraryLayouts set path_template = ?2 where library_root = ?1;";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TransferMode {
+    /// Leaves the original where it was
+    Copy,
+    /// Renames when it can, and otherwise copies, verifies, then deletes the original
+    Move,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Field(String),
+}
+
+/// A parsed `LibraryLayoutRecord::path_template`. Each `/` separated part becomes
+/// a dir, with the last one being the file name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTemplate {
+    components: Vec<Vec<Piece>>,
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let components = template
+            .split('/')
+            .map(|component| {
+                if component.is_empty() || component == "." || component == ".." {
+                    return Err(anyhow!("{:?} has an empty, . or .. part in it", template));
+                }
+                let mut pieces = vec![];
+                let mut rest = component;
+                while let Some(open) = rest.find(['{', '}']) {
+                    if rest[open..].starts_with('}') {
+                        return Err(anyhow!("Unmatched }} in {:?}", template));
+                    }
+                    let close = rest[open..]
+                        .find('}')
+                        .ok_or(anyhow!("Unmatched {{ in {:?}", template))?
+                        + open;
+                    let field = &rest[open + 1..close];
+                    if !TEMPLATE_FIELDS.contains(&field) {
+                        return Err(anyhow!("{{{}}} isn't something a template can use", field));
+                    }
+                    if open > 0 {
+                        pieces.push(Piece::Text(rest[..open].to_string()));
+                    }
+                    pieces.push(Piece::Field(field.to_string()));
+                    rest = &rest[close + 1..];
+                }
+                if !rest.is_empty() {
+                    pieces.push(Piece::Text(rest.to_string()));
+                }
+                Ok(pieces)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { components })
+    }
+
+    fn render(&self, fields: &HashMap<&str, String>) -> PathBuf {
+        self.components
+            .iter()
+            .map(|pieces| {
+                let rendered: String = pieces
+                    .iter()
+                    .map(|p| match p {
+                        Piece::Text(t) => sanitize(t),
+                        Piece::Field(f) => sanitize(fields.get(f.as_str()).map(|v| v.as_str()).unwrap_or("")),
+                    })
+                    .collect();
+                // Trailing dots and spaces are trouble on Windows, and a missing
+                // {ext} would leave one behind
+                let trimmed = rendered.trim().trim_end_matches(['.', ' ']);
+                if trimmed.is_empty() {
+                    UNKNOWN_FIELD.to_string()
+                } else {
+                    trimmed.to_string()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Keeps metadata from adding dirs of its own or using characters some filesystems reject
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn or_unknown(value: &str) -> String {
+    if value.trim().is_empty() {
+        UNKNOWN_FIELD.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn template_fields<'a>(
+    record: &FileRecord,
+    object: Option<&ObjectRecord>,
+    categories: &HashMap<String, String>,
+) -> HashMap<&'a str, String> {
+    let (stem, ext) = record
+        .file_name
+        .split_once('.')
+        .unwrap_or((record.file_name.as_str(), ""));
+    let media_type = record.media_type_id().unwrap_or_default().to_string();
+    let category = categories.get(&media_type.to_uppercase()).cloned().unwrap_or_default();
+    let name = object
+        .map(|o| o.object_name.as_str())
+        .filter(|n| !n.trim().is_empty())
+        .unwrap_or(stem);
+    HashMap::from([
+        ("category", or_unknown(&category)),
+        ("type", or_unknown(&media_type)),
+        ("artist", or_unknown(object.map(|o| o.object_artist.as_str()).unwrap_or(""))),
+        ("album", or_unknown(object.map(|o| o.object_album_name.as_str()).unwrap_or(""))),
+        ("genre", or_unknown(object.map(|o| o.object_genre.as_str()).unwrap_or(""))),
+        ("name", or_unknown(name)),
+        ("ext", ext.to_string()),
+        ("uuid", record.file_uuid.clone()),
+        ("hash", record.file_hash.clone()),
+    ])
+}
+
+/// Where a file went
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Placement {
+    pub file_uuid: String,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct PlacementReport {
+    pub placed: Vec<Placement>,
+    /// Already right where the template wants them
+    pub unmoved: usize,
+    /// Each file's uuid and what went wrong. These keep pointing at where they were.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Adds ` (2)`, ` (3)` and so on before the extension until nothing's in the way
+fn free_path(wanted: PathBuf, taken: &HashSet<PathBuf>) -> PathBuf {
+    let is_free = |p: &Path| !taken.contains(p) && fs::symlink_metadata(p).is_err();
+    if is_free(&wanted) {
+        return wanted;
+    }
+    let file_name = wanted.file_name().unwrap().to_string_lossy().to_string();
+    let (stem, ext) = match file_name.split_once('.') {
+        Some((stem, ext)) => (stem.to_string(), format!(".{}", ext)),
+        None => (file_name.clone(), "".to_string()),
+    };
+    (2..)
+        .map(|n| wanted.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|p| is_free(p))
+        .unwrap()
+}
+
+/// Copies to a partial file next to `to`, checks it hashes to `expected_hash`,
+/// and only then gives it its real name
+fn transfer(from: &Path, to: &Path, expected_hash: &str, mode: TransferMode) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if mode == TransferMode::Move && fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    let partial = PathBuf::from(partial);
+    fs::copy(from, &partial)?;
+    let copied_hash = hash_file_on_disk(&partial);
+    if copied_hash.is_empty() || copied_hash != expected_hash {
+        let _ = fs::remove_file(&partial);
+        return Err(anyhow!("The copy of {} doesn't match the original", from.display()));
+    }
+    fs::rename(&partial, to)?;
+    if mode == TransferMode::Move {
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Clears out dirs under `root` that moving files away from left empty
+fn remove_empty_dirs(root: &Path, dirs: impl IntoIterator<Item = PathBuf>) {
+    for dir in dirs {
+        let mut current = Some(dir.as_path());
+        while let Some(d) = current.filter(|d| d.starts_with(root) && *d != root) {
+            // Fails on anything that still has something in it, which is what stops us
+            if fs::remove_dir(d).is_err() {
+                break;
+            }
+            current = d.parent();
+        }
+    }
+}
+
+fn split_location(path: &Path) -> Result<(String, String)> {
+    let dir = path.parent().and_then(|p| p.to_str());
+    let name = path.file_name().and_then(|n| n.to_str());
+    match (dir, name) {
+        (Some(dir), Some(name)) => Ok((dir.to_string(), name.to_string())),
+        _ => Err(anyhow!("{} isn't a valid unicode file path", path.display())),
+    }
+}
+
+fn archive_prefix(path: &Path) -> String {
+    format!("{}{}", path.to_string_lossy(), ARCHIVE_ENTRY_MARKER)
+}
+
+/// A directory the library keeps its own copies of imported files in, laid out
+/// by a path template built from each file's metadata
+#[derive(Debug, Clone)]
+pub struct ManagedLibrary {
+    pub layout: LibraryLayoutRecord,
+    template: PathTemplate,
+}
+
+impl ManagedLibrary {
+    pub fn create(miko: &SQMiko, root: PathBuf, path_template: &str) -> Result<Self> {
+        let template = PathTemplate::parse(path_template)?;
+        fs::create_dir_all(&root)?;
+        let root = root.canonicalize()?;
+        let layout = LibraryLayoutRecord {
+            library_root: root
+                .to_str()
+                .ok_or(anyhow!("{:?} isn't valid unicode", root))?
+                .to_string(),
+            path_template: path_template.to_string(),
+        };
+        let to_insert = layout.clone();
+        miko.send_mutating_messenger(move |(_, conn)| Ok(to_insert.insert(conn)?))?;
+        Ok(Self { layout, template })
+    }
+
+    pub fn open(miko: &SQMiko, root: &Path) -> Result<Option<Self>> {
+        let root = root.canonicalize()?.to_string_lossy().to_string();
+        let layout = miko.send_messenger(move |(conn, _)| Ok(LibraryLayoutRecord::get_from_id(conn, &root)?))?;
+        layout
+            .map(|layout| {
+                Ok(Self {
+                    template: PathTemplate::parse(&layout.path_template)?,
+                    layout,
+                })
+            })
+            .transpose()
+    }
+
+    pub fn root(&self) -> &Path {
+        Path::new(&self.layout.library_root)
+    }
+
+    /// Gives one of the library's own copies a new name where it sits, and points
+    /// the entries of it at the new name if it's an archive. Files it doesn't
+    /// manage are left alone. Hands back the old and new paths, so whoever's
+    /// changing the db can put it back if that doesn't go through.
+    pub(crate) fn rename_managed_file(
+        &self,
+        conn: &Connection,
+        record: &FileRecord,
+        new_name: &str,
+    ) -> Result<Option<(PathBuf, PathBuf)>> {
+        let dir = Path::new(&record.file_dir_path);
+        if !dir.starts_with(self.root()) || record.file_dir_path.contains(ARCHIVE_ENTRY_MARKER) {
+            return Ok(None);
+        }
+        let from = dir.join(&record.file_name);
+        let to = dir.join(new_name);
+        // Only changing the case finds the file itself on some disks
+        if !new_name.eq_ignore_ascii_case(&record.file_name) && fs::symlink_metadata(&to).is_ok() {
+            return Err(anyhow!("{} is already there", to.display()));
+        }
+        fs::rename(&from, &to)?;
+        let entries_moved = conn
+            .prepare_cached(UPDATE_ARCHIVE_ENTRIES_SQL)
+            .and_then(|mut stmt| stmt.execute(params![archive_prefix(&from), archive_prefix(&to)]));
+        if let Err(e) = entries_moved {
+            let _ = fs::rename(&to, &from);
+            return Err(e.into());
+        }
+        Ok(Some((from, to)))
+    }
+
+    /// Copies or moves each file of an import into the library, before it gets
+    /// committed, and points its record at the new copy. `objects` fill in the
+    /// artist, album and name for the files they were made from. Files inside an
+    /// archive go wherever the archive goes.
+    ///
+    /// Nothing here knows if the commit after goes through, so if it doesn't, the
+    /// report has to go to `unplace`. `place_and_commit` does both. The records
+    /// need their ids first, since where they are is about to change.
+    pub fn place(
+        &self,
+        miko: &SQMiko,
+        container: &mut InboundFileRecordContainer,
+        mode: TransferMode,
+        objects: &[NewObject],
+    ) -> Result<PlacementReport> {
+        if container.records.iter().any(|r| r.file_uuid.is_empty()) {
+            return Err(anyhow!("Files need ids before they can be placed"));
+        }
+        let categories = miko.send_messenger(|(conn, _)| load_media_categories(conn))?;
+        let objects: HashMap<&str, &ObjectRecord> = objects
+            .iter()
+            .map(|o| (o.object.object_uuid.as_str(), &o.object))
+            .collect();
+        let mut report = PlacementReport::default();
+        let mut taken = HashSet::new();
+        let mut moved_archives = vec![];
+        for record in container.records.iter_mut() {
+            let FileSource::OnDisk(from) = FileSource::of(record) else {
+                continue;
+            };
+            let fields = template_fields(record, objects.get(record.file_uuid.as_str()).copied(), &categories);
+            let wanted = self.root().join(self.template.render(&fields));
+            if wanted == from {
+                report.unmoved += 1;
+                taken.insert(wanted);
+                continue;
+            }
+            let to = free_path(wanted, &taken);
+            let expected_hash = if record.file_hash.is_empty() {
+                hash_file_on_disk(&from)
+            } else {
+                record.file_hash.clone()
+            };
+            match transfer(&from, &to, &expected_hash, mode).and_then(|_| split_location(&to)) {
+                Ok((dir, name)) => {
+                    record.file_dir_path = dir;
+                    record.file_name = name;
+                    moved_archives.push((archive_prefix(&from), archive_prefix(&to)));
+                    taken.insert(to.clone());
+                    report.placed.push(Placement {
+                        file_uuid: record.file_uuid.clone(),
+                        from,
+                        to,
+                    });
+                }
+                Err(e) => report.failed.push((record.file_uuid.clone(), e.to_string())),
+            }
+        }
+        for record in container.records.iter_mut() {
+            for (old, new) in &moved_archives {
+                if let Some(inner) = record.file_dir_path.strip_prefix(old.as_str()) {
+                    record.file_dir_path = format!("{}{}", new, inner);
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Puts back what `place` did: copies get deleted and moved files go back
+    /// where they came from. Anything that can't be is logged and left.
+    pub fn unplace(&self, report: &PlacementReport, mode: TransferMode) {
+        for placement in report.placed.iter().rev() {
+            let undone = match mode {
+                TransferMode::Copy => fs::remove_file(&placement.to),
+                TransferMode::Move => fs::rename(&placement.to, &placement.from).or_else(|_| {
+                    fs::copy(&placement.to, &placement.from)?;
+                    fs::remove_file(&placement.to)
+                }),
+            };
+            if let Err(e) = undone {
+                tracing::error!(file = %placement.to.display(), "Couldn't undo placing file: {}", e);
+            }
+        }
+        remove_empty_dirs(
+            self.root(),
+            report.placed.iter().filter_map(|p| p.to.parent().map(|d| d.to_path_buf())),
+        );
+    }
+
+    /// Places the import's files then commits it, and puts the files back if the
+    /// commit doesn't go through
+    pub fn place_and_commit(
+        &self,
+        miko: &SQMiko,
+        mut container: InboundFileRecordContainer,
+        mode: TransferMode,
+        new_objects: Vec<NewObject>,
+        job: &ImportJob,
+    ) -> Result<(PlacementReport, Vec<RejectedObject>)> {
+        let report = self.place(miko, &mut container, mode, &new_objects)?;
+        match container.commit_with_objects_in(miko.clone(), new_objects, job) {
+            Ok(rejected) => Ok((report, rejected)),
+            Err(e) => {
+                self.unplace(&report, mode);
+                Err(e)
+            }
+        }
+    }
+
+    /// Moves everything already in the library to where `path_template` says it
+    /// should go, and keeps the template for later imports. Either every file
+    /// moves and the db follows, or whatever had moved gets put back.
+    pub fn reorganize(&mut self, miko: &SQMiko, path_template: &str) -> Result<PlacementReport> {
+        let template = PathTemplate::parse(path_template)?;
+        let root = self.layout.library_root.clone();
+        let under_root = format!("{}{}", root, std::path::MAIN_SEPARATOR);
+        let (files, categories) = miko.send_messenger(move |(conn, _)| {
+            let mut stmt = conn.prepare_cached(GET_LIBRARY_FILES_SQL)?;
+            let rows = stmt.query_map(params![root, under_root, ARCHIVE_ENTRY_MARKER], FileRecord::from_row)?;
+            let mut files = vec![];
+            for record in rows {
+                let record = record?;
+                let object = ObjectRecord::get_from_id(conn, &record.file_uuid)?;
+                files.push((record, object));
+            }
+            Ok((files, load_media_categories(conn)?))
+        })?;
+
+        let mut report = PlacementReport::default();
+        let mut taken = HashSet::new();
+        let mut plan = vec![];
+        for (record, object) in &files {
+            let from = Path::new(&record.file_dir_path).join(&record.file_name);
+            let wanted = self
+                .root()
+                .join(template.render(&template_fields(record, object.as_ref(), &categories)));
+            if wanted == from {
+                report.unmoved += 1;
+                taken.insert(wanted);
+            } else {
+                plan.push((record, from, wanted));
+            }
+        }
+        let mut done: Vec<Placement> = vec![];
+        let undo = |done: &[Placement]| {
+            for placement in done.iter().rev() {
+                if let Err(e) = fs::rename(&placement.to, &placement.from) {
+                    tracing::error!(file = %placement.to.display(), "Couldn't put file back: {}", e);
+                }
+            }
+        };
+        for (record, from, wanted) in plan {
+            let to = free_path(wanted, &taken);
+            if let Err(e) = transfer(&from, &to, &record.file_hash, TransferMode::Move) {
+                undo(&done);
+                return Err(anyhow!("Couldn't move {}, so nothing was moved: {}", from.display(), e));
+            }
+            taken.insert(to.clone());
+            done.push(Placement {
+                file_uuid: record.file_uuid.clone(),
+                from,
+                to,
+            });
+        }
+
+        let updates = done
+            .iter()
+            .map(|p| {
+                let (dir, name) = split_location(&p.to)?;
+                Ok((p.file_uuid.clone(), dir, name, archive_prefix(&p.from), archive_prefix(&p.to)))
+            })
+            .collect::<Result<Vec<_>>>();
+        let library_root = self.layout.library_root.clone();
+        let new_template = path_template.to_string();
+        let written = updates.and_then(|updates| {
+            miko.background().send_mutating_messenger(move |(_, conn)| {
+                let tx = conn.transaction()?;
+                for (file_uuid, dir, name, old_prefix, new_prefix) in updates {
+                    tx.prepare_cached(UPDATE_FILE_LOCATION_SQL)?
+                        .execute(params![file_uuid, dir, name])?;
+                    tx.prepare_cached(UPDATE_ARCHIVE_ENTRIES_SQL)?
+                        .execute(params![old_prefix, new_prefix])?;
+                }
+                tx.prepare_cached(UPDATE_TEMPLATE_SQL)?
+                    .execute(params![library_root, new_template])?;
+                tx.commit()?;
+                Ok(())
+            })
+        });
+        if let Err(e) = written {
+            undo(&done);
+            return Err(e);
+        }
+
+        remove_empty_dirs(
+            self.root(),
+            done.iter().filter_map(|p| p.from.parent().map(|d| d.to_path_buf())),
+        );
+        self.layout.path_template = path_template.to_string();
+        self.template = template;
+        report.placed = done;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod managed_library_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DigestAlgorithm, DirImportManifest, ExtensionRegistry};
+    use crate::db::FileDigestRecord;
+    use crate::miko::ShrineDestroyer;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+
+    fn init_miko(dbname: &str) -> Result<(SQMiko, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )
+    }
+
+    #[test]
+    fn templates_render_safe_paths() -> Result<()> {
+        let template = PathTemplate::parse("{category}/{artist}/{album}/{name}.{ext}")?;
+        let fields = HashMap::from([
+            ("category", "AUDIO".to_string()),
+            ("artist", "AC/DC".to_string()),
+            ("album", "".to_string()),
+            ("name", "Back in Black".to_string()),
+            ("ext", "".to_string()),
+        ]);
+        let rendered = template.render(&fields);
+        assert!(rendered == PathBuf::from("AUDIO/AC_DC/Unknown/Back in Black"));
+        assert!(PathTemplate::parse("{category}/../{name}").is_err());
+        assert!(PathTemplate::parse("{nope}/{name}").is_err());
+        assert!(PathTemplate::parse("{name").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn imports_get_copied_in_then_reorganized() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let library_dir = tempfile::tempdir()?;
+        fs::write(source.path().join("first.txt"), "one")?;
+        fs::write(source.path().join("second.txt"), "two")?;
+        fs::create_dir(source.path().join("sub"))?;
+        // Same name, so it has to make way for the other one
+        fs::write(source.path().join("sub/first.txt"), "also one")?;
+        let (miko, _d) = init_miko("managed_library")?;
+        let mut library = ManagedLibrary::create(&miko, library_dir.path().into(), "{type}/{name}.{ext}")?;
+        let registry = miko.send_messenger(|(conn, _)| ExtensionRegistry::load(conn))?;
+
+        let mut container = DirImportManifest::create_from_dir_on_disk(source.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records().resolve_media_types(&registry, false);
+        let report = library.place(&miko, &mut container, TransferMode::Copy, &[])?;
+        assert!(report.placed.len() == 3);
+        assert!(report.failed.is_empty());
+        let root = library.root().to_path_buf();
+        // txt could be plain text or a foo doc, so the type stays unknown
+        assert!(root.join("Unknown/first.txt").is_file());
+        assert!(root.join("Unknown/first (2).txt").is_file());
+        assert!(source.path().join("first.txt").is_file());
+        assert!(container.records().iter().all(|r| Path::new(&r.file_dir_path).starts_with(&root)));
+        container.commit_to_db(miko.clone())?;
+
+        let reorganized = library.reorganize(&miko, "files/{name}.{ext}")?;
+        assert!(reorganized.placed.len() == 3);
+        assert!(root.join("files/second.txt").is_file());
+        assert!(!root.join("Unknown").exists());
+        let reopened = ManagedLibrary::open(&miko, &root)?.expect("The library wasn't recorded");
+        assert!(reopened.layout.path_template == "files/{name}.{ext}");
+        let uuid = reorganized.placed[0].file_uuid.clone();
+        let moved = miko
+            .send_messenger(move |(conn, _)| Ok(FileRecord::get_from_id(conn, &uuid)?))?
+            .unwrap();
+        assert!(Path::new(&moved.file_dir_path) == root.join("files"));
+
+        // Files sitting right in the root are still the library's
+        library.reorganize(&miko, "{name}.{ext}")?;
+        assert!(root.join("second.txt").is_file());
+        let again = library.reorganize(&miko, "again/{name}.{ext}")?;
+        assert!(again.placed.len() == 3);
+        assert!(root.join("again/second.txt").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn placed_files_keep_their_digests() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let library_dir = tempfile::tempdir()?;
+        fs::write(source.path().join("first.txt"), "one")?;
+        let (miko, _d) = init_miko("managed_library_digests")?;
+        let library = ManagedLibrary::create(&miko, library_dir.path().into(), "{type}/{name}.{ext}")?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(source.path().into())?
+            .with_digests(&[DigestAlgorithm::Md5])
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        let uuid = container.records()[0].file_uuid.clone();
+        let (report, _) =
+            library.place_and_commit(&miko, container, TransferMode::Copy, vec![], &ImportJob::default())?;
+        assert!(report.placed.len() == 1);
+        let digests = miko.send_messenger(move |(conn, _)| FileDigestRecord::get_digests_for_file(conn, &uuid))?;
+        assert!(digests.len() == 1 && digests[0].digest_algorithm == "md5");
+        Ok(())
+    }
+
+    #[test]
+    fn failed_commits_take_their_files_back_out() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let library_dir = tempfile::tempdir()?;
+        fs::write(source.path().join("first.txt"), "one")?;
+        fs::write(source.path().join("second.txt"), "two")?;
+        let (miko, _d) = init_miko("managed_library_failed_commit")?;
+        let library = ManagedLibrary::create(&miko, library_dir.path().into(), "{type}/{name}.{ext}")?;
+
+        let mut container = DirImportManifest::create_from_dir_on_disk(source.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        // Taking one of the ids makes the commit fail partway
+        let squatter = container.records()[1].clone();
+        miko.send_mutating_messenger(move |(_, conn)| Ok(squatter.insert(conn)?))?;
+        let placed = library.place_and_commit(&miko, container, TransferMode::Copy, vec![], &ImportJob::default());
+        assert!(placed.is_err());
+        assert!(fs::read_dir(library.root())?.next().is_none());
+        assert!(source.path().join("first.txt").is_file());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::Result;
+use infer::MatcherType;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+
+use super::FileSource;
+use crate::db::FileRecord;
+
+const GET_EXTENSION_REGISTRY_SQL: &str = "select FE.file_extension_tag, MT.media_type_id, MT.media_category_id
+from FileExtensions FE
+left join MediaTypesForFileExtensions ME on ME.file_extension_tag = FE.file_extension_tag
+left join MediaTypes MT on MT.media_type_id = ME.media_type_id;";
+
+const GET_MEDIA_TYPE_CATEGORIES_SQL: &str = "select MT.media_type_id, MT.media_category_id from MediaTypes MT;";
+
+/// Enough of the file for `infer` to recognize any format it knows
+const SNIFF_LENGTH: u64 = 8192;
+
+/// Which media category a sniffed format belongs in. Formats `infer` can't
+/// place in one of these (fonts, executables) don't help narrow anything down.
+const SNIFFED_CATEGORIES: [(MatcherType, &str); 6] = [
+    (MatcherType::Image, "IMAGE"),
+    (MatcherType::Audio, "AUDIO"),
+    (MatcherType::Archive, "ARCHIVE"),
+    (MatcherType::Book, "DOCUMENT"),
+    (MatcherType::Doc, "DOCUMENT"),
+    (MatcherType::Text, "DOCUMENT"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CandidateType {
+    media_type_id: String,
+    media_category_id: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct RegisteredExtension {
+    file_extension_tag: String,
+    media_types: Vec<CandidateType>,
+}
+
+/// The `FileExtensions` table and the media types each extension can be,
+/// loaded once so a whole import can be resolved without going back to the db.
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionRegistry {
+    // Keyed by uppercased tag, since tags are compared without case in the db
+    extensions: HashMap<String, RegisteredExtension>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UnresolvedFile {
+    pub file_name: String,
+    pub file_vfs_path: String,
+    pub file_extension_tag: String,
+    /// Empty when the extension isn't registered at all. Otherwise these are the
+    /// types the extension could be, none of which sniffing could rule out.
+    pub candidate_media_type_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct TypeResolutionReport {
+    pub resolved: usize,
+    pub unresolved: Vec<UnresolvedFile>,
+}
+
+impl ExtensionRegistry {
+    pub fn load(conn: &Connection) -> Result<ExtensionRegistry> {
+        let mut registry = ExtensionRegistry::default();
+        let mut stmt = conn.prepare_cached(GET_EXTENSION_REGISTRY_SQL)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let tag: String = row.get(0)?;
+            let entry = registry
+                .extensions
+                .entry(tag.to_uppercase())
+                .or_insert_with(|| RegisteredExtension {
+                    file_extension_tag: tag,
+                    media_types: vec![],
+                });
+            // Both null for an extension that has no types yet
+            let types: (Option<String>, Option<String>) = (row.get(1)?, row.get(2)?);
+            if let (Some(media_type_id), Some(media_category_id)) = types {
+                entry.media_types.push(CandidateType {
+                    media_type_id,
+                    media_category_id,
+                });
+            }
+        }
+        Ok(registry)
+    }
+
+    /// The longest registered suffix of the file name, so `celeste.p8.png` is a
+    /// `p8.png` if that's registered and a `png` otherwise
+    fn longest_registered_suffix(&self, file_name: &str) -> Option<&RegisteredExtension> {
+        file_name
+            .match_indices('.')
+            .map(|(i, _)| &file_name[i + 1..])
+            .find_map(|suffix| self.extensions.get(&suffix.to_uppercase()))
+    }
+}
+
+/// Each media type's category, keyed by uppercased type id
+pub fn load_media_categories(conn: &Connection) -> Result<HashMap<String, String>> {
+    let mut stmt = conn.prepare_cached(GET_MEDIA_TYPE_CATEGORIES_SQL)?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?.to_uppercase(), r.get(1)?)))?;
+    Ok(rows.collect::<rusqlite::Result<HashMap<_, _>>>()?)
+}
+
+fn sniffed_category(record: &FileRecord) -> Option<&'static str> {
+    let head = FileSource::of(record)
+        .with_reader(|r| {
+            let mut buf = vec![];
+            r.take(SNIFF_LENGTH).read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+        .ok()?;
+    let sniffed = infer::get(&head)?.matcher_type();
+    SNIFFED_CATEGORIES
+        .iter()
+        .find(|(matcher, _)| *matcher == sniffed)
+        .map(|(_, category)| *category)
+}
+
+/// Sets each record's `file_extension_tag` to the registered extension it matches,
+/// and its `file_media_type_id` to the one type it can be. Overrides are left
+/// alone, since those are the user's. When an extension maps to several types and
+/// `sniff` is on, the file's contents get a say. Files that still can't be pinned
+/// down keep going with the tag they came in with, and end up in the report.
+pub fn resolve_media_types(
+    records: &mut [FileRecord],
+    registry: &ExtensionRegistry,
+    sniff: bool,
+) -> TypeResolutionReport {
+    let mut report = TypeResolutionReport::default();
+    for record in records.iter_mut() {
+        let Some(registered) = registry.longest_registered_suffix(&record.file_name) else {
+            report.unresolved.push(UnresolvedFile {
+                file_name: record.file_name.clone(),
+                file_vfs_path: record.file_vfs_path.clone(),
+                file_extension_tag: record.file_extension_tag.clone(),
+                candidate_media_type_ids: vec![],
+            });
+            continue;
+        };
+        record.file_extension_tag = registered.file_extension_tag.clone();
+        let mut candidates: Vec<&CandidateType> = registered.media_types.iter().collect();
+        if candidates.len() > 1 && sniff {
+            if let Some(category) = sniffed_category(record) {
+                let narrowed: Vec<&CandidateType> = candidates
+                    .iter()
+                    .copied()
+                    .filter(|c| c.media_category_id.eq_ignore_ascii_case(category))
+                    .collect();
+                if !narrowed.is_empty() {
+                    candidates = narrowed;
+                }
+            }
+        }
+        if let [only] = candidates[..] {
+            record.file_media_type_id = Some(only.media_type_id.clone());
+            report.resolved += 1;
+        } else {
+            report.unresolved.push(UnresolvedFile {
+                file_name: record.file_name.clone(),
+                file_vfs_path: record.file_vfs_path.clone(),
+                file_extension_tag: record.file_extension_tag.clone(),
+                candidate_media_type_ids: candidates.iter().map(|c| c.media_type_id.clone()).collect(),
+            });
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod media_type_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use crate::miko::{Miko, ShrineDestroyer};
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+    const IMPORT_PATH_STR: &str = "./src/testing_data/import_test";
+
+    fn init_miko(dbname: &str) -> Result<(Miko<(Connection, Connection)>, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )
+    }
+
+    fn record_named(file_name: &str) -> FileRecord {
+        FileRecord {
+            file_uuid: "".into(),
+            file_name: file_name.into(),
+            file_size_bytes: 0,
+            file_hash: "".into(),
+            file_dir_path: "".into(),
+            file_extension_tag: "".into(),
+            file_encoding: "".into(),
+            media_type_override_id: None,
+            file_media_type_id: None,
+            file_deleted: false,
+            file_read_only: false,
+            file_vfs_path: "".into(),
+        }
+    }
+
+    #[test]
+    fn longest_registered_suffix_wins() -> Result<()> {
+        let (miko, _d) = init_miko("media_types_suffix")?;
+        let registry = miko.send_messenger(|(conn, _)| ExtensionRegistry::load(conn))?;
+        let mut records = vec![
+            record_named("thing3.foo.txt"),
+            record_named("celeste.p8.png"),
+            record_named("sonic.SMS"),
+            record_named("mystery.qqq"),
+        ];
+        let report = resolve_media_types(&mut records, &registry, false);
+        assert!(records[0].file_extension_tag == "FOO.TXT");
+        assert!(records[0].file_media_type_id == Some("PLAINTEXT".into()));
+        assert!(records[0].media_type_override_id.is_none());
+        // p8.png isn't registered, and png could be a picture or a cart
+        assert!(records[1].file_extension_tag == "PNG");
+        assert!(records[1].file_media_type_id.is_none());
+        assert!(records[2].file_media_type_id == Some("MS".into()));
+        assert!(report.resolved == 2);
+        assert!(report.unresolved.len() == 2);
+        let mystery = report.unresolved.iter().find(|u| u.file_name == "mystery.qqq").unwrap();
+        assert!(mystery.candidate_media_type_ids.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn sniffing_settles_ambiguous_archives() -> Result<()> {
+        let (miko, _d) = init_miko("media_types_sniffing")?;
+        let registry = miko.send_messenger(|(conn, _)| ExtensionRegistry::load(conn))?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        let report = container.resolve_media_types(&registry, true);
+        let zip = container
+            .records
+            .iter()
+            .find(|r| r.file_name == "archive_with_files.zip")
+            .unwrap();
+        // A zip could be a ROM set or an ebook, but this one's just an archive
+        assert!(zip.file_media_type_id == Some("ARCHIVE".into()));
+        // Plain text gives sniffing nothing to go on
+        let txt = report
+            .unresolved
+            .iter()
+            .find(|u| u.file_name == "thing.txt")
+            .expect("thing.txt shouldn't have been resolved");
+        assert!(txt.candidate_media_type_ids.len() == 2);
+        Ok(())
+    }
+}
@@ -1,30 +1,87 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use base64::alphabet::URL_SAFE;
 use hypertext::html_elements::div;
 use mlua::serde::de;
 use rayon::prelude::*;
 use relative_path::{Component as rComponent, PathExt, RelativePath, RelativePathBuf};
-use rust_search::{FilterExt, SearchBuilder};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use std::fs::{self, File};
-use std::io::BufReader;
+use std::io::{self, BufReader, Read};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     path::{Component as sComponent, Path, PathBuf},
 };
-use zip::{self, read::ZipFile};
+use zip::{self, read::ZipFile, ZipArchive};
 use std::time::SystemTime;
 use base64::prelude::*;
-use rusqlite::Connection;
-use exemplar::Model;
+use rusqlite::{params, Connection};
+use exemplar::{Model, OnConflict};
 
-use crate::{db::FileRecord, miko::Miko};
+use crate::{db::{FileArtworkRecord, FileDigestRecord, FileRecord, HashCacheRecord}, miko::Miko};
+use crate::db::transaction::WriterSession;
+
+mod artwork;
+mod digests;
+mod dry_run;
+mod hash_cache;
+mod ignore_rules;
+mod inspection;
+mod library;
+mod media_types;
+mod objects;
+mod playlists;
+mod progress;
+mod rescan;
+mod sessions;
+mod sidecars;
+mod traversal;
+mod watch;
+pub use artwork::{associate_artwork, ArtworkPattern, ArtworkRule, ArtworkRules, IMAGE_EXTENSIONS};
+pub use digests::{DigestAlgorithm, FileDigest};
+pub use dry_run::{DryRunReport, KnownDuplicate, PlannedFile};
+pub use hash_cache::HashCache;
+pub use ignore_rules::{IgnoreRules, SkipReason, SkippedPath, DEFAULT_IGNORE_RULES, IGNORE_FILE_NAME};
+pub use inspection::{inspect_contents, ContentMismatch, InspectionReport};
+pub use library::{ManagedLibrary, PathTemplate, Placement, PlacementReport, TransferMode, TEMPLATE_FIELDS};
+pub use media_types::{load_media_categories, resolve_media_types, ExtensionRegistry, TypeResolutionReport, UnresolvedFile};
+pub use objects::{NewObject, RejectedObject};
+pub use playlists::{
+    import_playlist, parse_m3u, parse_pls, Playlist, PlaylistEntry, PlaylistFormat, PlaylistImportReport, UnresolvedEntry,
+    PLAYLIST_PLUGIN_PACKAGE,
+};
+pub use progress::{CancellationToken, ImportCancelled, ImportJob, ImportProgress, ImportStage};
+pub use rescan::{PendingRescan, RescanReport};
+pub use sessions::{ImportSessionDetail, RollbackMode};
+pub use sidecars::{apply_sidecars, SidecarKind, SidecarLink, SidecarReport, SIDECAR_PLUGIN_PACKAGE};
+pub use traversal::{SymlinkPolicy, TraversalOptions};
+pub use watch::{
+    register_watch_folder, watch_all_folders, FolderWatcher, ObjectMaker, WatchImportSteps, WATCH_FOLDER_PLUGIN_PACKAGE,
+};
+
+/// Sits between an archive's path and the entry's directory in `file_dir_path`,
+/// so `/media/roms/snes.zip!/europe` means the `europe` dir inside `snes.zip`
+pub const ARCHIVE_ENTRY_MARKER: &str = "!/";
+
+const SET_MISSING_HASH_SQL: &str = "update Files set file_hash = ?2 where file_uuid = ?1 and file_hash = '';";
+
+/// Archives that get opened up when a manifest has `expand_archives` set
+const ZIP_LIKE_EXTENSIONS: [&str; 2] = ["zip", "cbz"];
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DirImportManifest {
     pub root_dir: PathBuf,
     pub items: Vec<RelativePathBuf>,
+    /// Also record each file inside a zip archive, under the archive's own VFS path.
+    /// Those aren't hashed until something calls `ensure_hashed` on them.
+    #[serde(default)]
+    pub expand_archives: bool,
+    /// What the ignore rules kept out of `items`, when the manifest came from a dir walk
+    #[serde(default)]
+    pub skipped: Vec<SkippedPath>,
+    /// Digests to work out on top of blake3, in the same read
+    #[serde(default)]
+    pub extra_digests: Vec<DigestAlgorithm>,
 }
 
 impl DirImportManifest {
@@ -32,9 +89,22 @@ impl DirImportManifest {
         Self {
             root_dir: root_dir,
             items: vec![],
+            expand_archives: false,
+            skipped: vec![],
+            extra_digests: vec![],
         }
     }
 
+    pub fn expanding_archives(mut self, expand: bool) -> Self {
+        self.expand_archives = expand;
+        self
+    }
+
+    pub fn with_digests(mut self, algorithms: &[DigestAlgorithm]) -> Self {
+        self.extra_digests = algorithms.to_vec();
+        self
+    }
+
     pub fn add_relative_file(mut self, file: RelativePathBuf) -> Self {
         self.items.push(file);
         self
@@ -93,60 +163,96 @@ impl DirImportManifest {
                 .filter(|p| !p.to_string().is_empty())
                 .collect(),
             root_dir: root_accumulator,
+            expand_archives: false,
+            skipped: vec![],
+            extra_digests: vec![],
         })
     }
 
     pub fn create_from_dir_on_disk(location: PathBuf) -> Result<Self> {
-        let s: Vec<_> = SearchBuilder::default()
-            .location(&location)
-            .search_input(r#".*"#)
-            .ext("*")
-            .ignore_case()
-            .build()
-            .map(|e| PathBuf::from(e))
-            .filter(|e| e.is_file())
-            .collect();
-        //let _rootdir = s.pop(); // first is gonna be the qualified root dir itself
-        Ok(Self::create_manifest_from_path_vec(s)?)
-    }
-
-    pub fn construct_container(mut self, import_session_id: &str) -> Result<InboundFileRecordContainer> {
+        Self::create_from_dir_on_disk_with(location, &IgnoreRules::default())
+    }
+
+    /// Every file under `location` that isn't ignored. The root is always
+    /// `location` itself, since guessing it from the files would move it down a
+    /// level whenever everything happens to sit in one subdir, which breaks rescans.
+    pub fn create_from_dir_on_disk_with(location: PathBuf, rules: &IgnoreRules) -> Result<Self> {
+        Self::create_from_dir_on_disk_traversing(location, rules, &TraversalOptions::default())
+    }
+
+    /// Like `create_from_dir_on_disk_with`, with a say in how links, hardlinks and
+    /// other filesystems are handled
+    pub fn create_from_dir_on_disk_traversing(
+        location: PathBuf,
+        rules: &IgnoreRules,
+        options: &TraversalOptions,
+    ) -> Result<Self> {
+        let walked = ignore_rules::walk_dir(&location, rules, options)?;
+        let mut manifest = Self::new(location).add_relative_files(walked.files);
+        manifest.skipped = walked.skipped;
+        Ok(manifest)
+    }
+
+    /// Without a db to load the `HashCache` from, every file gets hashed
+    pub fn construct_container(self, import_session_id: &str) -> Result<InboundFileRecordContainer> {
+        self.construct_container_cached(import_session_id, &ImportJob::default(), &HashCache::default())
+    }
+
+    /// Hashes every file that the db's `HashCache` doesn't already have as part of
+    /// `job`, reporting each one as it goes. A cancelled job stops before the next
+    /// file and fails with `ImportCancelled`.
+    pub fn construct_container_in(
+        self,
+        miko: &Miko<(Connection, Connection)>,
+        import_session_id: &str,
+        job: &ImportJob,
+    ) -> Result<InboundFileRecordContainer> {
+        let cache = miko.send_messenger(|(conn, _)| HashCache::load(conn))?;
+        self.construct_container_cached(import_session_id, job, &cache)
+    }
+
+    /// Takes hashes from `cache` for files that haven't changed since they were
+    /// last hashed. Whatever had to be hashed goes back into the cache on commit.
+    pub fn construct_container_cached(
+        self,
+        import_session_id: &str,
+        job: &ImportJob,
+        cache: &HashCache,
+    ) -> Result<InboundFileRecordContainer> {
         let root_dir = self.root_dir.clone();
-        let records = self
+        job.update(|progress| {
+            progress.stage = ImportStage::Hashing;
+            progress.files_discovered += self.items.len();
+        });
+        let extra_digests = self.extra_digests;
+        let mut digests: HashMap<String, Vec<FileDigest>> = HashMap::new();
+        let (mut records, fresh_hashes): (Vec<FileRecord>, Vec<Option<HashCacheRecord>>) = self
             .items
             .into_iter()
             .map(|p| (p.clone(), p.to_path(&root_dir)))
             .map(|(rp, pb)| (rp, pb.canonicalize()))
             .filter(|(rp, pr)| (&pr).is_ok() && (&pr).as_ref().unwrap().is_file())
             .map(|(r, p)| (r, p.unwrap()))
-            .map(|(r, p)| {
-                let mut hasher = blake3::Hasher::new();
-                match hasher.update_mmap_rayon(&p) {
-                    Ok(h) => (r, p, Some(hasher.finalize())),
-                    Err(e) => (r, p, None),
-                }
-            })
-            .map(|(r, p, h)| {
-                let hashstr = match h {
-                    Some(hash) => hash.to_string(),
-                    None => "".to_string(),
-                };
+            .map(|(r, p)| -> Result<(FileRecord, Option<HashCacheRecord>)> {
+                job.bail_if_cancelled()?;
+                job.update(|progress| progress.current_path = Some(p.clone()));
+                let hashed = cache.hash(&p, &extra_digests);
                 let full_filename = p.file_name().unwrap().to_str().unwrap();
                 let (_, fileext) = match full_filename.split_once(".") {
                     Some(t) => t,
                     None => (full_filename, ""),
                 };
-                let filesize = if let Ok(openfile) = File::open(&p) {
-                    if let Ok(md) = openfile.metadata() {
-                        md.len()
+                let filesize = hashed.size;
+                job.update(|progress| {
+                    progress.files_hashed += 1;
+                    if hashed.from_cache {
+                        progress.files_from_cache += 1;
                     } else {
-                        0
+                        progress.bytes_processed += filesize;
                     }
-                } else {
-                    0
-                };
+                });
                 let vfspathroot = RelativePath::new(import_session_id).join(r);
-                FileRecord {
+                let record = FileRecord {
                     file_uuid: "".into(),
                     file_vfs_path: format!("{}/", vfspathroot.parent().unwrap().to_string()),
                     file_size_bytes: filesize,
@@ -155,19 +261,250 @@ impl DirImportManifest {
                     file_extension_tag: fileext.into(),
                     file_encoding: "".into(),
                     file_dir_path: p.parent().unwrap().to_str().unwrap().to_string(),
-                    file_hash: hashstr,
+                    file_hash: hashed.hash,
                     file_deleted: false,
                     media_type_override_id: None,
+                    file_media_type_id: None,
+                };
+                if !hashed.digests.is_empty() {
+                    digests.insert(digest_key(&record), hashed.digests);
                 }
-            }).collect();
+                Ok((record, hashed.fresh))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+            job.update(|progress| {
+                progress.stage = ImportStage::Inspecting;
+                progress.current_path = None;
+            });
+            // Sidecars and object adapters go by the encoding, so this isn't optional.
+            // It's done before archives get expanded, so their entries still only get
+            // read when something asks for them.
+            let inspection = inspect_contents(&mut records);
+            job.bail_if_cancelled()?;
+            let mut skipped = self.skipped;
+            if self.expand_archives {
+                let canonical_root = root_dir.canonicalize().unwrap_or(root_dir.clone());
+                let expanded: Vec<(Vec<(FileRecord, Vec<FileDigest>)>, Vec<SkippedPath>)> = records
+                    .par_iter()
+                    .filter(|r| is_zip_like(&r.file_name))
+                    .filter(|_| !job.is_cancelled())
+                    .filter_map(|archive| match records_in_archive(archive, &canonical_root, &extra_digests) {
+                        Ok(expanded) => Some(expanded),
+                        Err(e) => {
+                            tracing::warn!(archive = %archive.file_name, "Couldn't read archive: {}", e);
+                            None
+                        }
+                    })
+                    .collect();
+                job.bail_if_cancelled()?;
+                for (entries, unreadable) in expanded {
+                    job.update(|progress| progress.files_discovered += entries.len());
+                    for (entry, entry_digests) in entries {
+                        if !entry_digests.is_empty() {
+                            digests.insert(digest_key(&entry), entry_digests);
+                        }
+                        records.push(entry);
+                    }
+                    skipped.extend(unreadable);
+                }
+            }
             Ok(InboundFileRecordContainer {
                 root_dir,
                 import_session_id: import_session_id.to_string(),
-                records
+                records,
+                artwork: vec![],
+                skipped,
+                fresh_hashes: fresh_hashes.into_iter().flatten().collect(),
+                digests,
+                type_resolution: None,
+                inspection,
             })
     }
 }
 
+/// Blank if the file couldn't be read
+fn hash_file_on_disk(path: &Path) -> String {
+    let mut hasher = blake3::Hasher::new();
+    match hasher.update_mmap_rayon(path) {
+        Ok(h) => h.finalize().to_string(),
+        Err(_) => "".to_string(),
+    }
+}
+
+fn is_zip_like(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| ZIP_LIKE_EXTENSIONS.iter().any(|z| z.eq_ignore_ascii_case(e)))
+}
+
+/// What a record's extra digests are filed under: its id once it has one, since
+/// placing it in a library changes where it is, and where it was found before then
+fn digest_key(record: &FileRecord) -> String {
+    if record.file_uuid.is_empty() {
+        Path::new(&record.file_dir_path).join(&record.file_name).to_string_lossy().to_string()
+    } else {
+        record.file_uuid.clone()
+    }
+}
+
+/// One record per file in the archive, from its central directory alone. Nothing
+/// gets decompressed, so the records go in without a hash until `ensure_hashed`
+/// works one out. The archive already has each entry's CRC32, so that digest is
+/// the one that can be had up front. Entries whose headers can't be read are skipped.
+fn records_in_archive(
+    archive: &FileRecord,
+    canonical_root: &Path,
+    extra_digests: &[DigestAlgorithm],
+) -> Result<(Vec<(FileRecord, Vec<FileDigest>)>, Vec<SkippedPath>)> {
+    let archive_path = Path::new(&archive.file_dir_path).join(&archive.file_name);
+    let archive_str = archive_path
+        .to_str()
+        .ok_or(anyhow!("{:?} isn't valid unicode", archive_path))?;
+    let archive_rel = archive_path
+        .relative_to(canonical_root)
+        .unwrap_or_else(|_| RelativePathBuf::from(archive.file_name.as_str()));
+    let archive_vfs_dir = RelativePath::new(&archive.file_vfs_path).join(&archive.file_name);
+    let mut zipped = ZipArchive::new(BufReader::new(File::open(&archive_path)?))?;
+    let mut records = vec![];
+    let mut skipped = vec![];
+    for i in 0..zipped.len() {
+        let entry_name = zipped.name_for_index(i).map(str::to_string).unwrap_or_else(|| format!("#{}", i));
+        let entry = match zipped.by_index_raw(i) {
+            Ok(entry) => entry,
+            Err(e) => {
+                skipped.push(SkippedPath {
+                    path: RelativePathBuf::from(format!("{}{}{}", archive_rel, ARCHIVE_ENTRY_MARKER, entry_name)),
+                    is_dir: false,
+                    reason: SkipReason::Unreadable,
+                    rule: e.to_string(),
+                    rule_file: None,
+                });
+                continue;
+            }
+        };
+        // Anything that would land outside the archive's own dir gets skipped
+        if !entry.is_file() || entry.enclosed_name().is_none() {
+            continue;
+        }
+        let entry_path = RelativePathBuf::from(entry.name());
+        let full_filename = match entry_path.file_name() {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        let (_, fileext) = match full_filename.split_once(".") {
+            Some(t) => t,
+            None => (full_filename.as_str(), ""),
+        };
+        let inner_dir = entry_path.parent().map(|p| p.to_string()).unwrap_or_default();
+        let entry_digests = if extra_digests.contains(&DigestAlgorithm::Crc32) {
+            vec![(DigestAlgorithm::Crc32, format!("{:08x}", entry.crc32()))]
+        } else {
+            vec![]
+        };
+        records.push((FileRecord {
+            file_uuid: "".into(),
+            file_vfs_path: format!("{}/", archive_vfs_dir.join(&inner_dir).normalize()),
+            file_size_bytes: entry.size(),
+            // Changing a file would mean rewriting the whole archive
+            file_read_only: true,
+            file_name: full_filename.clone(),
+            file_extension_tag: fileext.into(),
+            file_encoding: "".into(),
+            file_dir_path: format!("{}{}{}", archive_str, ARCHIVE_ENTRY_MARKER, inner_dir),
+            file_hash: "".into(),
+            file_deleted: false,
+            media_type_override_id: None,
+            file_media_type_id: None,
+        }, entry_digests));
+    }
+    Ok((records, skipped))
+}
+
+/// Fills in the hash of a file that went in without one, like an archive entry,
+/// and saves it along with any `extra` digests. Files that already have a hash
+/// are left as they are, so this only ever reads a file once.
+pub fn ensure_hashed(miko: &Miko<(Connection, Connection)>, record: &mut FileRecord, extra: &[DigestAlgorithm]) -> Result<()> {
+    if !record.file_hash.is_empty() {
+        return Ok(());
+    }
+    let (hash, digests) = FileSource::of(record).hash(extra)?;
+    let file_uuid = record.file_uuid.clone();
+    let saved_hash = hash.clone();
+    miko.send_mutating_messenger(move |(_, conn)| {
+        let tx = conn.transaction()?;
+        tx.prepare_cached(SET_MISSING_HASH_SQL)?
+            .execute(params![file_uuid, saved_hash])?;
+        for (algorithm, value) in digests {
+            FileDigestRecord {
+                file_uuid: file_uuid.clone(),
+                digest_algorithm: algorithm.name().to_string(),
+                digest_value: value,
+            }
+            .insert_or(&tx, OnConflict::Replace)?;
+        }
+        tx.commit()?;
+        Ok(())
+    })?;
+    record.file_hash = hash;
+    Ok(())
+}
+
+/// Where a record's bytes actually live. Nothing is read until asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileSource {
+    OnDisk(PathBuf),
+    InArchive { archive: PathBuf, entry: String },
+}
+
+impl FileSource {
+    pub fn of(record: &FileRecord) -> Self {
+        match record.file_dir_path.split_once(ARCHIVE_ENTRY_MARKER) {
+            Some((archive, "")) => FileSource::InArchive {
+                archive: archive.into(),
+                entry: record.file_name.clone(),
+            },
+            Some((archive, inner_dir)) => FileSource::InArchive {
+                archive: archive.into(),
+                entry: format!("{}/{}", inner_dir, record.file_name),
+            },
+            None => FileSource::OnDisk(Path::new(&record.file_dir_path).join(&record.file_name)),
+        }
+    }
+
+    /// Hands `reader` a stream of the file's contents, decompressing as it goes
+    /// for files inside archives.
+    pub fn with_reader<R>(&self, reader: impl FnOnce(&mut dyn Read) -> Result<R>) -> Result<R> {
+        match self {
+            FileSource::OnDisk(path) => reader(&mut BufReader::new(File::open(path)?)),
+            FileSource::InArchive { archive, entry } => {
+                let mut zipped = ZipArchive::new(BufReader::new(File::open(archive)?))?;
+                let mut entry = zipped.by_name(entry)?;
+                reader(&mut entry)
+            }
+        }
+    }
+
+    /// The blake3 hash and any `extra` digests, in one read
+    pub fn hash(&self, extra: &[DigestAlgorithm]) -> Result<(String, Vec<FileDigest>)> {
+        self.with_reader(|r| {
+            let mut hasher = digests::MultiHasher::new(extra);
+            io::copy(r, &mut hasher)?;
+            Ok(hasher.finish())
+        })
+    }
+
+    pub fn read_to_vec(&self) -> Result<Vec<u8>> {
+        self.with_reader(|r| {
+            let mut buf = vec![];
+            r.read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+    }
+}
+
 pub fn make_import_id_with_time() -> Result<String>{
     let now = SystemTime::now();
     let since_epoch = now.duration_since(SystemTime::UNIX_EPOCH)?;
@@ -181,32 +518,183 @@ pub struct InboundFileRecordContainer {
     root_dir: PathBuf,
     import_session_id: String,
     records: Vec<FileRecord>,
+    artwork: Vec<FileArtworkRecord>,
+    /// Carried over from the manifest, for reporting
+    skipped: Vec<SkippedPath>,
+    /// Hashes to save to the `HashCache` table along with the files
+    fresh_hashes: Vec<HashCacheRecord>,
+    /// Extra digests, keyed by `digest_key` of the record they're for
+    digests: HashMap<String, Vec<FileDigest>>,
+    /// What `resolve_media_types` made of the files, if it's been run, so
+    /// committing doesn't do it again and dry runs know what it came to
+    #[serde(default)]
+    type_resolution: Option<TypeResolutionReport>,
+    /// From when the container was made
+    #[serde(default)]
+    inspection: InspectionReport,
 }
 
 impl InboundFileRecordContainer {
     pub fn give_ids_to_records(&mut self) -> &mut Self {
         (&mut self.records).into_iter().for_each(|r| {
+            let filed_under = digest_key(r);
             let id = Uuid::now_v7();
             r.file_uuid = id.simple().to_string();
+            if let Some(digests) = self.digests.remove(&filed_under) {
+                self.digests.insert(r.file_uuid.clone(), digests);
+            }
         }); 
         return self;
     }
 
+    /// Committing does this anyway if it hasn't been done, but anything that goes
+    /// by type before then (artwork, object adapters) needs it done first
+    pub fn resolve_media_types(&mut self, registry: &ExtensionRegistry, sniff: bool) -> TypeResolutionReport {
+        let resolution = resolve_media_types(&mut self.records, registry, sniff);
+        self.type_resolution = Some(resolution.clone());
+        resolution
+    }
+
+    /// What reading the start of every file outside an archive turned up, which
+    /// happens while the container is being made
+    pub fn inspection(&self) -> &InspectionReport {
+        &self.inspection
+    }
+
+    /// Goes over every file again, for when they might have changed since. Unlike
+    /// the inspection done while the container is made, this reads archive entries too.
+    pub fn inspect_contents(&mut self) -> InspectionReport {
+        self.inspection = inspect_contents(&mut self.records);
+        self.inspection.clone()
+    }
+
+    pub fn apply_sidecars(&self, objects: &mut Vec<NewObject>) -> SidecarReport {
+        apply_sidecars(&self.records, objects)
+    }
+
+    /// Works out the art for the files going in, to be committed along with them.
+    /// Needs the records to have their ids already.
+    pub fn associate_artwork(&mut self, rules: &ArtworkRules, categories: &HashMap<String, String>) -> &[FileArtworkRecord] {
+        self.artwork = associate_artwork(&self.records, rules, categories);
+        &self.artwork
+    }
+
+    pub fn records(&self) -> &[FileRecord] {
+        &self.records
+    }
+
     pub fn commit_to_db(self, miko: Miko<(Connection, Connection)>) -> Result<()> {
-        miko.send_mutating_messenger(move |(_, conn)| {
-            for record in self.records {
-                record.insert(conn)?;
+        self.commit_with_objects(miko, vec![])?;
+        Ok(())
+    }
+
+    /// Commits the files and the objects made from them in one transaction.
+    /// Objects that don't hold up are left out and handed back, but their files
+    /// still go in.
+    pub fn commit_with_objects(
+        self,
+        miko: Miko<(Connection, Connection)>,
+        new_objects: Vec<NewObject>,
+    ) -> Result<Vec<RejectedObject>> {
+        self.commit_with_objects_in(miko, new_objects, &ImportJob::default())
+    }
+
+    /// Commits inside a session that's already open, so whatever else the caller
+    /// does in it goes in along with the import or not at all
+    pub fn commit_in(mut self, session: &WriterSession, new_objects: Vec<NewObject>) -> Result<Vec<RejectedObject>> {
+        if self.type_resolution.is_none() {
+            let registry = session.run(ExtensionRegistry::load)?;
+            self.resolve_media_types(&registry, true);
+        }
+        let job = ImportJob::default();
+        let committing = job.clone();
+        session
+            .run(move |conn| self.write_to(conn, new_objects, &committing))?
+            .ok_or_else(|| job.cancelled())
+    }
+
+    /// Puts the session, the files and everything hanging off them in with `conn`,
+    /// which has to be in a transaction already. `None` if `job` got cancelled,
+    /// in which case the transaction has to be rolled back.
+    fn write_to(self, conn: &Connection, new_objects: Vec<NewObject>, job: &ImportJob) -> Result<Option<Vec<RejectedObject>>> {
+        let root_dir = self.root_dir.canonicalize().unwrap_or(self.root_dir.clone());
+        sessions::note_session_files(
+            conn,
+            &self.import_session_id,
+            &root_dir.to_string_lossy(),
+            self.records.iter().map(|r| r.file_uuid.as_str()),
+        )?;
+        let imported: HashSet<String> = self.records.iter().map(|r| r.file_uuid.clone()).collect();
+        for record in self.records {
+            if job.is_cancelled() {
+                return Ok(None);
             }
-            Ok(())
+            job.update(|progress| {
+                progress.current_path = Some(Path::new(&record.file_dir_path).join(&record.file_name))
+            });
+            record.insert(conn)?;
+            for (algorithm, value) in self.digests.get(&digest_key(&record)).into_iter().flatten() {
+                FileDigestRecord {
+                    file_uuid: record.file_uuid.clone(),
+                    digest_algorithm: algorithm.name().to_string(),
+                    digest_value: value.clone(),
+                }
+                .insert_or(conn, OnConflict::Replace)?;
+            }
+            job.update(|progress| progress.files_committed += 1);
+        }
+        let rejected = objects::commit_new_objects(conn, &imported, new_objects)?;
+        // Plugins might have already linked the same art to their objects' files
+        for art in &self.artwork {
+            art.insert_or(conn, OnConflict::Ignore)?;
+        }
+        for hash in &self.fresh_hashes {
+            hash.insert_or(conn, OnConflict::Replace)?;
+        }
+        if job.is_cancelled() {
+            return Ok(None);
+        }
+        Ok(Some(rejected))
+    }
+
+    /// Commits as part of `job`. Cancelling rolls the whole transaction back, so
+    /// the session and its files either all go in or none of them do.
+    pub fn commit_with_objects_in(
+        mut self,
+        miko: Miko<(Connection, Connection)>,
+        new_objects: Vec<NewObject>,
+        job: &ImportJob,
+    ) -> Result<Vec<RejectedObject>> {
+        if self.type_resolution.is_none() {
+            let registry = miko.send_messenger(|(conn, _)| ExtensionRegistry::load(conn))?;
+            self.resolve_media_types(&registry, true);
+        }
+        job.update(|progress| progress.stage = ImportStage::Committing);
+        let committing = job.clone();
+        // Bulk work, so it shouldn't hold up page loads
+        let committed = miko.background().send_mutating_messenger(move |(_, conn)| {
+            let tx = conn.transaction()?;
+            // Errors don't make it back out of the shrine, so cancelling is
+            // reported as nothing committed. Dropping `tx` rolls it all back.
+            let Some(rejected) = self.write_to(&tx, new_objects, &committing)? else {
+                return Ok(None);
+            };
+            tx.commit()?;
+            Ok(Some(rejected))
         })?;
-        
-        Ok(())
+        let rejected = committed.ok_or_else(|| job.cancelled())?;
+        job.update(|progress| {
+            progress.stage = ImportStage::Finished;
+            progress.current_path = None;
+        });
+        Ok(rejected)
     }
 }
 
 #[cfg(test)]
 mod file_import_tests {
     use super::*;
+    use crate::db::Fetchable1;
     use crate::miko::ShrineDestroyer;
 
     #[test]
@@ -268,6 +756,75 @@ mod file_import_tests {
         Ok(())
     }
 
+    #[test]
+    fn tests_expands_zip_archives() -> Result<()> {
+        let manifest = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .expanding_archives(true);
+        let old_len = manifest.items.len();
+        let import_id = make_import_id_with_time()?;
+        let inbound_container = manifest.construct_container(import_id.as_str())?;
+        // Five files in the zip, and the zip itself is still recorded too
+        assert!(inbound_container.records.len() == old_len + 5);
+        let nested = inbound_container
+            .records
+            .iter()
+            .find(|r| r.file_name == "file_in_zip2.txt")
+            .expect("Nested archive entry wasn't recorded");
+        assert!(nested.file_vfs_path == format!("{}/archive_with_files.zip/anormalsubdir/", import_id));
+        assert!(nested.file_size_bytes == 84);
+        assert!(nested.file_read_only);
+        let source = FileSource::of(nested);
+        assert!(matches!(&source, FileSource::InArchive { entry, .. } if entry == "anormalsubdir/file_in_zip2.txt"));
+        let contents = source.read_to_vec()?;
+        assert!(contents.len() == 84);
+        // Nothing in the archive gets decompressed until it's asked for
+        assert!(nested.file_hash.is_empty());
+        assert!(nested.file_encoding.is_empty());
+        assert!(inbound_container.inspection().inspected == old_len);
+        Ok(())
+    }
+
+    #[test]
+    fn archive_entries_get_hashed_when_asked() -> Result<()> {
+        let mut container = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .expanding_archives(true)
+            .construct_container(make_import_id_with_time()?.as_str())?;
+        container.give_ids_to_records();
+        let mut nested = container
+            .records
+            .iter()
+            .find(|r| r.file_name == "file_in_zip2.txt")
+            .cloned()
+            .expect("Nested archive entry wasn't recorded");
+        let (miko, _sd) = init_miko("import_lazy_archive_hashes")?;
+        container.commit_to_db(miko.clone())?;
+        ensure_hashed(&miko, &mut nested, &[DigestAlgorithm::Crc32])?;
+        let contents = FileSource::of(&nested).read_to_vec()?;
+        assert!(nested.file_hash == blake3::hash(&contents).to_string());
+        let uuid = nested.file_uuid.clone();
+        let (stored, digests) = miko.send_messenger(move |(conn, _)| {
+            let stored = FileRecord::get_from_id(conn, &uuid)?.expect("The entry wasn't committed");
+            Ok((stored, FileDigestRecord::get_digests_for_file(conn, &uuid)?))
+        })?;
+        assert!(stored.file_hash == nested.file_hash);
+        assert!(digests.len() == 1 && digests[0].digest_algorithm == "crc32");
+        Ok(())
+    }
+
+    #[test]
+    fn tests_leaves_archives_closed_by_default() -> Result<()> {
+        let manifest = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?;
+        let inbound_container = manifest.construct_container(make_import_id_with_time()?.as_str())?;
+        assert!(!inbound_container.records.iter().any(|r| r.file_name.starts_with("file_in_zip")));
+        let archive = inbound_container
+            .records
+            .iter()
+            .find(|r| r.file_name == "archive_with_files.zip")
+            .expect("The archive itself wasn't recorded");
+        assert!(FileSource::of(archive) == FileSource::OnDisk(Path::new(&archive.file_dir_path).join("archive_with_files.zip")));
+        Ok(())
+    }
+
     static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
     static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
 
@@ -293,4 +850,34 @@ mod file_import_tests {
         Ok(())
     }
 
+    #[test]
+    fn committing_resolves_types_without_touching_the_registry() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("notes.foo.txt"), "resolves")?;
+        fs::write(dir.path().join("mystery.qqq"), "doesn't")?;
+        fs::write(dir.path().join("README"), "no extension at all")?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        let (miko, _sd) = init_miko("import_commit_resolves_types")?;
+        container.commit_to_db(miko.clone())?;
+        let (files, extensions) = miko.send_messenger(|(conn, _)| {
+            let mut stmt = conn.prepare("select * from Files order by file_name")?;
+            let files = stmt
+                .query_map([], FileRecord::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let extensions = conn.query_row("select count(*) from FileExtensions", [], |r| r.get::<_, i64>(0))?;
+            Ok((files, extensions))
+        })?;
+        let notes = files.iter().find(|f| f.file_name == "notes.foo.txt").unwrap();
+        assert!(notes.file_extension_tag == "FOO.TXT");
+        assert!(notes.file_media_type_id == Some("PLAINTEXT".into()));
+        assert!(notes.media_type_override_id.is_none());
+        let mystery = files.iter().find(|f| f.file_name == "mystery.qqq").unwrap();
+        assert!(mystery.file_extension_tag == "qqq");
+        assert!(mystery.file_media_type_id.is_none());
+        // Only the ones from testing_values.sql
+        assert!(extensions == 16);
+        Ok(())
+    }
 }
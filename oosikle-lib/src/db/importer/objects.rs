@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Result};
+use exemplar::Model;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use time::OffsetDateTime;
+
+use crate::db::{FileArtworkRecord, FileRecord, Fetchable1, ObjectAttr, ObjectExtraFileRecord, ObjectRecord};
+
+/// An object made from one of the files in an import, along with everything
+/// that hangs off of it. Its `object_uuid` is the uuid of the file it came from.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct NewObject {
+    pub object: ObjectRecord,
+    pub attributes: Vec<ObjectAttr>,
+    pub artwork: Vec<FileArtworkRecord>,
+    pub extra_files: Vec<ObjectExtraFileRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct RejectedObject {
+    pub file_uuid: String,
+    pub reason: String,
+}
+
+fn check_file_is_known(conn: &Connection, imported: &HashSet<String>, file_uuid: &str) -> Result<()> {
+    if imported.contains(file_uuid) || FileRecord::check_exists(conn, file_uuid)? {
+        Ok(())
+    } else {
+        Err(anyhow!("No file has the uuid {}", file_uuid))
+    }
+}
+
+impl NewObject {
+    /// An object with nothing but a name, for a file that doesn't have one yet
+    pub fn bare(file: &FileRecord, plugin_package_name: &str) -> Self {
+        let stem = file.file_name.split_once('.').map(|(s, _)| s).unwrap_or(&file.file_name);
+        Self {
+            object: ObjectRecord {
+                object_uuid: file.file_uuid.clone(),
+                object_name: stem.to_string(),
+                plugin_package_name: plugin_package_name.to_string(),
+                object_deleted: false,
+                object_genre: "".into(),
+                object_album_name: "".into(),
+                object_album_position: 0,
+                object_region: "w".into(),
+                object_language: "en".into(),
+                object_artist: "".into(),
+                object_imprint: "".into(),
+                object_publish_timestamp: OffsetDateTime::UNIX_EPOCH,
+                object_website: "".into(),
+            },
+            attributes: vec![],
+            artwork: vec![],
+            extra_files: vec![],
+        }
+    }
+
+    fn validate(&self, conn: &Connection, imported: &HashSet<String>) -> Result<()> {
+        let uuid = &self.object.object_uuid;
+        if !imported.contains(uuid) {
+            return Err(anyhow!("{} isn't one of the files being imported", uuid));
+        }
+        if self.object.object_name.trim().is_empty() {
+            return Err(anyhow!("Objects need a name"));
+        }
+        if self.object.plugin_package_name.trim().is_empty() {
+            return Err(anyhow!("Objects need to say which plugin manages them"));
+        }
+        for attr in &self.attributes {
+            if &attr.object_uuid != uuid || attr.attribute_name.trim().is_empty() {
+                return Err(anyhow!("Attribute {:?} doesn't belong to this object", attr.attribute_name));
+            }
+        }
+        for art in &self.artwork {
+            if &art.file_uuid != uuid || art.artwork_role.trim().is_empty() {
+                return Err(anyhow!("Artwork needs a role, and has to be for this object"));
+            }
+            check_file_is_known(conn, imported, &art.artwork_file_uuid)?;
+        }
+        for extra in &self.extra_files {
+            if &extra.object_uuid != uuid {
+                return Err(anyhow!("Extra file {} doesn't belong to this object", extra.file_uuid));
+            }
+            check_file_is_known(conn, imported, &extra.file_uuid)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&self, conn: &Connection) -> Result<()> {
+        self.object.insert(conn)?;
+        for attr in &self.attributes {
+            attr.insert(conn)?;
+        }
+        for art in &self.artwork {
+            art.insert(conn)?;
+        }
+        for extra in &self.extra_files {
+            extra.insert(conn)?;
+        }
+        Ok(())
+    }
+}
+
+/// Meant to run inside the transaction that inserts the files in `imported`
+pub(super) fn commit_new_objects(
+    conn: &Connection,
+    imported: &HashSet<String>,
+    new_objects: Vec<NewObject>,
+) -> Result<Vec<RejectedObject>> {
+    let mut rejected = vec![];
+    for new_object in new_objects {
+        if let Err(e) = new_object.validate(conn, imported) {
+            rejected.push(RejectedObject {
+                file_uuid: new_object.object.object_uuid,
+                reason: e.to_string(),
+            });
+            continue;
+        }
+        new_object.insert(conn)?;
+    }
+    Ok(rejected)
+}
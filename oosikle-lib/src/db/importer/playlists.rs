@@ -0,0 +1,398 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::{UTF_8, WINDOWS_1252};
+use exemplar::Model;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use super::{hash_file_on_disk, NewObject};
+use crate::db::{CollectionRecord, Fetchable1, FileRecord, ObjectInCollection, ObjectRecord};
+use crate::miko::Miko;
+
+/// Who objects made only so a playlist's files could go in a collection are managed by
+pub const PLAYLIST_PLUGIN_PACKAGE: &str = "oosikle.builtin.playlists";
+
+const FIND_FILE_BY_PATH_SQL: &str = "select * from Files F
+where F.file_dir_path = ?1 and F.file_name = ?2 and F.file_deleted = 0 limit 1;";
+
+const FIND_FILE_BY_HASH_SQL: &str =
+    "select * from Files F where F.file_hash = ?1 and F.file_deleted = 0 limit 1;";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Plain and extended M3U both
+    M3u,
+    Pls,
+}
+
+impl PlaylistFormat {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "m3u" | "m3u8" => Some(Self::M3u),
+            "pls" => Some(Self::Pls),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    /// As the playlist has it, which might be relative, absolute or a `file://` url
+    pub location: String,
+    pub title: Option<String>,
+    /// Negative for streams, going by the M3U convention
+    pub duration_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Playlist {
+    pub name: String,
+    pub format: PlaylistFormat,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UnresolvedEntry {
+    /// Where it was in the playlist, counting from 0
+    pub position: usize,
+    pub entry: PlaylistEntry,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PlaylistImportReport {
+    pub collection: CollectionRecord,
+    /// Entries that made it into the collection
+    pub added: usize,
+    /// Of those, the ones that were found by hash because nothing was at their path
+    pub matched_by_hash: usize,
+    pub unresolved: Vec<UnresolvedEntry>,
+}
+
+/// `#EXTINF:123,Artist - Title` gives the next entry its duration and title
+pub fn parse_m3u(text: &str) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut pending: Option<(Option<i64>, Option<String>)> = None;
+    for line in text.lines().map(|l| l.trim()) {
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let (duration, title) = info.split_once(',').unwrap_or((info, ""));
+            // Durations can have attributes after them, like `-1 tvg-id="..."`
+            let duration = duration.split_whitespace().next().and_then(|d| d.parse::<f64>().ok());
+            let title = Some(title.trim().to_string()).filter(|t| !t.is_empty());
+            pending = Some((duration.map(|d| d as i64), title));
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let (duration_secs, title) = pending.take().unwrap_or((None, None));
+        entries.push(PlaylistEntry {
+            location: line.to_string(),
+            title,
+            duration_secs,
+        });
+    }
+    entries
+}
+
+/// Goes by the numbers on `File1=`, `Title1=` and `Length1=` rather than the
+/// order the lines are in
+pub fn parse_pls(text: &str) -> Vec<PlaylistEntry> {
+    let mut numbered: BTreeMap<u32, PlaylistEntry> = BTreeMap::new();
+    for line in text.lines().map(|l| l.trim()) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        let field = key.trim_end_matches(|c: char| c.is_ascii_digit());
+        let Ok(number) = key[field.len()..].parse::<u32>() else {
+            continue;
+        };
+        let entry = numbered.entry(number).or_insert_with(|| PlaylistEntry {
+            location: String::new(),
+            title: None,
+            duration_secs: None,
+        });
+        let value = value.trim();
+        match field {
+            "file" => entry.location = value.to_string(),
+            "title" => entry.title = Some(value.to_string()).filter(|t| !t.is_empty()),
+            "length" => entry.duration_secs = value.parse().ok(),
+            _ => {}
+        }
+    }
+    numbered.into_values().filter(|e| !e.location.is_empty()).collect()
+}
+
+/// UTF-8 if it decodes cleanly, which `.m3u8` always should, and windows-1252
+/// if it doesn't, since that's what older players wrote
+fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(bytes);
+    match UTF_8.decode_without_bom_handling_and_without_replacement(bytes) {
+        Some(text) => text.into_owned(),
+        None => WINDOWS_1252.decode_without_bom_handling(bytes).0.into_owned(),
+    }
+}
+
+impl Playlist {
+    pub fn read(path: &Path) -> Result<Playlist> {
+        let text = decode(&fs::read(path)?);
+        let format = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(PlaylistFormat::from_extension)
+            .or_else(|| {
+                let is_pls = text.trim_start().to_lowercase().starts_with("[playlist]");
+                is_pls.then_some(PlaylistFormat::Pls)
+            })
+            .unwrap_or(PlaylistFormat::M3u);
+        let entries = match format {
+            PlaylistFormat::M3u => parse_m3u(&text),
+            PlaylistFormat::Pls => parse_pls(&text),
+        };
+        Ok(Playlist {
+            name: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            format,
+            entries,
+        })
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Where on disk an entry points, relative to the playlist's own dir. Streams
+/// and other urls don't point anywhere on disk.
+fn entry_path(location: &str, playlist_dir: &Path) -> Option<PathBuf> {
+    let location = match location.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("file") => {
+            // `file:///C:/Music` has one slash too many for Windows
+            let rest = percent_decode(rest.strip_prefix("localhost").unwrap_or(rest));
+            if rest.get(2..3) == Some(":") {
+                rest[1..].to_string()
+            } else {
+                rest
+            }
+        }
+        Some(_) => return None,
+        None => location.to_string(),
+    };
+    // Playlists made on Windows get used everywhere
+    let location = if cfg!(windows) { location } else { location.replace('\\', "/") };
+    let path = playlist_dir.join(location);
+    Some(path.canonicalize().unwrap_or(path))
+}
+
+fn find_by_path(conn: &Connection, path: &Path) -> Result<Option<FileRecord>> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(None);
+    };
+    Ok(conn
+        .prepare_cached(FIND_FILE_BY_PATH_SQL)?
+        .query_row(
+            [dir.to_string_lossy(), name.to_string_lossy()],
+            FileRecord::from_row,
+        )
+        .optional()?)
+}
+
+/// Turns the playlist at `path` into a new collection of the files it lists, in the
+/// same order. Entries are matched to imported files by path, or failing that, by
+/// hashing whatever is at the path and looking for a file with the same contents.
+/// Files that don't have an object yet get a bare one, since collections hold objects.
+pub fn import_playlist(miko: &Miko<(Connection, Connection)>, path: &Path) -> Result<PlaylistImportReport> {
+    let playlist = Playlist::read(path)?;
+    let playlist_dir = path
+        .canonicalize()?
+        .parent()
+        .ok_or_else(|| anyhow!("{:?} isn't in a dir", path))?
+        .to_path_buf();
+    let paths: Vec<Option<PathBuf>> = playlist
+        .entries
+        .iter()
+        .map(|e| entry_path(&e.location, &playlist_dir))
+        .collect();
+
+    let to_find = paths.clone();
+    let by_path: Vec<Option<FileRecord>> = miko.send_messenger(move |(conn, _)| {
+        to_find
+            .iter()
+            .map(|p| match p {
+                Some(p) => find_by_path(conn, p),
+                None => Ok(None),
+            })
+            .collect()
+    })?;
+    // Only what isn't imported under this path but is on disk somewhere can be hashed
+    let hashes: Vec<Option<String>> = paths
+        .iter()
+        .zip(&by_path)
+        .map(|(p, found)| match (p, found) {
+            (Some(p), None) if p.is_file() => Some(hash_file_on_disk(p)).filter(|h| !h.is_empty()),
+            _ => None,
+        })
+        .collect();
+
+    let collection = CollectionRecord {
+        uuid: Uuid::now_v7().simple().to_string(),
+        name: playlist.name.clone(),
+        visible: true,
+        location: "".into(),
+        deleted: false,
+    };
+    let to_insert = collection.clone();
+    let entries = playlist.entries.clone();
+    let (added, matched_by_hash, unresolved) = miko.background().send_mutating_messenger(move |(_, conn)| {
+        let tx = conn.transaction()?;
+        to_insert.insert(&tx)?;
+        let mut index_in_collection = 0;
+        let mut matched_by_hash = 0;
+        let mut unresolved = vec![];
+        for (position, ((entry, found), hash)) in entries.into_iter().zip(by_path).zip(hashes).enumerate() {
+            let found = match (found, hash) {
+                (Some(file), _) => Some(file),
+                (None, Some(hash)) => {
+                    let file = tx
+                        .prepare_cached(FIND_FILE_BY_HASH_SQL)?
+                        .query_row([&hash], FileRecord::from_row)
+                        .optional()?;
+                    matched_by_hash += file.is_some() as usize;
+                    file
+                }
+                (None, None) => None,
+            };
+            let Some(file) = found else {
+                let is_url = entry.location.contains("://") && !entry.location.to_lowercase().starts_with("file://");
+                let reason = if is_url { "not a local file" } else { "no imported file matches it" };
+                unresolved.push(UnresolvedEntry {
+                    position,
+                    entry,
+                    reason: reason.to_string(),
+                });
+                continue;
+            };
+            if !ObjectRecord::check_exists(&tx, &file.file_uuid)? {
+                NewObject::bare(&file, PLAYLIST_PLUGIN_PACKAGE).object.insert(&tx)?;
+            }
+            ObjectInCollection {
+                collection_uuid: to_insert.uuid.clone(),
+                index_in_collection,
+                object_uuid: file.file_uuid.clone(),
+            }
+            .insert(&tx)?;
+            index_in_collection += 1;
+        }
+        tx.commit()?;
+        Ok((index_in_collection as usize, matched_by_hash, unresolved))
+    })?;
+    Ok(PlaylistImportReport {
+        collection,
+        added,
+        matched_by_hash,
+        unresolved,
+    })
+}
+
+#[cfg(test)]
+mod playlist_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use crate::db::PageOfObjectsInCollection;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+
+    #[test]
+    fn pls_goes_by_the_numbers() {
+        let entries = parse_pls(
+            "[playlist]
+File2=second.flac
+Title1=First One
+File1=first.flac
+Length1=215
+NumberOfEntries=2
+Version=2",
+        );
+        assert!(entries.len() == 2);
+        assert!(entries[0].location == "first.flac");
+        assert!(entries[0].title == Some("First One".into()));
+        assert!(entries[0].duration_secs == Some(215));
+        assert!(entries[1].location == "second.flac");
+    }
+
+    #[test]
+    fn playlists_become_collections_in_order() -> Result<()> {
+        let (miko, _d) = Miko::construct_connection_shrine(
+            "file:playlist_import?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let library = tempfile::tempdir()?;
+        fs::create_dir(library.path().join("album"))?;
+        for name in ["a.flac", "b.flac", "c.flac"] {
+            fs::write(library.path().join("album").join(name), format!("pretend {} is music", name))?;
+        }
+        let mut container = DirImportManifest::create_from_dir_on_disk(library.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        container.commit_to_db(miko.clone())?;
+
+        // A copy of b that was never imported, so only its hash can find it
+        let elsewhere = tempfile::tempdir()?;
+        fs::write(elsewhere.path().join("b copy.flac"), "pretend b.flac is music")?;
+        let a_path = library.path().join("album/a.flac").canonicalize()?;
+        let playlist_path = library.path().join("mix.m3u");
+        fs::write(
+            &playlist_path,
+            format!(
+                "#EXTM3U\n#EXTINF:180,Somebody - C\nalbum\\c.flac\n{}\nfile://{}\nmissing.flac\nhttp://radio.example/stream\n",
+                a_path.display(),
+                elsewhere.path().join("b copy.flac").canonicalize()?.display().to_string().replace(' ', "%20"),
+            ),
+        )?;
+
+        let report = import_playlist(&miko, &playlist_path)?;
+        assert!(report.collection.name == "mix");
+        assert!(report.added == 3);
+        assert!(report.matched_by_hash == 1);
+        assert!(report.unresolved.len() == 2);
+        assert!(report.unresolved[0].position == 3);
+        assert!(report.unresolved[1].reason == "not a local file");
+
+        let collection_uuid = report.collection.uuid.clone();
+        let page = miko.send_messenger(move |(conn, _)| {
+            Ok(PageOfObjectsInCollection::get_object_page(conn, &collection_uuid, 10, 0)?)
+        })?;
+        let names: Vec<&str> = page.objects.iter().map(|o| o.object_name.as_str()).collect();
+        assert!(names == vec!["c", "a", "b"]);
+        Ok(())
+    }
+}
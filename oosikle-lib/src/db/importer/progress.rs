@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Shared between an import and whoever might want to stop it. Cancelling
+/// doesn't interrupt a file that's already being hashed, it stops the import
+/// before the next one.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What a cancelled import fails with, so callers can tell it apart from a
+/// real failure with `error.is::<ImportCancelled>()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImportCancelled;
+
+impl fmt::Display for ImportCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The import was cancelled")
+    }
+}
+
+impl std::error::Error for ImportCancelled {}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImportStage {
+    #[default]
+    Starting,
+    Hashing,
+    /// Reading the start of each file for its format and encoding
+    Inspecting,
+    Committing,
+    Finished,
+    /// Nothing from the import made it into the db
+    Cancelled,
+}
+
+/// A snapshot of how far along an import is. A new one goes out every time
+/// a file gets hashed or committed, or the stage changes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ImportProgress {
+    pub stage: ImportStage,
+    /// Includes files found inside archives, which only turn up while hashing
+    pub files_discovered: usize,
+    pub files_hashed: usize,
+    /// Of the files hashed, the ones whose hash came from the `HashCache` instead
+    pub files_from_cache: usize,
+    pub files_committed: usize,
+    /// Doesn't count files whose hash came from the cache, since they weren't read
+    pub bytes_processed: u64,
+    /// Blank between files
+    pub current_path: Option<PathBuf>,
+}
+
+/// Carries an import's progress and cancellation through `construct_container_in`
+/// and `commit_with_objects_in`. Use the same job for both to get one continuous
+/// stream of progress.
+#[derive(Debug, Clone, Default)]
+pub struct ImportJob {
+    token: CancellationToken,
+    progress: Arc<Mutex<ImportProgress>>,
+    listener: Option<mpsc::Sender<ImportProgress>>,
+}
+
+impl ImportJob {
+    /// A job along with the receiving end of its progress events
+    pub fn new() -> (Self, mpsc::Receiver<ImportProgress>) {
+        let (tx, rx) = mpsc::channel();
+        (
+            Self {
+                listener: Some(tx),
+                ..Default::default()
+            },
+            rx,
+        )
+    }
+
+    pub fn with_token(mut self, token: CancellationToken) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// The latest snapshot, for anyone polling instead of listening
+    pub fn progress(&self) -> ImportProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    pub(super) fn update(&self, change: impl FnOnce(&mut ImportProgress)) {
+        let mut progress = self.progress.lock().unwrap();
+        change(&mut progress);
+        if let Some(listener) = &self.listener {
+            // Nobody listening anymore is fine, the import carries on regardless
+            let _ = listener.send(progress.clone());
+        }
+    }
+
+    /// Marks the job cancelled, and hands back the error to stop with
+    pub(super) fn cancelled(&self) -> anyhow::Error {
+        self.update(|p| {
+            p.stage = ImportStage::Cancelled;
+            p.current_path = None;
+        });
+        ImportCancelled.into()
+    }
+
+    pub(super) fn bail_if_cancelled(&self) -> anyhow::Result<()> {
+        if self.is_cancelled() {
+            Err(self.cancelled())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod import_progress_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use crate::db::ImportSessionRecord;
+    use crate::miko::{Miko, ShrineDestroyer};
+    use anyhow::Result;
+    use rusqlite::Connection;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+    const IMPORT_PATH_STR: &str = "./src/testing_data/import_test";
+
+    fn init_miko(dbname: &str) -> Result<(Miko<(Connection, Connection)>, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )
+    }
+
+    #[test]
+    fn progress_follows_the_whole_import() -> Result<()> {
+        let (miko, _d) = init_miko("import_progress_events")?;
+        let (job, events) = ImportJob::new();
+        let mut container = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .construct_container_in(&miko, &make_import_id_with_time()?, &job)?;
+        container.give_ids_to_records();
+        container.commit_with_objects_in(miko, vec![], &job)?;
+
+        let events: Vec<ImportProgress> = events.try_iter().collect();
+        assert!(events.iter().any(|e| e.stage == ImportStage::Hashing && e.current_path.is_some()));
+        assert!(events.iter().any(|e| e.stage == ImportStage::Committing && e.files_committed == 3));
+        let last = events.last().unwrap();
+        assert!(*last == job.progress());
+        assert!(last.stage == ImportStage::Finished);
+        assert!(last.files_discovered == 6);
+        assert!(last.files_hashed == 6);
+        assert!(last.files_committed == 6);
+        assert!(last.bytes_processed > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_imports_leave_nothing_behind() -> Result<()> {
+        let (miko, _d) = init_miko("import_progress_cancelled")?;
+        let token = CancellationToken::new();
+        let job = ImportJob::default().with_token(token.clone());
+        let mut container = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .construct_container_in(&miko, &make_import_id_with_time()?, &job)?;
+        container.give_ids_to_records();
+
+        token.cancel();
+        let err = container
+            .commit_with_objects_in(miko.clone(), vec![], &job)
+            .expect_err("A cancelled import shouldn't commit");
+        assert!(err.is::<ImportCancelled>());
+        assert!(job.progress().stage == ImportStage::Cancelled);
+        let sessions = miko.send_messenger(|(conn, _)| ImportSessionRecord::list_all(conn))?;
+        assert!(sessions.is_empty());
+
+        let hashing = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .construct_container_in(&miko, &make_import_id_with_time()?, &job);
+        assert!(hashing.is_err_and(|e| e.is::<ImportCancelled>()));
+        Ok(())
+    }
+}
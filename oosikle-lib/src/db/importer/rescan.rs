@@ -0,0 +1,530 @@
+use anyhow::{anyhow, Result};
+use exemplar::{Model, OnConflict};
+use rayon::prelude::*;
+use relative_path::{PathExt, RelativePath, RelativePathBuf};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use super::{
+    inspect_contents, resolve_media_types, ContentMismatch, DirImportManifest, ExtensionRegistry, HashCache,
+    InboundFileRecordContainer, NewObject, RejectedObject, UnresolvedFile, ARCHIVE_ENTRY_MARKER,
+};
+use crate::db::transaction::{with_transaction, WriterSession};
+use crate::db::{FileRecord, HashCacheRecord};
+use crate::miko::Miko;
+
+type SQMiko = Miko<(Connection, Connection)>;
+
+// Archive entries are left alone, since their dir path has the marker in it.
+// substr instead of like, so a `%` or `_` in the root can't match other dirs
+const GET_FILES_UNDER_DIR_SQL: &str = "select F.* from Files F
+where (F.file_dir_path = ?1 or substr(F.file_dir_path, 1, length(?2)) = ?2)
+and instr(F.file_dir_path, ?3) = 0;";
+
+/// ?1 is the root's canonical path
+const FIND_IMPORT_SESSION_SQL: &str = "select import_session_id from (
+    select W.import_session_id, 0 as preference, '' as imported_at from WatchFolders W
+    where W.watch_folder_path = ?1
+    union all
+    select S.import_session_id, 1, S.import_timestamp from ImportSessions S
+    where S.import_root_dir = ?1 and S.import_rolled_back = 0
+) order by preference, imported_at limit 1;";
+
+const UPDATE_FILE_SQL: &str = "update Files set file_name = ?2, file_size_bytes = ?3,
+file_hash = ?4, file_dir_path = ?5, file_extension_tag = ?6, file_deleted = ?7,
+file_vfs_path = ?8, file_encoding = ?9, file_media_type_id = ?10 where file_uuid = ?1;";
+
+/// What a rescan did. Every record is as it now stands in the db.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RescanReport {
+    pub added: Vec<FileRecord>,
+    /// Same path, different contents. Also covers files that came back after
+    /// being marked deleted.
+    pub changed: Vec<FileRecord>,
+    /// Same contents, different path
+    pub moved: Vec<FileRecord>,
+    pub deleted: Vec<FileRecord>,
+    pub unchanged: usize,
+    /// Added or moved files whose media type couldn't be worked out
+    pub unresolved: Vec<UnresolvedFile>,
+    /// Added or changed files whose contents don't match their extension
+    pub mismatched: Vec<ContentMismatch>,
+}
+
+struct KnownFile {
+    record: FileRecord,
+    seen: bool,
+}
+
+struct FoundFile {
+    rel: RelativePathBuf,
+    path: PathBuf,
+    size: u64,
+}
+
+fn update_file_record(conn: &Connection, record: &FileRecord) -> Result<()> {
+    conn.prepare_cached(UPDATE_FILE_SQL)?.execute(params![
+        record.file_uuid,
+        record.file_name,
+        record.file_size_bytes,
+        record.file_hash,
+        record.file_dir_path,
+        record.file_extension_tag,
+        record.file_deleted,
+        record.file_vfs_path,
+        record.file_encoding,
+        record.file_media_type_id,
+    ])?;
+    Ok(())
+}
+
+/// The VFS dir a file at `rel` under the root gets, going by where it is on disk
+fn vfs_dir_for(import_session_id: &str, rel: &RelativePath) -> String {
+    let vfspathroot = RelativePath::new(import_session_id).join(rel);
+    format!("{}/", vfspathroot.parent().unwrap())
+}
+
+/// Points `record` at `found`, keeping everything that isn't about where the file
+/// is on disk. That includes its VFS path, which is the caller's call.
+fn relocate(mut record: FileRecord, found: &FoundFile, hash: String) -> FileRecord {
+    let full_filename = found.path.file_name().unwrap().to_str().unwrap();
+    let (_, fileext) = match full_filename.split_once(".") {
+        Some(t) => t,
+        None => (full_filename, ""),
+    };
+    record.file_size_bytes = found.size;
+    // Keeps whatever `resolve_media_types` made the tag, unless the name changed
+    if record.file_name != full_filename {
+        record.file_extension_tag = fileext.into();
+    }
+    record.file_name = full_filename.into();
+    record.file_dir_path = found.path.parent().unwrap().to_str().unwrap().to_string();
+    record.file_hash = hash;
+    record.file_deleted = false;
+    record
+}
+
+/// The session a root was imported under. A watch folder's own session comes
+/// first, and otherwise it's the earliest import of the root that's still around.
+fn find_import_session(conn: &Connection, root: &str) -> Result<String> {
+    conn.prepare_cached(FIND_IMPORT_SESSION_SQL)?
+        .query_row([root], |r| r.get::<_, String>(0))
+        .optional()?
+        .ok_or_else(|| anyhow!("{} was never imported, so there's nothing to rescan", root))
+}
+
+/// A rescan that's been worked out but not written yet
+pub struct PendingRescan {
+    report: RescanReport,
+    /// The added files, so they can go through the rest of the import (objects,
+    /// sidecars, artwork) before they're written
+    added: InboundFileRecordContainer,
+    fresh_hashes: Vec<HashCacheRecord>,
+}
+
+impl PendingRescan {
+    pub fn report(&self) -> &RescanReport {
+        &self.report
+    }
+
+    pub fn added(&mut self) -> &mut InboundFileRecordContainer {
+        &mut self.added
+    }
+
+    /// Writes the rescan and `new_objects` for the added files in `session`, the
+    /// same way committing an import does
+    pub fn write_in(
+        self,
+        session: &WriterSession,
+        new_objects: Vec<NewObject>,
+    ) -> Result<(RescanReport, Vec<RejectedObject>)> {
+        let updated: Vec<FileRecord> = self
+            .report
+            .changed
+            .iter()
+            .chain(&self.report.moved)
+            .chain(&self.report.deleted)
+            .cloned()
+            .collect();
+        let fresh_hashes = self.fresh_hashes;
+        session.run(move |conn| {
+            for record in &updated {
+                update_file_record(conn, record)?;
+            }
+            for hash in fresh_hashes {
+                hash.insert_or(conn, OnConflict::Replace)?;
+            }
+            Ok(())
+        })?;
+        let rejected = self.added.commit_in(session, new_objects)?;
+        Ok((self.report, rejected))
+    }
+}
+
+impl DirImportManifest {
+    /// Brings the records under an already-imported root up to date with what's
+    /// on disk, keeping the UUIDs of files that are still there. Files the
+    /// `HashCache` says haven't changed since they were last hashed aren't rehashed.
+    ///
+    /// New files land in the VFS dir of the session the root was imported under.
+    /// Files that stayed put on disk keep whatever VFS path they have now. The
+    /// walking and hashing happen first, and only the writes after them hold the
+    /// writer, all in one transaction.
+    pub fn rescan(self, miko: &SQMiko) -> Result<RescanReport> {
+        let pending = self.prepare_rescan(miko)?;
+        with_transaction(&miko.background(), |session| Ok(pending.write_in(session, vec![])?.0))
+    }
+
+    /// Works out what `rescan` would write, without holding the writer. Anything
+    /// that changes under the root before it's written gets caught next time.
+    pub fn prepare_rescan(self, miko: &SQMiko) -> Result<PendingRescan> {
+        let root = self.root_dir.canonicalize()?;
+        let root_str = root
+            .to_str()
+            .ok_or(anyhow!("{:?} isn't valid unicode", root))?
+            .to_string();
+        let under_root = format!("{}{}", root_str, std::path::MAIN_SEPARATOR);
+        let session_root = root.clone();
+        // Bulk reading, so it shouldn't hold up page loads
+        let (import_session_id, mut known, registry, cache) = miko.background().send_messenger(move |(conn, _)| {
+            let import_session_id = find_import_session(conn, &root_str)?;
+            let registry = ExtensionRegistry::load(conn)?;
+            let mut stmt = conn.prepare_cached(GET_FILES_UNDER_DIR_SQL)?;
+            let rows = stmt.query_map(params![root_str, under_root, ARCHIVE_ENTRY_MARKER], |row| {
+                Ok(KnownFile {
+                    record: FileRecord::from_row(row)?,
+                    seen: false,
+                })
+            })?;
+            let known = rows.collect::<rusqlite::Result<Vec<KnownFile>>>()?;
+            Ok((import_session_id, known, registry, HashCache::load(conn)?))
+        })?;
+        let known_by_path: HashMap<(String, String), usize> = known
+            .iter()
+            .enumerate()
+            .map(|(i, k)| ((k.record.file_dir_path.clone(), k.record.file_name.clone()), i))
+            .collect();
+
+        let found: Vec<FoundFile> = self
+            .items
+            .iter()
+            .filter_map(|rel| {
+                let path = rel.to_path(&root).canonicalize().ok()?;
+                let md = fs::metadata(&path).ok()?;
+                md.is_file().then(|| FoundFile {
+                    rel: rel.clone(),
+                    size: md.len(),
+                    path,
+                })
+            })
+            .collect();
+
+        let mut report = RescanReport::default();
+        let mut to_hash: Vec<(FoundFile, Option<usize>)> = vec![];
+        for f in found {
+            let key = (
+                f.path.parent().unwrap().to_str().unwrap().to_string(),
+                f.path.file_name().unwrap().to_str().unwrap().to_string(),
+            );
+            match known_by_path.get(&key) {
+                Some(&i) => {
+                    known[i].seen = true;
+                    to_hash.push((f, Some(i)));
+                }
+                None => to_hash.push((f, None)),
+            }
+        }
+        // Files that haven't changed since they were last hashed come straight out of the cache
+        let hashed: Vec<(FoundFile, Option<usize>, String, Option<HashCacheRecord>)> = to_hash
+            .into_par_iter()
+            .map(|(f, i)| {
+                let cached = cache.hash(&f.path, &[]);
+                (f, i, cached.hash, cached.fresh)
+            })
+            .collect();
+
+        let mut fresh_hashes: Vec<HashCacheRecord> = vec![];
+        let mut unknown: Vec<(FoundFile, String)> = vec![];
+        for (f, i, hash, fresh) in hashed {
+            fresh_hashes.extend(fresh);
+            let Some(i) = i else {
+                unknown.push((f, hash));
+                continue;
+            };
+            let k = &known[i];
+            if k.record.file_deleted || k.record.file_hash != hash || k.record.file_size_bytes != f.size {
+                report.changed.push(relocate(k.record.clone(), &f, hash));
+            } else {
+                report.unchanged += 1;
+            }
+        }
+
+        // Whatever went missing and has the same contents as a new file was moved there
+        let mut missing_by_hash: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, k) in known.iter().enumerate() {
+            if !k.seen && !k.record.file_deleted && !k.record.file_hash.is_empty() {
+                missing_by_hash.entry(k.record.file_hash.clone()).or_default().push(i);
+            }
+        }
+        for (f, hash) in unknown {
+            match missing_by_hash.get_mut(&hash).and_then(|v| v.pop()) {
+                Some(i) => {
+                    known[i].seen = true;
+                    let old = &known[i].record;
+                    // Only follow the file on disk if it was still where the import put it
+                    let old_rel = Path::new(&old.file_dir_path)
+                        .join(&old.file_name)
+                        .relative_to(&root)
+                        .ok();
+                    let vfs_untouched = old_rel.is_some_and(|r| old.file_vfs_path == vfs_dir_for(&import_session_id, &r));
+                    let mut moved = relocate(old.clone(), &f, hash);
+                    if vfs_untouched {
+                        moved.file_vfs_path = vfs_dir_for(&import_session_id, &f.rel);
+                    }
+                    report.moved.push(moved);
+                }
+                None => {
+                    let fresh = relocate(
+                        FileRecord {
+                            file_uuid: Uuid::now_v7().simple().to_string(),
+                            file_name: "".into(),
+                            file_size_bytes: 0,
+                            file_hash: "".into(),
+                            file_dir_path: "".into(),
+                            file_extension_tag: "".into(),
+                            file_encoding: "".into(),
+                            media_type_override_id: None,
+                            file_media_type_id: None,
+                            file_deleted: false,
+                            file_read_only: false,
+                            file_vfs_path: vfs_dir_for(&import_session_id, &f.rel),
+                        },
+                        &f,
+                        hash,
+                    );
+                    report.added.push(fresh);
+                }
+            }
+        }
+        report.deleted = known
+            .into_iter()
+            .filter(|k| !k.seen && !k.record.file_deleted)
+            .map(|k| FileRecord {
+                file_deleted: true,
+                ..k.record
+            })
+            .collect();
+
+        // Changed files kept their names, so only these could have a new type
+        let added_resolution = resolve_media_types(&mut report.added, &registry, true);
+        report.unresolved = added_resolution.unresolved.clone();
+        report
+            .unresolved
+            .extend(resolve_media_types(&mut report.moved, &registry, true).unresolved);
+        // Only these have new contents to look at
+        let added_inspection = inspect_contents(&mut report.added);
+        report.mismatched = added_inspection.mismatches.clone();
+        report
+            .mismatched
+            .extend(inspect_contents(&mut report.changed).mismatches);
+
+        let added = InboundFileRecordContainer {
+            root_dir: session_root,
+            import_session_id,
+            records: report.added.clone(),
+            artwork: vec![],
+            skipped: vec![],
+            fresh_hashes: vec![],
+            digests: HashMap::new(),
+            type_resolution: Some(added_resolution),
+            inspection: added_inspection,
+        };
+        Ok(PendingRescan {
+            report,
+            added,
+            fresh_hashes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod rescan_tests {
+    use super::*;
+    use crate::db::{Fetchable1, ImportSessionRecord};
+    use crate::db::importer::make_import_id_with_time;
+    use crate::miko::ShrineDestroyer;
+    use std::path::Path;
+
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+
+    fn init_miko(dbname: &str) -> Result<(SQMiko, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            INIT_DB_STR,
+        )
+    }
+
+    fn import(root: &Path, import_id: &str, miko: &SQMiko) -> Result<Vec<FileRecord>> {
+        let mut container = DirImportManifest::create_from_dir_on_disk(root.into())?
+            .construct_container(import_id)?;
+        container.give_ids_to_records();
+        let records = container.records.clone();
+        container.commit_to_db(miko.clone())?;
+        Ok(records)
+    }
+
+    fn uuid_of(records: &[FileRecord], name: &str) -> String {
+        records
+            .iter()
+            .find(|r| r.file_name == name)
+            .map(|r| r.file_uuid.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn rescan_keeps_uuids_and_notices_everything() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(dir.path().join("same.txt"), "nothing happens to me")?;
+        fs::write(dir.path().join("edited.txt"), "before")?;
+        fs::write(dir.path().join("gone.txt"), "soon to be deleted")?;
+        fs::write(dir.path().join("sub/wanderer.txt"), "I get moved")?;
+        let (miko, _d) = init_miko("rescan_everything")?;
+        let import_id = make_import_id_with_time()?;
+        let first = import(dir.path(), &import_id, &miko)?;
+
+        fs::write(dir.path().join("edited.txt"), "after, and longer")?;
+        fs::remove_file(dir.path().join("gone.txt"))?;
+        fs::rename(dir.path().join("sub/wanderer.txt"), dir.path().join("wanderer.txt"))?;
+        fs::write(dir.path().join("sub/new.txt"), "brand new")?;
+
+        let report = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .rescan(&miko)?;
+        assert!(report.unchanged == 1);
+        assert!(report.changed.len() == 1);
+        assert!(report.changed[0].file_uuid == uuid_of(&first, "edited.txt"));
+        assert!(report.moved.len() == 1);
+        assert!(report.moved[0].file_uuid == uuid_of(&first, "wanderer.txt"));
+        assert!(report.moved[0].file_vfs_path == format!("{}/", import_id));
+        assert!(report.deleted.len() == 1);
+        assert!(report.deleted[0].file_uuid == uuid_of(&first, "gone.txt"));
+        assert!(report.added.len() == 1);
+        assert!(report.added[0].file_vfs_path == format!("{}/sub/", import_id));
+        assert!(report.added[0].file_encoding == "UTF-8");
+
+        let detail_id = import_id.clone();
+        let session = miko
+            .send_messenger(move |(conn, _)| ImportSessionRecord::get_detail(conn, &detail_id))?
+            .expect("The import session wasn't recorded");
+        // Four from the first import, and the one the rescan found
+        assert!(session.session.import_file_count == 5);
+
+        let gone_uuid = uuid_of(&first, "gone.txt");
+        let gone = miko.send_messenger(move |(conn, _)| Ok(FileRecord::get_from_id(conn, &gone_uuid)?))?;
+        assert!(gone.is_some_and(|r| r.file_deleted));
+        Ok(())
+    }
+
+    #[test]
+    fn second_rescan_finds_nothing_to_do() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.txt"), "a")?;
+        fs::write(dir.path().join("b.txt"), "b")?;
+        let (miko, _d) = init_miko("rescan_nothing")?;
+        let import_id = make_import_id_with_time()?;
+        import(dir.path(), &import_id, &miko)?;
+        fs::remove_file(dir.path().join("b.txt"))?;
+        let first = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .rescan(&miko)?;
+        assert!(first.deleted.len() == 1);
+        let second = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .rescan(&miko)?;
+        assert!(second.unchanged == 1);
+        assert!(second.added.is_empty() && second.changed.is_empty());
+        assert!(second.moved.is_empty() && second.deleted.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rescans_take_hashes_from_the_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.txt"), "a")?;
+        let (miko, _d) = init_miko("rescan_hash_cache")?;
+        import(dir.path(), &make_import_id_with_time()?, &miko)?;
+        // Only a rescan that trusts the cache would believe this
+        miko.send_mutating_messenger(|(_, conn)| Ok(conn.execute("update HashCache set file_hash = 'cached';", [])?))?;
+        let report = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .rescan(&miko)?;
+        assert!(report.changed.len() == 1);
+        assert!(report.changed[0].file_hash == "cached");
+        Ok(())
+    }
+
+    #[test]
+    fn edited_files_keep_their_extension_tags() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("notes.md"), "before")?;
+        let (miko, _d) = Miko::construct_connection_shrine(
+            "file:rescan_keeps_tags?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let first = import(dir.path(), &make_import_id_with_time()?, &miko)?;
+        assert!(first[0].file_extension_tag == "MD");
+        fs::write(dir.path().join("notes.md"), "after, and longer")?;
+        let report = DirImportManifest::create_from_dir_on_disk(dir.path().into())?.rescan(&miko)?;
+        assert!(report.changed.len() == 1);
+        assert!(report.changed[0].file_extension_tag == "MD");
+        let uuid = first[0].file_uuid.clone();
+        let stored = miko.send_messenger(move |(conn, _)| Ok(FileRecord::get_from_id(conn, &uuid)?))?;
+        assert!(stored.is_some_and(|r| r.file_extension_tag == "MD"));
+        Ok(())
+    }
+
+    #[test]
+    fn rescan_leaves_vfs_moves_alone() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("edited.txt"), "before")?;
+        fs::write(dir.path().join("wanderer.txt"), "I get moved")?;
+        let (miko, _d) = init_miko("rescan_vfs_moves")?;
+        let import_id = make_import_id_with_time()?;
+        let first = import(dir.path(), &import_id, &miko)?;
+        let edited_uuid = uuid_of(&first, "edited.txt");
+        let wanderer_uuid = uuid_of(&first, "wanderer.txt");
+        let to_move = [edited_uuid.clone(), wanderer_uuid.clone()];
+        miko.send_messenger(move |(_, conn)| {
+            for uuid in &to_move {
+                conn.execute("update Files set file_vfs_path = 'shelf/' where file_uuid = ?1", [uuid])?;
+            }
+            Ok(())
+        })?;
+
+        fs::write(dir.path().join("edited.txt"), "after, and longer")?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::rename(dir.path().join("wanderer.txt"), dir.path().join("sub/wanderer.txt"))?;
+        let report = DirImportManifest::create_from_dir_on_disk(dir.path().into())?.rescan(&miko)?;
+        assert!(report.changed.len() == 1 && report.changed[0].file_uuid == edited_uuid);
+        assert!(report.changed[0].file_vfs_path == "shelf/");
+        assert!(report.moved.len() == 1 && report.moved[0].file_uuid == wanderer_uuid);
+        assert!(report.moved[0].file_vfs_path == "shelf/");
+        Ok(())
+    }
+
+    #[test]
+    fn roots_that_were_never_imported_cant_be_rescanned() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.txt"), "a")?;
+        let (miko, _d) = init_miko("rescan_unknown_root")?;
+        let res = DirImportManifest::create_from_dir_on_disk(dir.path().into())?.rescan(&miko);
+        assert!(res.is_err());
+        let nothing = miko.send_messenger(|(conn, _)| {
+            Ok(conn.query_row("select count(*) from Files", [], |r| r.get::<_, i64>(0))?)
+        })?;
+        assert!(nothing == 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::Result;
+use exemplar::Model;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::db::{fetch_vec_of, FileRecord, Fetchable1, ImportSessionRecord};
+
+const NOTE_SESSION_SQL: &str = "insert or ignore into ImportSessions values (?1, ?2, ?3, 0, false);";
+const NOTE_SESSION_FILE_SQL: &str = "insert or ignore into FilesInImportSessions values (?1, ?2);";
+const RECOUNT_SESSION_SQL: &str = "update ImportSessions set import_file_count = (
+    select count(*) from FilesInImportSessions FS where FS.import_session_id = ?1
+) where import_session_id = ?1;";
+const LIST_SESSIONS_SQL: &str = "select * from ImportSessions order by import_timestamp desc;";
+const GET_SESSION_FILES_SQL: &str = "select F.* from Files F
+inner join FilesInImportSessions FS on FS.file_uuid = F.file_uuid
+where FS.import_session_id = ?
+order by F.file_vfs_path, F.file_name;";
+
+/// Every statement takes the session id as ?1
+const TRASH_SESSION_SQL: [&str; 3] = [
+    "update Files set file_deleted = true where file_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "update Objects set object_deleted = true where object_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "update ImportSessions set import_rolled_back = true where import_session_id = ?1;",
+];
+const REMOVE_SESSION_SQL: [&str; 11] = [
+    "delete from ObjectsInCollections where object_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from ObjectAttributes where object_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from ExtraFilesForObjects where object_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1)
+        or file_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from FileArtwork where file_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1)
+        or artwork_file_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from FileBlobs where file_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from FileDigests where file_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from Objects where object_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from Files where file_uuid in (select file_uuid from FilesInImportSessions where import_session_id = ?1);",
+    "delete from FilesInImportSessions where import_session_id = ?1;",
+    "update ImportSessions set import_rolled_back = true where import_session_id = ?1;",
+    "update ImportSessions set import_file_count = 0 where import_session_id = ?1;",
+];
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum RollbackMode {
+    /// Marks the files and their objects deleted, so they can still be brought back
+    Trash,
+    /// Takes the files, their objects and everything hanging off them out of the db.
+    /// Nothing on disk is touched either way.
+    Remove,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ImportSessionDetail {
+    pub session: ImportSessionRecord,
+    pub files: Vec<FileRecord>,
+}
+
+/// Creates the session if this is the first we've heard of it, then links the files to it
+pub(super) fn note_session_files<'a>(
+    conn: &Connection,
+    import_session_id: &str,
+    root_dir: &str,
+    file_uuids: impl IntoIterator<Item = &'a str>,
+) -> Result<()> {
+    conn.prepare_cached(NOTE_SESSION_SQL)?.execute(params![
+        import_session_id,
+        root_dir,
+        OffsetDateTime::now_utc()
+    ])?;
+    let mut link_stmt = conn.prepare_cached(NOTE_SESSION_FILE_SQL)?;
+    for file_uuid in file_uuids {
+        link_stmt.execute(params![import_session_id, file_uuid])?;
+    }
+    conn.prepare_cached(RECOUNT_SESSION_SQL)?
+        .execute(params![import_session_id])?;
+    Ok(())
+}
+
+impl ImportSessionRecord {
+    /// Newest first
+    pub fn list_all(conn: &Connection) -> Result<Vec<ImportSessionRecord>> {
+        let mut stmt = conn.prepare_cached(LIST_SESSIONS_SQL)?;
+        let rows = stmt.query_map([], ImportSessionRecord::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn get_files(&self, conn: &Connection) -> Result<Vec<FileRecord>> {
+        Ok(fetch_vec_of(conn, &self.import_session_id, GET_SESSION_FILES_SQL)?)
+    }
+
+    pub fn get_detail(conn: &Connection, import_session_id: &str) -> Result<Option<ImportSessionDetail>> {
+        Ok(match ImportSessionRecord::get_from_id(conn, import_session_id)? {
+            Some(session) => Some(ImportSessionDetail {
+                files: session.get_files(conn)?,
+                session,
+            }),
+            None => None,
+        })
+    }
+
+    pub fn rollback(&self, conn: &mut Connection, mode: RollbackMode) -> Result<()> {
+        let statements: &[&str] = match mode {
+            RollbackMode::Trash => &TRASH_SESSION_SQL,
+            RollbackMode::Remove => &REMOVE_SESSION_SQL,
+        };
+        let tx = conn.transaction()?;
+        for sql in statements {
+            tx.prepare_cached(sql)?
+                .execute(params![self.import_session_id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod import_session_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DigestAlgorithm, DirImportManifest};
+    use crate::miko::{Miko, ShrineDestroyer};
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+    const IMPORT_PATH_STR: &str = "./src/testing_data/import_test";
+
+    fn init_miko(dbname: &str) -> Result<(Miko<(Connection, Connection)>, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )
+    }
+
+    fn import_test_dir(miko: &Miko<(Connection, Connection)>) -> Result<String> {
+        let import_id = make_import_id_with_time()?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .construct_container(&import_id)?;
+        container.give_ids_to_records();
+        container.commit_to_db(miko.clone())?;
+        Ok(import_id)
+    }
+
+    fn detail(miko: &Miko<(Connection, Connection)>, import_id: &str) -> Result<ImportSessionDetail> {
+        let import_id = import_id.to_string();
+        Ok(miko
+            .send_messenger(move |(conn, _)| ImportSessionRecord::get_detail(conn, &import_id))?
+            .expect("The session wasn't recorded"))
+    }
+
+    #[test]
+    fn committed_imports_are_listed_with_their_files() -> Result<()> {
+        let (miko, _d) = init_miko("import_sessions_listed")?;
+        let import_id = import_test_dir(&miko)?;
+        let sessions = miko.send_messenger(|(conn, _)| ImportSessionRecord::list_all(conn))?;
+        assert!(sessions.len() == 1);
+        assert!(sessions[0].import_session_id == import_id);
+        assert!(sessions[0].import_file_count == 6);
+        assert!(!sessions[0].import_rolled_back);
+        let found = detail(&miko, &import_id)?;
+        assert!(found.files.len() == 6);
+        assert!(found.files.iter().all(|f| f.file_vfs_path.starts_with(&import_id)));
+        Ok(())
+    }
+
+    fn count_files(miko: &Miko<(Connection, Connection)>) -> Result<i64> {
+        miko.send_messenger(|(conn, _)| {
+            Ok(conn.query_row("select count(*) from Files", [], |r| r.get::<_, i64>(0))?)
+        })
+    }
+
+    #[test]
+    fn trashed_sessions_can_then_be_removed() -> Result<()> {
+        let (miko, _d) = init_miko("import_sessions_rollback")?;
+        let before_import = count_files(&miko)?;
+        let import_id = import_test_dir(&miko)?;
+        let session = detail(&miko, &import_id)?.session;
+
+        let to_trash = session.clone();
+        miko.send_mutating_messenger(move |(_, conn)| to_trash.rollback(conn, RollbackMode::Trash))?;
+        let trashed = detail(&miko, &import_id)?;
+        assert!(trashed.session.import_rolled_back);
+        assert!(trashed.files.len() == 6);
+        assert!(trashed.files.iter().all(|f| f.file_deleted));
+
+        miko.send_mutating_messenger(move |(_, conn)| session.rollback(conn, RollbackMode::Remove))?;
+        let removed = detail(&miko, &import_id)?;
+        assert!(removed.files.is_empty());
+        assert!(removed.session.import_file_count == 0);
+        // The files from testing_values.sql belong to no session, so they're untouched
+        assert!(count_files(&miko)? == before_import);
+        Ok(())
+    }
+
+    #[test]
+    fn removed_sessions_take_their_digests_with_them() -> Result<()> {
+        let (miko, _d) = init_miko("import_sessions_digests")?;
+        let count_digests = || {
+            miko.send_messenger(|(conn, _)| {
+                Ok(conn.query_row("select count(*) from FileDigests", [], |r| r.get::<_, i64>(0))?)
+            })
+        };
+        let before_import = count_digests()?;
+        let import_id = make_import_id_with_time()?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .with_digests(&DigestAlgorithm::ALL)
+            .construct_container(&import_id)?;
+        container.give_ids_to_records();
+        container.commit_to_db(miko.clone())?;
+        assert!(count_digests()? > before_import);
+
+        let session = detail(&miko, &import_id)?.session;
+        miko.send_mutating_messenger(move |(_, conn)| session.rollback(conn, RollbackMode::Remove))?;
+        assert!(count_digests()? == before_import);
+        Ok(())
+    }
+}
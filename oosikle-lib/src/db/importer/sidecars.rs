@@ -0,0 +1,493 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::{Encoding, UTF_8};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use time::{Date, Month, OffsetDateTime};
+
+use super::{FileSource, NewObject};
+use crate::db::{AttrValue, FileRecord, ObjectAttr, ObjectExtraFileRecord};
+
+/// Who objects made only because a sidecar described a file are managed by
+pub const SIDECAR_PLUGIN_PACKAGE: &str = "oosikle.builtin.sidecars";
+
+/// Nobody writes sidecars this big by hand, so anything past it is something else
+const SIDECAR_SIZE_LIMIT: u64 = 1024 * 1024;
+
+/// Names that describe everything else in their dir, rather than one file.
+/// `readme*.txt` counts too.
+const DIR_SIDECAR_NAMES: [&str; 6] = ["metadata.opf", "movie.nfo", "album.nfo", "tvshow.nfo", "info.json", "metadata.json"];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SidecarKind {
+    Nfo,
+    Json,
+    Opf,
+    Cue,
+    Readme,
+}
+
+impl SidecarKind {
+    fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "nfo" => Some(Self::Nfo),
+            "json" => Some(Self::Json),
+            "opf" => Some(Self::Opf),
+            "cue" => Some(Self::Cue),
+            "txt" => Some(Self::Readme),
+            _ => None,
+        }
+    }
+
+    fn note(&self) -> &'static str {
+        match self {
+            Self::Nfo => "nfo sidecar",
+            Self::Json => "json sidecar",
+            Self::Opf => "opf sidecar",
+            Self::Cue => "cue sheet",
+            Self::Readme => "readme",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SidecarLink {
+    pub sidecar_uuid: String,
+    pub object_uuid: String,
+    pub kind: SidecarKind,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct SidecarReport {
+    pub linked: Vec<SidecarLink>,
+    /// Sidecars that were linked, but couldn't be read or made no sense. The uuid and why.
+    pub unparsed: Vec<(String, String)>,
+}
+
+/// What a sidecar had to say. Blank means it didn't say.
+#[derive(Debug, Clone, PartialEq, Default)]
+struct SidecarFields {
+    name: String,
+    artist: String,
+    album_name: String,
+    album_position: Option<u32>,
+    genre: String,
+    language: String,
+    imprint: String,
+    website: String,
+    publish_timestamp: Option<OffsetDateTime>,
+    attributes: Vec<(String, AttrValue)>,
+}
+
+fn last_extension(file_name: &str) -> Option<(&str, String)> {
+    file_name
+        .rsplit_once('.')
+        .map(|(stem, ext)| (stem, ext.to_lowercase()))
+}
+
+/// `1999`, `1999-05` or `1999-05-01`, with whatever comes after ignored
+fn parse_date(value: &str) -> Option<OffsetDateTime> {
+    let mut parts = value.trim().splitn(3, ['-', '/']);
+    let year: i32 = parts.next()?.get(..4)?.parse().ok()?;
+    let leading_number = |part: &str| part.chars().take(2).collect::<String>().parse::<u8>().ok();
+    let month = parts.next().and_then(leading_number).unwrap_or(1);
+    let day = parts.next().and_then(leading_number).unwrap_or(1);
+    let date = Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()?;
+    Some(date.midnight().assume_utc())
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The text inside the first `<tag>` in the document, whether or not it has
+/// attributes. Sidecars are simple enough that this is all the XML we need.
+fn xml_tag_text(doc: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut search_from = 0;
+    while let Some(found) = doc[search_from..].find(&open) {
+        let start = search_from + found + open.len();
+        search_from = start;
+        // Stops `<title` from matching `<titles>`
+        let after = doc[start..].chars().next()?;
+        if after != '>' && !after.is_whitespace() {
+            continue;
+        }
+        let content_start = start + doc[start..].find('>')? + 1;
+        let content_end = content_start + doc[content_start..].find(&close)?;
+        let text = unescape_xml(doc[content_start..content_end].trim());
+        return (!text.is_empty()).then_some(text);
+    }
+    None
+}
+
+fn first_xml_tag(doc: &str, tags: &[&str]) -> String {
+    tags.iter().find_map(|t| xml_tag_text(doc, t)).unwrap_or_default()
+}
+
+/// Kodi style `.nfo`. The ones that are just release notes don't have any tags,
+/// and only get linked.
+fn parse_nfo(doc: &str) -> SidecarFields {
+    let mut fields = SidecarFields {
+        name: first_xml_tag(doc, &["title"]),
+        artist: first_xml_tag(doc, &["artist", "director", "developer", "author"]),
+        album_name: first_xml_tag(doc, &["album", "set", "showtitle"]),
+        album_position: xml_tag_text(doc, "track")
+            .or_else(|| xml_tag_text(doc, "episode"))
+            .and_then(|t| t.parse().ok()),
+        genre: first_xml_tag(doc, &["genre"]),
+        imprint: first_xml_tag(doc, &["studio", "publisher", "label"]),
+        website: first_xml_tag(doc, &["website", "url"]),
+        publish_timestamp: ["premiered", "releasedate", "aired", "year"]
+            .iter()
+            .find_map(|t| parse_date(&xml_tag_text(doc, t)?)),
+        ..Default::default()
+    };
+    for tag in ["plot", "outline", "rating", "runtime", "mpaa"] {
+        if let Some(value) = xml_tag_text(doc, tag) {
+            fields.attributes.push((tag.to_string(), AttrValue::STRING(value)));
+        }
+    }
+    fields
+}
+
+/// Calibre's `metadata.opf`, and the package file inside an epub
+fn parse_opf(doc: &str) -> SidecarFields {
+    let mut fields = SidecarFields {
+        name: first_xml_tag(doc, &["dc:title"]),
+        artist: first_xml_tag(doc, &["dc:creator"]),
+        genre: first_xml_tag(doc, &["dc:subject"]),
+        language: first_xml_tag(doc, &["dc:language"]),
+        imprint: first_xml_tag(doc, &["dc:publisher"]),
+        publish_timestamp: xml_tag_text(doc, "dc:date").and_then(|d| parse_date(&d)),
+        ..Default::default()
+    };
+    for (tag, attribute) in [("dc:identifier", "identifier"), ("dc:description", "description")] {
+        if let Some(value) = xml_tag_text(doc, tag) {
+            fields.attributes.push((attribute.to_string(), AttrValue::STRING(value)));
+        }
+    }
+    fields
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_matches('"').to_string()
+}
+
+/// Only the disc level `TITLE` and `PERFORMER` describe the album. The ones
+/// under each `TRACK` are for that track.
+fn parse_cue(doc: &str) -> SidecarFields {
+    let mut fields = SidecarFields::default();
+    let mut tracks: i64 = 0;
+    for line in doc.lines().map(|l| l.trim()) {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match command.to_uppercase().as_str() {
+            "TRACK" => tracks += 1,
+            "TITLE" if tracks == 0 => fields.album_name = unquote(rest),
+            "PERFORMER" if tracks == 0 => fields.artist = unquote(rest),
+            "REM" => {
+                let (key, value) = rest.split_once(' ').unwrap_or((rest, ""));
+                match key.to_uppercase().as_str() {
+                    "GENRE" => fields.genre = unquote(value),
+                    "DATE" => fields.publish_timestamp = parse_date(&unquote(value)),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    fields.name = fields.album_name.clone();
+    fields.attributes.push(("track_count".to_string(), AttrValue::INT(tracks)));
+    fields
+}
+
+fn json_string(object: &serde_json::Map<String, JsonValue>, keys: &[&str]) -> String {
+    keys.iter()
+        .find_map(|k| match object.get(*k)? {
+            JsonValue::String(s) => Some(s.clone()),
+            JsonValue::Number(n) => Some(n.to_string()),
+            // Lists of authors and the like
+            JsonValue::Array(a) => a.first()?.as_str().map(|s| s.to_string()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+const JSON_COLUMN_KEYS: [&str; 20] = [
+    "name", "title", "artist", "author", "authors", "creator", "album", "genre", "track", "language",
+    "publisher", "imprint", "label", "website", "url", "date", "year", "released", "release_date", "series",
+];
+
+/// A flat object. Keys that match a column go there, and every other key with a
+/// plain value becomes an attribute.
+fn parse_json(doc: &str) -> Result<SidecarFields> {
+    let JsonValue::Object(object) = serde_json::from_str(doc)? else {
+        return Err(anyhow!("A json sidecar should hold an object"));
+    };
+    let mut fields = SidecarFields {
+        name: json_string(&object, &["name", "title"]),
+        artist: json_string(&object, &["artist", "author", "authors", "creator"]),
+        album_name: json_string(&object, &["album", "series"]),
+        album_position: json_string(&object, &["track"]).parse().ok(),
+        genre: json_string(&object, &["genre"]),
+        language: json_string(&object, &["language"]),
+        imprint: json_string(&object, &["publisher", "imprint", "label"]),
+        website: json_string(&object, &["website", "url"]),
+        publish_timestamp: parse_date(&json_string(&object, &["date", "release_date", "released", "year"])),
+        ..Default::default()
+    };
+    for (key, value) in object.iter().filter(|(k, _)| !JSON_COLUMN_KEYS.contains(&k.as_str())) {
+        let value = match value {
+            JsonValue::String(s) => AttrValue::STRING(s.clone()),
+            JsonValue::Number(n) => match n.as_i64() {
+                Some(i) => AttrValue::INT(i),
+                None => AttrValue::FLOAT(n.as_f64().unwrap_or_default()),
+            },
+            JsonValue::Bool(b) => AttrValue::INT(*b as i64),
+            _ => continue,
+        };
+        fields.attributes.push((key.clone(), value));
+    }
+    Ok(fields)
+}
+
+fn read_sidecar(record: &FileRecord, kind: SidecarKind) -> Result<SidecarFields> {
+    if record.file_size_bytes > SIDECAR_SIZE_LIMIT {
+        return Err(anyhow!("Too big to be a sidecar"));
+    }
+    let bytes = FileSource::of(record).read_to_vec()?;
+    // `file_encoding` is set by `inspect_contents`, and .nfo files in particular
+    // are often not UTF-8
+    let encoding = Encoding::for_label(record.file_encoding.as_bytes()).unwrap_or(UTF_8);
+    let (doc, _, _) = encoding.decode(&bytes);
+    Ok(match kind {
+        SidecarKind::Nfo => parse_nfo(&doc),
+        SidecarKind::Json => parse_json(&doc)?,
+        SidecarKind::Opf => parse_opf(&doc),
+        SidecarKind::Cue => parse_cue(&doc),
+        SidecarKind::Readme => SidecarFields::default(),
+    })
+}
+
+fn fill_blank(column: &mut String, value: String) {
+    if column.trim().is_empty() && !value.trim().is_empty() {
+        *column = value;
+    }
+}
+
+/// Whatever the object already has stays, since an adapter looked at the file
+/// itself to come up with it. The sidecar fills in the rest.
+fn merge_into(new_object: &mut NewObject, fields: SidecarFields) {
+    let object = &mut new_object.object;
+    fill_blank(&mut object.object_name, fields.name);
+    fill_blank(&mut object.object_artist, fields.artist);
+    fill_blank(&mut object.object_album_name, fields.album_name);
+    fill_blank(&mut object.object_genre, fields.genre);
+    fill_blank(&mut object.object_imprint, fields.imprint);
+    fill_blank(&mut object.object_website, fields.website);
+    if !fields.language.trim().is_empty() && object.object_language == "en" {
+        object.object_language = fields.language;
+    }
+    if let Some(position) = fields.album_position.filter(|_| object.object_album_position == 0) {
+        object.object_album_position = position;
+    }
+    if let Some(ts) = fields.publish_timestamp.filter(|_| object.object_publish_timestamp == OffsetDateTime::UNIX_EPOCH) {
+        object.object_publish_timestamp = ts;
+    }
+    for (attribute_name, attribute_value) in fields.attributes {
+        if new_object.attributes.iter().all(|a| a.attribute_name != attribute_name) {
+            new_object.attributes.push(ObjectAttr {
+                object_uuid: new_object.object.object_uuid.clone(),
+                attribute_name,
+                attribute_value,
+            });
+        }
+    }
+}
+
+/// The record a sidecar describes, if it's clear which one that is
+fn main_file_for<'a>(
+    sidecar: &FileRecord,
+    kind: SidecarKind,
+    neighbors: &[&'a FileRecord],
+    has_object: impl Fn(&str) -> bool,
+) -> Option<&'a FileRecord> {
+    let lowered = sidecar.file_name.to_lowercase();
+    let is_dir_sidecar = DIR_SIDECAR_NAMES.contains(&lowered.as_str())
+        || (kind == SidecarKind::Readme && lowered.starts_with("readme"));
+    let not_sidecars = neighbors.iter().copied().filter(|n| {
+        n.file_uuid != sidecar.file_uuid
+            && last_extension(&n.file_name).is_none_or(|(_, ext)| SidecarKind::from_extension(&ext).is_none())
+    });
+    if is_dir_sidecar {
+        let candidates: Vec<&FileRecord> = not_sidecars.collect();
+        let with_objects: Vec<&FileRecord> = candidates.iter().copied().filter(|c| has_object(&c.file_uuid)).collect();
+        return match (with_objects.as_slice(), candidates.as_slice()) {
+            ([only], _) => Some(*only),
+            ([], [only]) => Some(*only),
+            _ => None,
+        };
+    }
+    // `Movie.nfo` goes with `Movie.mkv`, and `game.p8.json` with `game.p8.png`.
+    // When a cue sheet has both a .bin and a .log next to it, the biggest is the one.
+    let (stem, _) = last_extension(&sidecar.file_name)?;
+    let prefix = format!("{}.", stem.to_lowercase());
+    not_sidecars
+        .filter(|n| n.file_name.to_lowercase().starts_with(&prefix))
+        .max_by_key(|n| n.file_size_bytes)
+}
+
+/// Finds the sidecars among `records`, folds what they say into the object of the
+/// file they describe (making one if there isn't one yet), and links each sidecar
+/// to that object as an extra file. Objects that were made for the sidecars
+/// themselves are dropped, since they aren't really anything on their own.
+/// `.txt` files only count as sidecars when they're a readme or share a name with
+/// another file, so plain text documents are left alone.
+pub fn apply_sidecars(records: &[FileRecord], objects: &mut Vec<NewObject>) -> SidecarReport {
+    let mut report = SidecarReport::default();
+    let mut by_dir: HashMap<&str, Vec<&FileRecord>> = HashMap::new();
+    for record in records {
+        by_dir.entry(record.file_vfs_path.as_str()).or_default().push(record);
+    }
+    let mut found = vec![];
+    for record in records {
+        let Some(kind) = last_extension(&record.file_name).and_then(|(_, ext)| SidecarKind::from_extension(&ext)) else {
+            continue;
+        };
+        let has_object = |uuid: &str| objects.iter().any(|o| o.object.object_uuid == uuid);
+        if let Some(main) = main_file_for(record, kind, &by_dir[record.file_vfs_path.as_str()], has_object) {
+            found.push((record, kind, main));
+        }
+    }
+    objects.retain(|o| found.iter().all(|(sidecar, _, _)| sidecar.file_uuid != o.object.object_uuid));
+
+    for (sidecar, kind, main) in found {
+        let index = match objects.iter().position(|o| o.object.object_uuid == main.file_uuid) {
+            Some(i) => i,
+            None => {
+                objects.push(NewObject::bare(main, SIDECAR_PLUGIN_PACKAGE));
+                objects.len() - 1
+            }
+        };
+        match read_sidecar(sidecar, kind) {
+            Ok(fields) => merge_into(&mut objects[index], fields),
+            Err(e) => report.unparsed.push((sidecar.file_uuid.clone(), e.to_string())),
+        }
+        objects[index].extra_files.push(ObjectExtraFileRecord {
+            object_uuid: main.file_uuid.clone(),
+            file_uuid: sidecar.file_uuid.clone(),
+            file_note: kind.note().to_string(),
+        });
+        report.linked.push(SidecarLink {
+            sidecar_uuid: sidecar.file_uuid.clone(),
+            object_uuid: main.file_uuid.clone(),
+            kind,
+        });
+    }
+    report
+}
+
+#[cfg(test)]
+mod sidecar_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest};
+    use std::fs;
+
+    const NFO: &str = "<?xml version=\"1.0\"?>
+<movie>
+    <title>Night of the Living Dead</title>
+    <director>George A. Romero</director>
+    <genre>Horror</genre>
+    <premiered>1968-10-04</premiered>
+    <studio>Image Ten</studio>
+    <plot>Strangers hole up in a farmhouse.</plot>
+</movie>";
+
+    const CUE: &str = "REM GENRE Jazz
+REM DATE 1959
+PERFORMER \"Miles Davis\"
+TITLE \"Kind of Blue\"
+FILE \"album.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"So What\"
+    PERFORMER \"Someone Else\"
+  TRACK 02 AUDIO
+    TITLE \"Freddie Freeloader\"";
+
+    #[test]
+    fn cue_sheets_describe_the_album() {
+        let fields = parse_cue(CUE);
+        assert!(fields.album_name == "Kind of Blue");
+        assert!(fields.artist == "Miles Davis");
+        assert!(fields.genre == "Jazz");
+        assert!(fields.publish_timestamp == parse_date("1959"));
+        assert!(fields.attributes.contains(&("track_count".to_string(), AttrValue::INT(2))));
+    }
+
+    #[test]
+    fn sidecars_fill_in_and_attach_to_their_objects() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("night.mkv"), "pretend this is a movie")?;
+        fs::write(dir.path().join("night.nfo"), NFO)?;
+        fs::create_dir(dir.path().join("book"))?;
+        fs::write(dir.path().join("book/story.epub"), "pretend this is a book")?;
+        fs::write(
+            dir.path().join("book/info.json"),
+            r#"{"title": "A Story", "authors": ["Somebody"], "year": 2001, "pages": 212}"#,
+        )?;
+        fs::write(dir.path().join("book/README.txt"), "Thanks for downloading")?;
+        fs::write(dir.path().join("notes.txt"), "Not a sidecar, just a note")?;
+
+        let mut container = DirImportManifest::create_from_dir_on_disk(dir.path().into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records();
+        let uuid_of = |name: &str| {
+            container
+                .records()
+                .iter()
+                .find(|r| r.file_name == name)
+                .map(|r| r.file_uuid.clone())
+                .unwrap()
+        };
+        // As if an adapter had made one for the movie and one for the readme
+        let mut movie = NewObject::bare(
+            container.records().iter().find(|r| r.file_name == "night.mkv").unwrap(),
+            SIDECAR_PLUGIN_PACKAGE,
+        );
+        movie.object.object_name = "Night of the Living Dead (1968)".into();
+        movie.object.plugin_package_name = "some.video.plugin".into();
+        let readme = NewObject::bare(
+            container.records().iter().find(|r| r.file_name == "README.txt").unwrap(),
+            SIDECAR_PLUGIN_PACKAGE,
+        );
+        let mut objects = vec![movie, readme];
+
+        let report = container.apply_sidecars(&mut objects);
+        assert!(report.linked.len() == 3);
+        assert!(report.unparsed.is_empty());
+        assert!(objects.len() == 2);
+
+        let movie = objects.iter().find(|o| o.object.object_uuid == uuid_of("night.mkv")).unwrap();
+        // The adapter's name stays, the rest comes from the nfo
+        assert!(movie.object.object_name == "Night of the Living Dead (1968)");
+        assert!(movie.object.object_artist == "George A. Romero");
+        assert!(movie.object.object_imprint == "Image Ten");
+        assert!(movie.object.object_publish_timestamp == parse_date("1968-10-04").unwrap());
+        assert!(movie.attributes.iter().any(|a| a.attribute_name == "plot"));
+        assert!(movie.extra_files.len() == 1 && movie.extra_files[0].file_uuid == uuid_of("night.nfo"));
+
+        let book = objects.iter().find(|o| o.object.object_uuid == uuid_of("story.epub")).unwrap();
+        assert!(book.object.plugin_package_name == SIDECAR_PLUGIN_PACKAGE);
+        assert!(book.object.object_name == "A Story");
+        assert!(book.object.object_artist == "Somebody");
+        assert!(book.attributes.iter().any(|a| a.attribute_name == "pages" && a.attribute_value == AttrValue::INT(212)));
+        assert!(book.extra_files.len() == 2);
+        assert!(report.linked.iter().all(|l| l.sidecar_uuid != uuid_of("notes.txt")));
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Leave every link out
+    Skip,
+    /// Take linked files but don't walk into linked dirs
+    #[default]
+    FollowFiles,
+    /// Walk into linked dirs too. Loops get caught by checking device and inode.
+    Follow,
+}
+
+/// How a directory import walks the tree, past what the ignore rules say
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct TraversalOptions {
+    pub symlinks: SymlinkPolicy,
+    /// Only list a file once, however many hard or soft links lead to it
+    pub collapse_hardlinks: bool,
+    /// Don't go into anything mounted from another filesystem than the root's
+    pub same_filesystem: bool,
+}
+
+impl Default for TraversalOptions {
+    fn default() -> Self {
+        Self {
+            symlinks: SymlinkPolicy::default(),
+            collapse_hardlinks: true,
+            same_filesystem: false,
+        }
+    }
+}
+
+impl TraversalOptions {
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn collapsing_hardlinks(mut self, collapse: bool) -> Self {
+        self.collapse_hardlinks = collapse;
+        self
+    }
+
+    pub fn on_one_filesystem(mut self, same: bool) -> Self {
+        self.same_filesystem = same;
+        self
+    }
+}
+
+/// Device and inode, which is what makes two paths the same file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) struct FileIdentity {
+    pub(super) device: u64,
+    pub(super) inode: u64,
+}
+
+impl FileIdentity {
+    /// Follows links. Always `None` where there's no such thing, so none of the
+    /// checks that need it do anything there.
+    #[cfg(unix)]
+    pub(super) fn of(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(super) fn of(_: &Path) -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(all(test, unix))]
+mod traversal_tests {
+    use super::*;
+    use crate::db::importer::{DirImportManifest, IgnoreRules, SkipReason};
+    use anyhow::Result;
+    use std::os::unix::fs::symlink;
+
+    fn walk(root: &Path, options: TraversalOptions) -> Result<DirImportManifest> {
+        DirImportManifest::create_from_dir_on_disk_traversing(root.into(), &IgnoreRules::default(), &options)
+    }
+
+    fn names(manifest: &DirImportManifest) -> Vec<&str> {
+        let mut names: Vec<&str> = manifest.items.iter().map(|p| p.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    fn reason_for(manifest: &DirImportManifest, path: &str) -> Option<SkipReason> {
+        manifest.skipped.iter().find(|s| s.path == path).map(|s| s.reason)
+    }
+
+    #[test]
+    fn symlinks_follow_the_policy() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("real"))?;
+        fs::write(dir.path().join("real/a.txt"), "a")?;
+        fs::write(outside.path().join("b.txt"), "b")?;
+        symlink(dir.path().join("real"), dir.path().join("link_dir"))?;
+        symlink(dir.path().join("real/a.txt"), dir.path().join("link_file"))?;
+        symlink(outside.path(), dir.path().join("outside"))?;
+        symlink(dir.path().join("nowhere"), dir.path().join("broken"))?;
+
+        let skipping = walk(dir.path(), TraversalOptions::default().with_symlinks(SymlinkPolicy::Skip))?;
+        assert!(names(&skipping) == vec!["real/a.txt"]);
+        assert!(reason_for(&skipping, "link_file") == Some(SkipReason::Symlink));
+        assert!(reason_for(&skipping, "outside") == Some(SkipReason::Symlink));
+
+        let files_only = walk(dir.path(), TraversalOptions::default().collapsing_hardlinks(false))?;
+        assert!(names(&files_only) == vec!["link_file", "real/a.txt"]);
+        assert!(reason_for(&files_only, "link_dir") == Some(SkipReason::Symlink));
+
+        let following = walk(dir.path(), TraversalOptions::default().with_symlinks(SymlinkPolicy::Follow))?;
+        assert!(names(&following) == vec!["outside/b.txt", "real/a.txt"]);
+        assert!(reason_for(&following, "link_dir") == Some(SkipReason::DuplicateLink));
+        assert!(reason_for(&following, "link_file") == Some(SkipReason::DuplicateLink));
+        // Broken links aren't anything, so they aren't skipped either
+        assert!(reason_for(&following, "broken").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn loops_only_get_walked_once() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("a/b"))?;
+        fs::write(dir.path().join("a/b/deep.txt"), "deep")?;
+        symlink(dir.path(), dir.path().join("a/b/back_to_root"))?;
+        symlink(dir.path().join("a"), dir.path().join("a/b/back_to_a"))?;
+
+        let manifest = walk(dir.path(), TraversalOptions::default().with_symlinks(SymlinkPolicy::Follow))?;
+        assert!(names(&manifest) == vec!["a/b/deep.txt"]);
+        let root_loop = manifest.skipped.iter().find(|s| s.path == "a/b/back_to_root").unwrap();
+        assert!(root_loop.reason == SkipReason::Cycle);
+        assert!(root_loop.rule == "loops back to .");
+        assert!(reason_for(&manifest, "a/b/back_to_a") == Some(SkipReason::Cycle));
+        Ok(())
+    }
+
+    #[test]
+    fn hardlinks_collapse_into_one_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.sfc"), "the same rom")?;
+        fs::hard_link(dir.path().join("a.sfc"), dir.path().join("b.sfc"))?;
+        fs::write(dir.path().join("c.sfc"), "the same rom")?;
+
+        let collapsed = walk(dir.path(), TraversalOptions::default())?;
+        // Same contents isn't the same file, so c.sfc stays
+        assert!(names(&collapsed) == vec!["a.sfc", "c.sfc"]);
+        let b = collapsed.skipped.iter().find(|s| s.path == "b.sfc").unwrap();
+        assert!(b.reason == SkipReason::DuplicateLink);
+        assert!(b.rule == "same file as a.sfc");
+
+        let separate = walk(dir.path(), TraversalOptions::default().collapsing_hardlinks(false))?;
+        assert!(names(&separate) == vec!["a.sfc", "b.sfc", "c.sfc"]);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs /dev/shm mounted separately from the temp dir"]
+    fn other_filesystems_can_be_left_out() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let elsewhere = tempfile::tempdir_in("/dev/shm")?;
+        assert!(
+            FileIdentity::of(elsewhere.path()).map(|i| i.device) != FileIdentity::of(dir.path()).map(|i| i.device),
+            "/dev/shm is on the same filesystem as the temp dir here"
+        );
+        fs::write(dir.path().join("here.txt"), "here")?;
+        fs::write(elsewhere.path().join("there.txt"), "there")?;
+        symlink(elsewhere.path(), dir.path().join("mounted"))?;
+        let following = TraversalOptions::default().with_symlinks(SymlinkPolicy::Follow);
+
+        let everywhere = walk(dir.path(), following)?;
+        assert!(names(&everywhere) == vec!["here.txt", "mounted/there.txt"]);
+        let one_filesystem = walk(dir.path(), following.on_one_filesystem(true))?;
+        assert!(names(&one_filesystem) == vec!["here.txt"]);
+        assert!(reason_for(&one_filesystem, "mounted") == Some(SkipReason::OtherFilesystem));
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::{anyhow, Result};
+use exemplar::Model;
+use notify::event::ModifyKind;
+use notify::{Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use rusqlite::{params, Connection};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::{
+    load_media_categories, make_import_id_with_time, ArtworkRules, DirImportManifest, NewObject, RejectedObject,
+    RescanReport,
+};
+use crate::db::transaction::with_transaction;
+use crate::db::{Fetchable1, FileRecord, ObjectRecord, WatchFolderRecord};
+use crate::miko::Miko;
+
+type SQMiko = Miko<(Connection, Connection)>;
+type EventSender = mpsc::Sender<notify::Result<Event>>;
+
+/// A folder has to be quiet for this long before it gets rescanned, so that
+/// copying in a whole ROM set only causes one rescan
+const DEBOUNCE: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Reports nobody has picked up yet. Past this, new ones get dropped.
+const UNREAD_REPORT_LIMIT: usize = 16;
+
+/// Who objects made only so a watch folder's new files could go in its collection are managed by
+pub const WATCH_FOLDER_PLUGIN_PACKAGE: &str = "oosikle.builtin.watch_folders";
+
+const APPEND_TO_COLLECTION_SQL: &str = "insert into ObjectsInCollections
+select ?1, coalesce(max(OC.index_in_collection) + 1, 0), ?2
+from ObjectsInCollections OC where OC.collection_uuid = ?1;";
+
+const GET_WATCH_FOLDERS_SQL: &str = "select * from WatchFolders;";
+
+/// Makes objects for a rescan's new files, like `ObjectAdapters::create_objects`
+pub type ObjectMaker =
+    Arc<dyn Fn(&SQMiko, &[FileRecord]) -> Result<(Vec<NewObject>, Vec<RejectedObject>)> + Send + Sync>;
+
+/// What a watch folder's new files go through before they're written, same as
+/// they would in an import. Sidecars always get applied.
+#[derive(Clone, Default)]
+pub struct WatchImportSteps {
+    pub artwork_rules: ArtworkRules,
+    /// Without one, new files only get objects from their sidecars, or bare ones
+    pub make_objects: Option<ObjectMaker>,
+}
+
+pub fn register_watch_folder(
+    miko: &SQMiko,
+    path: PathBuf,
+    target_collection_uuid: Option<String>,
+    polling: bool,
+) -> Result<WatchFolderRecord> {
+    let path = path.canonicalize()?;
+    let record = WatchFolderRecord {
+        watch_folder_uuid: Uuid::now_v7().simple().to_string(),
+        watch_folder_path: path
+            .to_str()
+            .ok_or(anyhow!("{:?} isn't valid unicode", path))?
+            .to_string(),
+        import_session_id: make_import_id_with_time()?,
+        target_collection_uuid,
+        watch_folder_polling: polling,
+    };
+    let to_insert = record.clone();
+    miko.send_messenger(move |(_, conn)| Ok(to_insert.insert(conn)?))?;
+    Ok(record)
+}
+
+/// Starts a watcher for every registered folder. Folders that can't be watched
+/// (usually because they're gone) are logged and skipped.
+pub fn watch_all_folders(miko: &SQMiko, steps: &WatchImportSteps) -> Result<Vec<FolderWatcher>> {
+    let folders = miko.send_messenger(|(conn, _)| {
+        let mut stmt = conn.prepare_cached(GET_WATCH_FOLDERS_SQL)?;
+        let rows = stmt.query_map([], WatchFolderRecord::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    })?;
+    Ok(folders
+        .into_iter()
+        .filter_map(|folder| {
+            let path = folder.watch_folder_path.clone();
+            FolderWatcher::start(miko.clone(), folder, steps.clone())
+                .inspect_err(|e| tracing::warn!(folder = %path, "Couldn't watch folder: {}", e))
+                .ok()
+        })
+        .collect())
+}
+
+/// Keeps a watch folder imported for as long as it's alive. Every change gets
+/// run through `DirImportManifest::rescan`, so UUIDs stay put across edits and moves.
+pub struct FolderWatcher {
+    pub folder: WatchFolderRecord,
+    reports: mpsc::Receiver<RescanReport>,
+    // Dropping this stops the events, which in turn ends the rescan thread
+    _watcher: Box<dyn Watcher + Send>,
+}
+
+fn native_watcher(events: EventSender, path: &Path) -> notify::Result<Box<dyn Watcher + Send>> {
+    let mut watcher = RecommendedWatcher::new(events, Config::default())?;
+    watcher.watch(path, RecursiveMode::Recursive)?;
+    Ok(Box::new(watcher))
+}
+
+fn polling_watcher(events: EventSender, path: &Path) -> notify::Result<Box<dyn Watcher + Send>> {
+    let mut watcher = PollWatcher::new(events, Config::default().with_poll_interval(POLL_INTERVAL))?;
+    watcher.watch(path, RecursiveMode::Recursive)?;
+    Ok(Box::new(watcher))
+}
+
+/// Our own hashing reads every file, so reads and atime bumps have to be ignored
+fn is_worth_a_rescan(event: &notify::Result<Event>) -> bool {
+    match event {
+        Ok(e) => !matches!(
+            e.kind,
+            EventKind::Access(_) | EventKind::Modify(ModifyKind::Metadata(_))
+        ),
+        // Probably dropped events, so we can't know what we missed
+        Err(_) => true,
+    }
+}
+
+/// Rescans the folder, sends what it added through `steps`, and puts the new files
+/// into the folder's collection in the same transaction. Collections hold objects,
+/// so files nothing made an object for get a bare one, unless they're only there as
+/// another object's artwork or extra file. Only the writes hold the writer.
+fn sync_folder(miko: &SQMiko, folder: &WatchFolderRecord, steps: &WatchImportSteps) -> Result<RescanReport> {
+    let manifest = DirImportManifest::create_from_dir_on_disk(PathBuf::from(&folder.watch_folder_path))?;
+    let mut pending = manifest.prepare_rescan(miko)?;
+    let (mut new_objects, mut rejected) = match &steps.make_objects {
+        Some(make_objects) => make_objects(miko, &pending.report().added)?,
+        None => (vec![], vec![]),
+    };
+    pending.added().apply_sidecars(&mut new_objects);
+    let categories = miko.send_messenger(|(conn, _)| load_media_categories(conn))?;
+    let mut claimed: HashSet<String> = pending
+        .added()
+        .associate_artwork(&steps.artwork_rules, &categories)
+        .iter()
+        .map(|art| art.artwork_file_uuid.clone())
+        .collect();
+    for new_object in &new_objects {
+        claimed.extend(new_object.artwork.iter().map(|art| art.artwork_file_uuid.clone()));
+        claimed.extend(new_object.extra_files.iter().map(|extra| extra.file_uuid.clone()));
+    }
+    for new_object in &new_objects {
+        claimed.remove(&new_object.object.object_uuid);
+    }
+
+    let report = with_transaction(&miko.background(), |session| {
+        let (report, rejected_here) = pending.write_in(session, new_objects)?;
+        rejected.extend(rejected_here);
+        let Some(collection_uuid) = folder.target_collection_uuid.clone() else {
+            return Ok(report);
+        };
+        let added: Vec<FileRecord> = report
+            .added
+            .iter()
+            .filter(|file| !claimed.contains(&file.file_uuid))
+            .cloned()
+            .collect();
+        session.run(move |conn| {
+            for file in added {
+                if !ObjectRecord::check_exists(conn, &file.file_uuid)? {
+                    NewObject::bare(&file, WATCH_FOLDER_PLUGIN_PACKAGE).object.insert(conn)?;
+                }
+                conn.prepare_cached(APPEND_TO_COLLECTION_SQL)?
+                    .execute(params![collection_uuid, file.file_uuid])?;
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    })?;
+    for object in rejected {
+        tracing::warn!(folder = %folder.watch_folder_path, file = %object.file_uuid, "No object made: {}", object.reason);
+    }
+    Ok(report)
+}
+
+impl FolderWatcher {
+    pub fn start(miko: SQMiko, folder: WatchFolderRecord, steps: WatchImportSteps) -> Result<FolderWatcher> {
+        let path = PathBuf::from(&folder.watch_folder_path);
+        let (event_tx, event_rx) = mpsc::channel();
+        let watcher = if folder.watch_folder_polling {
+            polling_watcher(event_tx, &path)?
+        } else {
+            match native_watcher(event_tx.clone(), &path) {
+                Ok(w) => w,
+                Err(e) => {
+                    tracing::warn!(folder = %folder.watch_folder_path, "Falling back to polling: {}", e);
+                    polling_watcher(event_tx, &path)?
+                }
+            }
+        };
+        let (report_tx, report_rx) = mpsc::sync_channel(UNREAD_REPORT_LIMIT);
+        let watched = folder.clone();
+        thread::Builder::new()
+            .name("oosikle_watch_folder".into())
+            .spawn(move || {
+                let sync = || match sync_folder(&miko, &watched, &steps) {
+                    Ok(report) => {
+                        let _ = report_tx.try_send(report);
+                    }
+                    Err(e) => {
+                        tracing::warn!(folder = %watched.watch_folder_path, "Watch folder sync failed: {}", e)
+                    }
+                };
+                // Catches whatever changed while nobody was watching
+                sync();
+                while let Ok(event) = event_rx.recv() {
+                    if !is_worth_a_rescan(&event) {
+                        continue;
+                    }
+                    while event_rx.recv_timeout(DEBOUNCE).is_ok() {}
+                    sync();
+                }
+            })?;
+        Ok(FolderWatcher {
+            folder,
+            reports: report_rx,
+            _watcher: watcher,
+        })
+    }
+
+    /// The next rescan's report, the first one being from the scan on startup
+    pub fn next_report(&self, timeout: Duration) -> Option<RescanReport> {
+        self.reports.recv_timeout(timeout).ok()
+    }
+}
+
+#[cfg(test)]
+mod watch_folder_tests {
+    use super::*;
+    use crate::miko::ShrineDestroyer;
+    use std::fs;
+
+    static TESTING_VALUES: &'static str = include_str!("../../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../../db/init_db.sql");
+    const BRIEFCASE: &str = "BADC0FFEE0DDF00DBADC0FFEE0DDF00D";
+
+    fn init_miko(dbname: &str) -> Result<(SQMiko, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )
+    }
+
+    fn wait_for_file(watcher: &FolderWatcher, name: &str) -> Option<RescanReport> {
+        (0..10)
+            .filter_map(|_| watcher.next_report(Duration::from_secs(2)))
+            .find(|r| r.added.iter().any(|f| f.file_name == name))
+    }
+
+    fn watch_and_drop_in_a_file(dbname: &str, polling: bool) -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("already_here.txt"), "from before")?;
+        let (miko, _d) = init_miko(dbname)?;
+        let folder = register_watch_folder(&miko, dir.path().into(), Some(BRIEFCASE.into()), polling)?;
+        let watcher = FolderWatcher::start(miko.clone(), folder, WatchImportSteps::default())?;
+        let first = watcher
+            .next_report(Duration::from_secs(10))
+            .expect("The startup scan never finished");
+        assert!(first.added.len() == 1);
+
+        fs::write(dir.path().join("dropped_in.txt"), "a new book")?;
+        let report = wait_for_file(&watcher, "dropped_in.txt").expect("The new file was never imported");
+        let new_uuid = report.added[0].file_uuid.clone();
+        let (in_briefcase, object) = miko.send_messenger(move |(conn, _)| {
+            let in_briefcase = conn.query_row(
+                "select count(*) from ObjectsInCollections OC where OC.collection_uuid = ?1 and OC.object_uuid = ?2",
+                params![BRIEFCASE, new_uuid],
+                |r| r.get::<_, i64>(0),
+            )?;
+            Ok((in_briefcase, ObjectRecord::get_from_id(conn, &new_uuid)?))
+        })?;
+        assert!(in_briefcase == 1);
+        let object = object.expect("The new file didn't get an object");
+        assert!(object.object_name == "dropped_in");
+        assert!(object.plugin_package_name == WATCH_FOLDER_PLUGIN_PACKAGE);
+        Ok(())
+    }
+
+    #[test]
+    fn watch_folder_imports_new_files() -> Result<()> {
+        watch_and_drop_in_a_file("watch_folder_native", false)
+    }
+
+    #[test]
+    fn polling_watch_folder_imports_new_files() -> Result<()> {
+        watch_and_drop_in_a_file("watch_folder_polling", true)
+    }
+
+    fn objects_for_mp3s(_: &SQMiko, records: &[FileRecord]) -> Result<(Vec<NewObject>, Vec<RejectedObject>)> {
+        let objects = records
+            .iter()
+            .filter(|r| r.file_name.ends_with(".mp3"))
+            .map(|r| NewObject::bare(r, "oosikle.testing.watch"))
+            .collect();
+        Ok((objects, vec![]))
+    }
+
+    #[test]
+    fn new_files_go_through_the_import_steps() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("song.mp3"), "not really a song")?;
+        fs::write(dir.path().join("song.nfo"), "<musicvideo><artist>Somebody</artist></musicvideo>")?;
+        fs::write(dir.path().join("lonely.txt"), "nothing makes me an object")?;
+        let (miko, _d) = init_miko("watch_folder_steps")?;
+        let folder = register_watch_folder(&miko, dir.path().into(), Some(BRIEFCASE.into()), true)?;
+        let steps = WatchImportSteps {
+            artwork_rules: ArtworkRules::default(),
+            make_objects: Some(Arc::new(objects_for_mp3s)),
+        };
+        let watcher = FolderWatcher::start(miko.clone(), folder, steps)?;
+        let report = watcher
+            .next_report(Duration::from_secs(10))
+            .expect("The startup scan never finished");
+        assert!(report.added.len() == 3);
+        let uuid_of = |name: &str| {
+            report
+                .added
+                .iter()
+                .find(|f| f.file_name == name)
+                .map(|f| f.file_uuid.clone())
+                .unwrap()
+        };
+        let uuids = [uuid_of("song.mp3"), uuid_of("song.nfo"), uuid_of("lonely.txt")];
+        let (in_briefcase, song, extras, nfo_object) = miko.send_messenger(move |(conn, _)| {
+            let mut stmt = conn.prepare("select OC.object_uuid from ObjectsInCollections OC where OC.collection_uuid = ?1")?;
+            let in_briefcase = stmt
+                .query_map([BRIEFCASE], |r| r.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let song = ObjectRecord::get_from_id(conn, &uuids[0])?.expect("The song didn't get an object");
+            let extras = song.get_extra_files(conn)?;
+            let nfo_object = ObjectRecord::check_exists(conn, &uuids[1])?;
+            Ok((in_briefcase, song, extras, nfo_object))
+        })?;
+        assert!(song.plugin_package_name == "oosikle.testing.watch");
+        assert!(song.object_artist == "Somebody");
+        assert!(extras.len() == 1 && extras[0].file_uuid == uuid_of("song.nfo"));
+        // The sidecar belongs to the song, so it isn't anything on its own
+        assert!(!nfo_object);
+        assert!(in_briefcase.len() == 2);
+        assert!(in_briefcase.contains(&uuid_of("song.mp3")));
+        assert!(in_briefcase.contains(&uuid_of("lonely.txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn registered_folders_get_watched() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (miko, _d) = init_miko("watch_folder_registry")?;
+        let folder = register_watch_folder(&miko, dir.path().into(), None, true)?;
+        let watchers = watch_all_folders(&miko, &WatchImportSteps::default())?;
+        assert!(watchers.len() == 1);
+        assert!(watchers[0].folder == folder);
+        Ok(())
+    }
+}
@@ -35,9 +35,11 @@ create table if not exists Files (
     file_deleted integer,
     file_read_only integer,
     file_vfs_path text not null collate rtrim,
+    file_media_type_id text collate nocase, -- what the importer worked out, as opposed to the override
     unique (file_vfs_path, file_name),
     foreign key (file_extension_tag) references FileExtensions(file_extension_tag),
-    foreign key (media_type_override_id) references MediaTypes(media_type_id)
+    foreign key (media_type_override_id) references MediaTypes(media_type_id),
+    foreign key (file_media_type_id) references MediaTypes(media_type_id)
 );
 
 create table if not exists FileBlobs (
@@ -80,6 +82,16 @@ create table if not exists ExtraFilesForObjects (
     foreign key (file_uuid) references Files(file_uuid)
 );
 
+create table if not exists FileDigests (
+    file_uuid text not null collate nocase,
+    digest_algorithm text not null collate nocase,
+    digest_value text not null collate nocase,
+    primary key (file_uuid, digest_algorithm),
+    foreign key (file_uuid) references Files(file_uuid)
+);
+
+create index if not exists FileDigestsByValue on FileDigests (digest_algorithm, digest_value);
+
 create table if not exists FileArtwork (
     file_uuid text not null collate nocase,
     artwork_file_uuid text not null collate nocase,
@@ -148,6 +160,48 @@ create table if not exists DeviceSyncLists (
     foreign key (collection_uuid) references Collections(collection_uuid)
 );
 
+create table if not exists ImportSessions (
+    import_session_id text primary key collate nocase,
+    import_root_dir text not null,
+    import_timestamp text not null,
+    import_file_count integer not null,
+    import_rolled_back integer not null -- bool
+);
+
+create table if not exists FilesInImportSessions (
+    import_session_id text not null collate nocase,
+    file_uuid text not null collate nocase,
+    primary key (import_session_id, file_uuid),
+    foreign key (import_session_id) references ImportSessions(import_session_id),
+    foreign key (file_uuid) references Files(file_uuid)
+);
+
+create table if not exists WatchFolders (
+    watch_folder_uuid text primary key collate nocase,
+    watch_folder_path text not null unique,
+    import_session_id text not null collate nocase,
+    target_collection_uuid text collate nocase,
+    watch_folder_polling integer not null, -- bool
+    foreign key (target_collection_uuid) references Collections(collection_uuid)
+);
+
+create table if not exists LibraryLayouts (
+    library_root text primary key,
+    path_template text not null
+);
+
+create table if not exists HashCache (
+    canonical_path text primary key,
+    file_size_bytes integer not null,
+    file_modified_nanos integer not null,
+    file_inode integer not null,
+    file_hash text not null
+);
+
+create table if not exists VfsDirs (
+    vfs_dir_path text primary key collate rtrim
+);
+
 /*
 create view if not exists ObjectRecordView as
 select
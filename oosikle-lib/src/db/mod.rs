@@ -14,14 +14,43 @@ use std::vec::Vec;
 use std::path::Path;
 use time::OffsetDateTime;
 
-mod importer;
-
-
-static DB_INIT_SQL: &'static str = include_str!("./init_db.sql");
+pub mod importer;
+pub mod transaction;
+pub mod changes;
+pub mod diagnostics;
+
+
+pub static DB_INIT_SQL: &'static str = include_str!("./init_db.sql");
+
+/// Columns tables got after they first shipped. `create table if not exists` leaves
+/// the tables of older databases alone, so these have to be added on their own.
+const ADDED_COLUMNS: [(&str, &str, &str); 1] = [(
+    "Files",
+    "file_media_type_id",
+    "file_media_type_id text collate nocase references MediaTypes(media_type_id)",
+)];
+
+/// Brings tables made by an older init_db.sql up to date. Meant to run right
+/// after it, every time a database is opened.
+pub fn add_missing_columns(conn: &Connection) -> Result<(), Error> {
+    for (table, column, definition) in ADDED_COLUMNS {
+        let exists: bool = conn.query_row(
+            "select exists(select 1 from pragma_table_info(?1) where name = ?2);",
+            params![table, column],
+            |r| r.get(0),
+        )?;
+        if !exists {
+            conn.execute_batch(&format!("alter table {} add column {};", table, definition))?;
+        }
+    }
+    Ok(())
+}
 
 pub fn init_db(db_loc: &str) -> Result<Connection, Error> {
     let conn = Connection::open(db_loc)?;
     conn.execute_batch(DB_INIT_SQL)?;
+    add_missing_columns(&conn)?;
+    crate::facadefs::register_vfs_functions(&conn)?;
     return Ok(conn);
 }
 
@@ -275,6 +304,8 @@ pub struct FileRecord {
     pub file_deleted: bool,
     pub file_read_only: bool,
     pub file_vfs_path: String,
+    /// What the importer worked out the file is. An override still wins.
+    pub file_media_type_id: Option<String>,
 }
 
 impl Fetchable1<&str> for FileRecord {}
@@ -285,6 +316,13 @@ impl WithSQL for FileRecord {
 }
 
 impl FileRecord {
+    /// The override if there is one, and otherwise whatever the importer resolved
+    pub fn media_type_id(&self) -> Option<&str> {
+        self.media_type_override_id
+            .as_deref()
+            .or(self.file_media_type_id.as_deref())
+    }
+
     pub fn get_object_record(&self, conn: &Connection) -> Result<Option<ObjectRecord>> {
         ObjectRecord::get_from_id(conn, &self.file_uuid)
     }
@@ -297,8 +335,8 @@ impl FileRecord {
             ObjectAttr {object_uuid: self.file_uuid.clone(), attribute_name: "dir".to_string(), attribute_value: AttrValue::STRING(self.file_dir_path)},
             ObjectAttr {object_uuid: self.file_uuid.clone(), attribute_name: "extension".to_string(), attribute_value: AttrValue::STRING(self.file_extension_tag)},
             ObjectAttr {object_uuid: self.file_uuid.clone(), attribute_name: "encoding".to_string(), attribute_value: AttrValue::STRING(self.file_encoding)},
-            ObjectAttr {object_uuid: self.file_uuid.clone(), attribute_name: "media_type".to_string(), attribute_value: match self.media_type_override_id {
-                Some(s) => AttrValue::STRING(s),
+            ObjectAttr {object_uuid: self.file_uuid.clone(), attribute_name: "media_type".to_string(), attribute_value: match self.media_type_id() {
+                Some(s) => AttrValue::STRING(s.to_string()),
                 None => AttrValue::NONE
             }},
             ObjectAttr {object_uuid: self.file_uuid.clone(), attribute_name: "read_only".to_string(), attribute_value: AttrValue::INT(if self.file_read_only {1} else {0})},
@@ -337,6 +375,15 @@ impl FileRecord {
         self.get_art_by_role(conn, "cover")
     }
 
+    pub fn get_digests(&self, conn: &Connection) -> Result<Vec<FileDigestRecord>> {
+        FileDigestRecord::get_digests_for_file(conn, &self.file_uuid)
+    }
+
+    /// The file's digest in `algorithm`, like `crc32`, if one was worked out on import
+    pub fn get_digest(&self, conn: &Connection, algorithm: &str) -> Result<Option<String>> {
+        Ok(FileDigestRecord::get_from_id(conn, &self.file_uuid, algorithm)?.map(|d| d.digest_value))
+    }
+
     pub fn get_blob_contents(&self, conn: &Connection) -> Result<Option<Vec<u8>>> {
         let mut stmt = conn.prepare_cached(
             "select FileBlobs.blob_value from FileBlobs where FileBlobs.file_uuid = ?1 limit 1;",
@@ -359,6 +406,47 @@ impl FileRecord {
     }
 }
 
+/// A digest of a file besides its blake3 `file_hash`, in lowercase hex. These are
+/// what DATs from No-Intro, Redump and TOSEC identify dumps by.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
+#[table("FileDigests")]
+#[check("./init_db.sql")]
+pub struct FileDigestRecord {
+    pub file_uuid: String,
+    /// `crc32`, `md5` or `sha1`
+    pub digest_algorithm: String,
+    pub digest_value: String,
+}
+
+impl Fetchable2<&str, &str> for FileDigestRecord {}
+impl WithSQL for FileDigestRecord {
+    fn get_fetch_sql() -> &'static str {
+        "select * from FileDigests FD where FD.file_uuid = ?1 and FD.digest_algorithm = ?2 limit 1;"
+    }
+}
+
+impl FileDigestRecord {
+    pub fn get_digests_for_file(conn: &Connection, file_uuid: &str) -> Result<Vec<FileDigestRecord>> {
+        fetch_vec_of(
+            conn,
+            file_uuid,
+            "select * from FileDigests FD where FD.file_uuid = ?",
+        )
+    }
+
+    /// Every file that isn't deleted with this digest. Case doesn't matter, since
+    /// DATs tend to be in uppercase.
+    pub fn find_files_with_digest(conn: &Connection, algorithm: &str, value: &str) -> Result<Vec<FileRecord>> {
+        fetch_specific_vec_of(
+            conn,
+            algorithm,
+            value,
+            "select F.* from FileDigests FD join Files F on F.file_uuid = FD.file_uuid
+            where FD.digest_algorithm = ?1 and FD.digest_value = ?2 and F.file_deleted = 0;",
+        )
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
 #[table("FileArtwork")]
 #[check("./init_db.sql")]
@@ -460,7 +548,8 @@ impl ObjectAttr {
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
-#[table("ObjectAttributes")]
+#[table("ExtraFilesForObjects")]
+#[check("./init_db.sql")]
 pub struct ObjectExtraFileRecord {
     pub object_uuid: String,
     pub file_uuid: String,
@@ -735,6 +824,98 @@ impl WithSQL for DeviceRecord {
 
 impl DeviceRecord {}
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
+#[table("ImportSessions")]
+#[check("./init_db.sql")]
+pub struct ImportSessionRecord {
+    pub import_session_id: String,
+    pub import_root_dir: String,
+    pub import_timestamp: OffsetDateTime,
+    pub import_file_count: i64,
+    pub import_rolled_back: bool,
+}
+
+impl Fetchable1<&str> for ImportSessionRecord {}
+impl WithSQL for ImportSessionRecord {
+    fn get_fetch_sql() -> &'static str {
+        "select * from ImportSessions where ImportSessions.import_session_id = ?1 limit 1;"
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
+#[table("FilesInImportSessions")]
+#[check("./init_db.sql")]
+pub struct FileInImportSession {
+    pub import_session_id: String,
+    pub file_uuid: String,
+}
+
+impl Fetchable2<&str, &str> for FileInImportSession {}
+impl WithSQL for FileInImportSession {
+    fn get_fetch_sql() -> &'static str {
+        "select * from FilesInImportSessions FS where FS.import_session_id = ?1 and FS.file_uuid = ?2 limit 1;"
+    }
+}
+
+/// A directory that gets imported again whenever something in it changes
+#[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
+#[table("WatchFolders")]
+#[check("./init_db.sql")]
+pub struct WatchFolderRecord {
+    pub watch_folder_uuid: String,
+    pub watch_folder_path: String,
+    /// Files found in the folder live under this VFS dir
+    pub import_session_id: String,
+    /// Newly found files get appended here
+    pub target_collection_uuid: Option<String>,
+    /// For network shares and other places where change notifications don't arrive
+    pub watch_folder_polling: bool,
+}
+
+impl Fetchable1<&str> for WatchFolderRecord {}
+impl WithSQL for WatchFolderRecord {
+    fn get_fetch_sql() -> &'static str {
+        "select * from WatchFolders where WatchFolders.watch_folder_uuid = ?1 limit 1;"
+    }
+}
+
+/// A directory the library owns, with imported files arranged under it by template
+#[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
+#[table("LibraryLayouts")]
+#[check("./init_db.sql")]
+pub struct LibraryLayoutRecord {
+    pub library_root: String,
+    /// Something like `{category}/{artist}/{album}/{name}.{ext}`
+    pub path_template: String,
+}
+
+impl Fetchable1<&str> for LibraryLayoutRecord {}
+impl WithSQL for LibraryLayoutRecord {
+    fn get_fetch_sql() -> &'static str {
+        "select * from LibraryLayouts where LibraryLayouts.library_root = ?1 limit 1;"
+    }
+}
+
+/// What a file on disk hashed to, back when it had this size, mtime and inode
+#[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
+#[table("HashCache")]
+#[check("./init_db.sql")]
+pub struct HashCacheRecord {
+    pub canonical_path: String,
+    pub file_size_bytes: u64,
+    pub file_modified_nanos: i64,
+    /// Always 0 where there's no such thing
+    pub file_inode: i64,
+    pub file_hash: String,
+}
+
+impl Fetchable1<&str> for HashCacheRecord {}
+impl WithSQL for HashCacheRecord {
+    fn get_fetch_sql() -> &'static str {
+        "select * from HashCache where HashCache.canonical_path = ?1 limit 1;"
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Model, Clone)]
 #[table("DeviceSyncLists")]
 #[check("./init_db.sql")]
@@ -984,3 +1165,45 @@ mod simple_read_tests {
         return Ok(());
     } */
 }
+
+#[cfg(test)]
+mod schema_upgrade_tests {
+    use super::*;
+
+    /// Files as it was before `file_media_type_id`
+    const OLD_FILES_TABLE: &str = "create table Files (
+    file_uuid text primary key collate nocase,
+    file_name text not null collate nocase,
+    file_size_bytes integer not null,
+    file_hash text not null collate nocase,
+    file_dir_path text not null collate nocase,
+    file_extension_tag text not null collate nocase,
+    file_encoding text collate nocase,
+    media_type_override_id text collate nocase,
+    file_deleted integer,
+    file_read_only integer,
+    file_vfs_path text not null collate rtrim,
+    unique (file_vfs_path, file_name)
+);";
+
+    fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, Error> {
+        conn.query_row(
+            "select exists(select 1 from pragma_table_info(?1) where name = ?2);",
+            params![table, column],
+            |r| r.get(0),
+        )
+    }
+
+    #[test]
+    fn old_files_tables_get_new_columns() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(OLD_FILES_TABLE)?;
+        conn.execute_batch(DB_INIT_SQL)?;
+        assert!(!has_column(&conn, "Files", "file_media_type_id")?);
+        add_missing_columns(&conn)?;
+        assert!(has_column(&conn, "Files", "file_media_type_id")?);
+        // Opening it again shouldn't try to add it twice
+        add_missing_columns(&conn)?;
+        Ok(())
+    }
+}
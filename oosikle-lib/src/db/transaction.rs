@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+use std::sync::mpsc;
+
+use crate::db::changes;
+use crate::miko::Miko;
+
+type SQMiko = Miko<(Connection, Connection)>;
+type SessionMessenger = Box<dyn FnOnce(&Connection) + Send + 'static>;
+
+enum SessionEnd {
+    Commit,
+    Rollback,
+}
+
+enum SessionMessage {
+    Run(SessionMessenger),
+    Finish(SessionEnd, mpsc::Sender<Result<()>>),
+}
+
+/// Holds the writer connection inside one open transaction across any number of
+/// messengers. While a session is open the shrine runs nothing else, so don't send
+/// ordinary messengers to the same shrine from the thread that owns the session.
+///
+/// Dropping a session without committing rolls it back.
+#[derive(Debug)]
+pub struct WriterSession {
+    chan: mpsc::Sender<SessionMessage>,
+    finished: bool,
+}
+
+fn check_savepoint_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let starts_ok = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if starts_ok && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(anyhow!("{:?} is not a usable savepoint name", name))
+    }
+}
+
+impl WriterSession {
+    pub fn begin(miko: &SQMiko) -> Result<WriterSession> {
+        let (chan, rx) = mpsc::channel::<SessionMessage>();
+        let (ready_tx, ready_rx) = mpsc::channel::<Result<()>>();
+        miko.send_raw_messenger(move |(_, conn)| {
+            if let Err(e) = conn.execute_batch("begin immediate;") {
+                ready_tx.send(Err(e.into()))?;
+                return Ok(());
+            }
+            ready_tx.send(Ok(()))?;
+            // Everything the session sends runs here, inside the transaction
+            for message in rx {
+                match message {
+                    SessionMessage::Run(the_fn) => the_fn(conn),
+                    SessionMessage::Finish(end, reply) => {
+                        let sql = match end {
+                            SessionEnd::Commit => "commit;",
+                            SessionEnd::Rollback => "rollback;",
+                        };
+                        let res = conn.execute_batch(sql).map_err(anyhow::Error::from);
+                        if res.is_err() && !conn.is_autocommit() {
+                            conn.execute_batch("rollback;")?;
+                        }
+                        let _ = reply.send(res);
+                        return Ok(());
+                    }
+                }
+            }
+            // The session went away without saying how to end it
+            conn.execute_batch("rollback;")?;
+            Ok(())
+        })?;
+        ready_rx.recv()??;
+        Ok(WriterSession {
+            chan,
+            finished: false,
+        })
+    }
+
+    pub fn run<R: Send + 'static>(
+        &self,
+        messenger: impl FnOnce(&Connection) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let (tx, rx) = mpsc::channel::<Result<R>>();
+        self.chan
+            .send(SessionMessage::Run(Box::new(move |conn| {
+                let _ = tx.send(messenger(conn));
+            })))
+            .map_err(|e| anyhow!("The session is no longer open: {:?}", e))?;
+        rx.recv()?
+    }
+
+    pub fn savepoint(&self, name: &str) -> Result<()> {
+        check_savepoint_name(name)?;
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.execute_batch(&format!("savepoint {};", name))?;
+            changes::savepoint_opened(&name);
+            Ok(())
+        })
+    }
+
+    /// Keeps everything since the savepoint and forgets the savepoint itself.
+    pub fn release_savepoint(&self, name: &str) -> Result<()> {
+        check_savepoint_name(name)?;
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.execute_batch(&format!("release savepoint {};", name))?;
+            changes::savepoint_released(&name);
+            Ok(())
+        })
+    }
+
+    /// Undoes everything since the savepoint. The savepoint stays usable.
+    pub fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        check_savepoint_name(name)?;
+        let name = name.to_string();
+        self.run(move |conn| {
+            conn.execute_batch(&format!("rollback to savepoint {};", name))?;
+            changes::rolled_back_to_savepoint(&name);
+            Ok(())
+        })
+    }
+
+    fn finish(&mut self, end: SessionEnd) -> Result<()> {
+        self.finished = true;
+        let (tx, rx) = mpsc::channel::<Result<()>>();
+        self.chan
+            .send(SessionMessage::Finish(end, tx))
+            .map_err(|e| anyhow!("The session is no longer open: {:?}", e))?;
+        rx.recv()?
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.finish(SessionEnd::Commit)
+    }
+
+    pub fn rollback(mut self) -> Result<()> {
+        self.finish(SessionEnd::Rollback)
+    }
+}
+
+impl Drop for WriterSession {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish(SessionEnd::Rollback);
+        }
+    }
+}
+
+/// Runs `f` inside a session, committing if it returns `Ok` and rolling back otherwise.
+pub fn with_transaction<R>(miko: &SQMiko, f: impl FnOnce(&WriterSession) -> Result<R>) -> Result<R> {
+    let session = WriterSession::begin(miko)?;
+    match f(&session) {
+        Ok(r) => {
+            session.commit()?;
+            Ok(r)
+        }
+        Err(e) => {
+            session.rollback()?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod transaction_tests {
+    use super::*;
+    use crate::db::{Fetchable1, MediaCategoryRecord};
+    use crate::miko::ShrineDestroyer;
+    use exemplar::Model;
+
+    static INIT_DB_STR: &'static str = include_str!("./init_db.sql");
+
+    fn init(dbname: &str) -> Result<(SQMiko, ShrineDestroyer)> {
+        Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            INIT_DB_STR,
+        )
+    }
+
+    fn category(id: &str) -> MediaCategoryRecord {
+        MediaCategoryRecord {
+            media_category_id: id.into(),
+            media_category_string_key: format!("media_category_{}", id),
+        }
+    }
+
+    fn category_exists(miko: &SQMiko, id: &'static str) -> Result<bool> {
+        miko.send_messenger(move |(_, conn)| Ok(MediaCategoryRecord::check_exists(conn, id)?))
+    }
+
+    #[test]
+    fn committed_session_keeps_every_step() -> Result<()> {
+        let (miko, _d) = init("session_commits")?;
+        let session = WriterSession::begin(&miko)?;
+        session.run(|conn| Ok(category("first").insert(conn)?))?;
+        session.run(|conn| Ok(category("second").insert(conn)?))?;
+        session.commit()?;
+        assert!(category_exists(&miko, "first")?);
+        assert!(category_exists(&miko, "second")?);
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_session_rolls_back() -> Result<()> {
+        let (miko, _d) = init("session_drops")?;
+        {
+            let session = WriterSession::begin(&miko)?;
+            session.run(|conn| Ok(category("dropped").insert(conn)?))?;
+        }
+        assert!(!category_exists(&miko, "dropped")?);
+        Ok(())
+    }
+
+    #[test]
+    fn savepoints_undo_part_of_a_session() -> Result<()> {
+        let (miko, _d) = init("session_savepoints")?;
+        with_transaction(&miko, |session| {
+            session.run(|conn| Ok(category("kept").insert(conn)?))?;
+            session.savepoint("maybe")?;
+            session.run(|conn| Ok(category("undone").insert(conn)?))?;
+            session.rollback_to_savepoint("maybe")?;
+            session.release_savepoint("maybe")?;
+            Ok(())
+        })?;
+        assert!(category_exists(&miko, "kept")?);
+        assert!(!category_exists(&miko, "undone")?);
+        assert!(WriterSession::begin(&miko)?.savepoint("no; drop table Files").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn failing_closure_rolls_back() -> Result<()> {
+        let (miko, _d) = init("session_fails")?;
+        let res: Result<()> = with_transaction(&miko, |session| {
+            session.run(|conn| Ok(category("doomed").insert(conn)?))?;
+            Err(anyhow!("Something went wrong partway"))
+        });
+        assert!(res.is_err());
+        assert!(!category_exists(&miko, "doomed")?);
+        Ok(())
+    }
+}
@@ -1,15 +1,21 @@
 use std::cmp::Ordering;
 use std::collections::{hash_map, HashMap, HashSet};
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 
 use crate::{
-    db::FileRecord,
+    db::{importer::ManagedLibrary, FileRecord},
     miko::{Miko, ShrineDestroyer},
+    vfs_path::{VfsDirPath, VfsFilePath},
 };
 use anyhow::{anyhow, Result};
 use exemplar::Model;
 use relative_path::{RelativePath, RelativePathBuf};
-use rusqlite::{fallible_iterator::IteratorExt, params, Connection};
+use rusqlite::{
+    fallible_iterator::IteratorExt, functions::FunctionFlags, params, params_from_iter, Connection,
+    OptionalExtension,
+};
 use serde::{Deserialize, Serialize};
 use uuid::serde::simple;
 use fast_glob::glob_match;
@@ -27,6 +33,10 @@ pub struct DirTreeNode {
     pub dirpath: String,
     pub files: HashMap<String, FileRecord>,
     pub subdirs: HashMap<String, DirTreeNode>,
+    /// Whether this dir was past the depth limit, so nothing in it got loaded yet.
+    /// `FacadeFS::expand_dir_tree` fills it in.
+    #[serde(default)]
+    pub unexpanded: bool,
 }
 
 type FlattenedDir = Vec<(RelativePathBuf, FileRecord)>;
@@ -37,7 +47,21 @@ impl DirTreeNode {
             dirpath: dirpath.to_string(),
             files: HashMap::new(),
             subdirs: HashMap::new(),
+            unexpanded: false,
+        }
+    }
+
+    /// The dir `relative_dir` under this one, making any that aren't there yet
+    fn subdir_at_mut(&mut self, relative_dir: &str) -> &mut DirTreeNode {
+        let mut node = self;
+        for comp in relative_dir.split('/').filter(|c| !c.is_empty()) {
+            let dirpath = format!("{}{}/", node.dirpath, comp);
+            node = node
+                .subdirs
+                .entry(comp.to_string())
+                .or_insert_with(|| DirTreeNode::new(&dirpath));
         }
+        node
     }
     pub fn get_at_path(&self, dirpath: &str) -> Option<CursorIntoItem> {
         //println!("Starting cursor_into with {:?}", dirpath);
@@ -146,15 +170,100 @@ pub struct FacadeFS {
     miko: SQMiko,
 }
 
+// Prefixes get compared with substr rather than like, which would take any % or _
+// in a dir name as a wildcard
 const GET_DIRS_IN_DIR_SQL: &str = "
-            select distinct 
-	            substr(F.file_vfs_path, 0, instr(substr(F.file_vfs_path, length(?1)), '/')+length(?1)) as foldername
-            from Files F where F.file_vfs_path like ?1 and F.file_vfs_path != trim(?1, '%');";
+            select distinct
+                ?1 || substr(F.file_vfs_path, length(?1) + 1, instr(substr(F.file_vfs_path, length(?1) + 1), '/')) as foldername
+            from (
+                select file_vfs_path from Files where file_deleted = 0
+                union all select vfs_dir_path from VfsDirs
+            ) F where substr(F.file_vfs_path, 1, length(?1)) = ?1 and length(F.file_vfs_path) > length(?1);";
+
+// Depth is counted in slashes, and a null ?2 means no limit
+const GET_FILES_IN_TREE_SQL: &str = "select * from Files F
+where substr(F.file_vfs_path, 1, length(?1)) = ?1 and F.file_deleted = 0
+    and (?2 is null or length(F.file_vfs_path) - length(replace(F.file_vfs_path, '/', '')) <= ?2);";
+const GET_DIRS_PAST_DEPTH_SQL: &str = "select distinct F.file_vfs_path from Files F
+where substr(F.file_vfs_path, 1, length(?1)) = ?1 and F.file_deleted = 0
+    and length(F.file_vfs_path) - length(replace(F.file_vfs_path, '/', '')) > ?2;";
+const GET_EMPTY_DIRS_IN_TREE_SQL: &str =
+    "select D.vfs_dir_path from VfsDirs D where substr(D.vfs_dir_path, 1, length(?1)) = ?1;";
+
+const GET_FILES_IN_DIR_SQL: &str = "select * from Files F where F.file_vfs_path = ?1 and F.file_deleted = 0;";
+const GET_FILES_IN_SUBDIRS_SQL: &str = "select * from Files F
+where substr(F.file_vfs_path, 1, length(?1)) = ?1 and F.file_deleted = 0
+order by length(F.file_vfs_path);";
+
+// `vfs_glob` is ours, see `register_vfs_functions`. The paths it gets are relative to ?1.
+const GET_FILES_MATCHING_GLOB_SQL: &str = "select * from Files F
+where substr(F.file_vfs_path, 1, length(?1)) = ?1 and F.file_deleted = 0
+    and vfs_glob(?2, substr(F.file_vfs_path, length(?1) + 1) || F.file_name)
+order by F.file_vfs_path, F.file_name;";
+const GET_FILES_WITH_NAMES_MATCHING_SQL: &str = "select * from Files F
+where substr(F.file_vfs_path, 1, length(?1)) = ?1 and F.file_deleted = 0 and vfs_glob(?2, F.file_name)
+order by F.file_vfs_path, F.file_name;";
+const GET_FILES_WITH_EXTENSION_SQL: &str = "select * from Files F
+where F.file_vfs_path = ?1 and F.file_deleted = 0 and substr(F.file_name, -length(?2)) = ?2 collate nocase
+order by F.file_name;";
+const GET_FILES_WITH_EXTENSION_RECURSIVE_SQL: &str = "select * from Files F
+where substr(F.file_vfs_path, 1, length(?1)) = ?1 and F.file_deleted = 0
+    and substr(F.file_name, -length(?2)) = ?2 collate nocase
+order by F.file_vfs_path, F.file_name;";
+
+const GET_FILE_AT_SQL: &str = "select * from Files F
+where F.file_vfs_path = ?1 and F.file_name = ?2 and F.file_deleted = 0;";
+// Trashed files still count, since they'd still break the unique constraint
+const FILE_SPOT_TAKEN_SQL: &str =
+    "select 1 from Files F where F.file_vfs_path = ?1 and F.file_name = ?2 and F.file_uuid != ?3;";
+const MOVE_FILE_SQL: &str = "update Files set file_vfs_path = ?2, file_name = ?3 where file_uuid = ?1;";
+const VFS_DIR_TAKEN_SQL: &str = "select 1 from (
+    select file_vfs_path from Files union all select vfs_dir_path from VfsDirs
+) F where substr(F.file_vfs_path, 1, length(?1)) = ?1 limit 1;";
+const VFS_DIR_SHOWS_SQL: &str = "select 1 from (
+    select file_vfs_path from Files where file_deleted = 0 union all select vfs_dir_path from VfsDirs
+) F where substr(F.file_vfs_path, 1, length(?1)) = ?1 limit 1;";
+const MOVE_DIR_FILES_SQL: &str = "update Files set file_vfs_path = ?2 || substr(file_vfs_path, length(?1) + 1)
+where substr(file_vfs_path, 1, length(?1)) = ?1;";
+const MOVE_VFS_DIRS_SQL: &str = "update VfsDirs set vfs_dir_path = ?2 || substr(vfs_dir_path, length(?1) + 1)
+where substr(vfs_dir_path, 1, length(?1)) = ?1;";
+const MAKE_VFS_DIR_SQL: &str = "insert or ignore into VfsDirs values (?1);";
+const TRASH_FILE_SQL: &str =
+    "update Files set file_deleted = true where file_vfs_path = ?1 and file_name = ?2 and file_deleted = 0;";
+const TRASH_DIR_FILES_SQL: &str =
+    "update Files set file_deleted = true where substr(file_vfs_path, 1, length(?1)) = ?1 and file_deleted = 0;";
+const REMOVE_VFS_DIRS_SQL: &str = "delete from VfsDirs where substr(vfs_dir_path, 1, length(?1)) = ?1;";
+
+/// Lets queries use `vfs_glob(pattern, path)`, which matches the way `fast_glob`
+/// does, `**` and all. Every connection FacadeFS reads through needs it.
+pub fn register_vfs_functions(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "vfs_glob",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let pattern = ctx.get_raw(0).as_str().map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            let path = ctx.get_raw(1).as_str().map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            Ok(glob_match(pattern, path))
+        },
+    )
+}
+
+/// Files coming out of a FacadeFS search as SQLite finds them. The query runs in
+/// one messenger that never waits on the reader, and stops early if this gets
+/// dropped. Errors come through as the last item.
+#[derive(Debug)]
+pub struct VfsFileStream {
+    rx: mpsc::Receiver<Result<FileRecord>>,
+}
 
-const GET_FILES_IN_DIR_SQL: &str = "select * from Files F where F.file_vfs_path = ?1;";
-const GET_FILES_IN_SUBDIRS_SQL: &str =
-    "select * from Files F where F.file_vfs_path like ?1 order by length(F.file_vfs_path);";
-const GET_FILES_MATCHING_GLOB: &str = "select * from Files F where F.file_vfs_path GLOB ?1;";
+impl Iterator for VfsFileStream {
+    type Item = Result<FileRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
 
 impl FacadeFS {
     pub fn new(miko: SQMiko) -> Self {
@@ -162,12 +271,12 @@ impl FacadeFS {
     }
 
     pub fn get_directories_at(&self, dirpath: &str) -> Result<Vec<String>> {
-        let dirpath_string = dirpath.to_string();
+        let dirpath_string = String::from(VfsDirPath::parse(dirpath)?);
         let ret: Vec<String> = self.miko.send_mutating_messenger(move |(_, conn)| {
             let mut stmt = conn.prepare_cached(GET_DIRS_IN_DIR_SQL)?;
 
             let ret = stmt
-                .query_map([dirpath_string + "%"], |r| Ok(r.get("foldername")?))?
+                .query_map([dirpath_string], |r| Ok(r.get("foldername")?))?
                 .filter(|t| t.is_ok())
                 .map(|t| t.expect("filter didn't work"))
                 .collect();
@@ -178,7 +287,7 @@ impl FacadeFS {
     }
 
     pub fn get_files_at(&self, dirpath: &str) -> Result<Vec<FileRecord>> {
-        let dirpath_string = dirpath.to_string();
+        let dirpath_string = String::from(VfsDirPath::parse(dirpath)?);
         let ret: Vec<FileRecord> = self.miko.send_mutating_messenger(move |(_, conn)| {
             let mut stmt = conn.prepare_cached(GET_FILES_IN_DIR_SQL)?;
 
@@ -194,28 +303,274 @@ impl FacadeFS {
     }
 
     pub fn get_dir_tree_at(&self, dirpath: &str) -> Result<DirTreeNode> {
-        let mut node = DirTreeNode::new(dirpath);
-        for file in self.get_files_at(dirpath)? {
+        self.get_dir_tree_to_depth(dirpath, None)
+    }
+
+    /// The tree under `dirpath`, going `max_depth` dirs down at most. Dirs right
+    /// past the limit are there but `unexpanded`, so a UI can show them and
+    /// load them when they get opened. Takes one messenger however big the tree is.
+    pub fn get_dir_tree_to_depth(&self, dirpath: &str, max_depth: Option<usize>) -> Result<DirTreeNode> {
+        let dir = VfsDirPath::parse(dirpath)?;
+        let dirpath = dir.as_str();
+        let dirpath_string = dirpath.to_string();
+        let max_slashes = max_depth.map(|d| (dir.depth() + d) as i64);
+        let (files, dirs) = self.miko.send_messenger(move |(conn, _)| {
+            let files = conn
+                .prepare_cached(GET_FILES_IN_TREE_SQL)?
+                .query_map(params![dirpath_string, max_slashes], |r| FileRecord::from_row(r))?
+                .collect::<rusqlite::Result<Vec<FileRecord>>>()?;
+            let mut dirs = conn
+                .prepare_cached(GET_EMPTY_DIRS_IN_TREE_SQL)?
+                .query_map([&dirpath_string], |r| r.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            if let Some(max_slashes) = max_slashes {
+                let mut stmt = conn.prepare_cached(GET_DIRS_PAST_DEPTH_SQL)?;
+                for dir in stmt.query_map(params![dirpath_string, max_slashes], |r| r.get::<_, String>(0))? {
+                    dirs.push(dir?);
+                }
+            }
+            Ok((files, dirs))
+        })?;
+
+        let mut root = DirTreeNode::new(dirpath);
+        for file in files {
+            let node = root.subdir_at_mut(&file.file_vfs_path[dirpath.len()..]);
             node.files.insert(file.file_name.clone(), file);
         }
-        let folderpaths = self.get_directories_at(dirpath)?;
-        for folderpath in folderpaths {
-            let childnode = self.get_dir_tree_at(folderpath.as_str())?;
-            let filepart = Path::new(folderpath.as_str())
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            node.subdirs.insert(filepart, childnode);
+        // Made with `make_dir` or past the limit, so no files came with them
+        for dir in dirs {
+            let components: Vec<&str> = dir[dirpath.len()..].split('/').filter(|c| !c.is_empty()).collect();
+            match max_depth {
+                Some(depth) if components.len() > depth => {
+                    root.subdir_at_mut(&components[..=depth].join("/")).unexpanded = true;
+                }
+                _ => {
+                    root.subdir_at_mut(&components.join("/"));
+                }
+            }
         }
-        Ok(node)
+        Ok(root)
     }
 
-    /*
-    pub fn glob(&self, startdir: &str, pattern: &str) -> Result<FlattenedDir> {
+    /// Loads what's in `node`, `max_depth` dirs down, in place
+    pub fn expand_dir_tree(&self, node: &mut DirTreeNode, max_depth: Option<usize>) -> Result<()> {
+        *node = self.get_dir_tree_to_depth(&node.dirpath, max_depth)?;
+        Ok(())
+    }
+
+    fn stream_files(&self, sql: &'static str, args: [String; 2]) -> Result<VfsFileStream> {
+        let (tx, rx) = mpsc::channel();
+        self.miko.send_raw_messenger(move |(conn, _)| {
+            let streamed = (|| -> Result<()> {
+                let mut stmt = conn.prepare_cached(sql)?;
+                let mut rows = stmt.query(params_from_iter(args))?;
+                while let Some(row) = rows.next()? {
+                    if tx.send(Ok(FileRecord::from_row(row)?)).is_err() {
+                        // Nobody's reading anymore
+                        break;
+                    }
+                }
+                Ok(())
+            })();
+            if let Err(e) = streamed {
+                let _ = tx.send(Err(e));
+            }
+            Ok(())
+        })?;
+        Ok(VfsFileStream { rx })
+    }
+
+    /// Files under `startdir` whose path relative to it matches `pattern`, so
+    /// `*.md` only looks in `startdir` itself and `**/*.md` looks everywhere under it
+    pub fn glob(&self, startdir: &str, pattern: &str) -> Result<VfsFileStream> {
+        let startdir = VfsDirPath::parse(startdir)?.into();
+        self.stream_files(GET_FILES_MATCHING_GLOB_SQL, [startdir, pattern.to_string()])
+    }
 
-    } */
+    /// Files anywhere under `startdir` with names matching `pattern`
+    pub fn search_names(&self, startdir: &str, pattern: &str) -> Result<VfsFileStream> {
+        let startdir = VfsDirPath::parse(startdir)?.into();
+        self.stream_files(GET_FILES_WITH_NAMES_MATCHING_SQL, [startdir, pattern.to_string()])
+    }
+
+    /// Files in `dirpath` ending in `.extension`, in any case. Multi-part ones like
+    /// `p8.png` work too.
+    pub fn files_with_extension(&self, dirpath: &str, extension: &str, recursive: bool) -> Result<VfsFileStream> {
+        let sql = if recursive {
+            GET_FILES_WITH_EXTENSION_RECURSIVE_SQL
+        } else {
+            GET_FILES_WITH_EXTENSION_SQL
+        };
+        let suffix = format!(".{}", extension.trim_start_matches('.'));
+        self.stream_files(sql, [VfsDirPath::parse(dirpath)?.into(), suffix])
+    }
+
+    /// Moves or renames a file or a dir, dirs being the paths that end in a slash.
+    /// A dir takes everything under it along, trashed files included. A file's
+    /// name is also what it's called on disk, so renaming one needs a `mirror`
+    /// that it's one of the copies of, and gets renamed on disk too. Only names
+    /// get mirrored, since where a copy sits on disk is up to the library's
+    /// template, so moving a dir or a file to another dir with one is an error.
+    /// Gives back how many files moved.
+    pub fn move_path(&self, from: &str, to: &str, mirror: Option<&ManagedLibrary>) -> Result<usize> {
+        match (from.ends_with('/'), to.ends_with('/')) {
+            (true, true) => self.move_dir(VfsDirPath::parse(from)?, VfsDirPath::parse(to)?, mirror),
+            (false, false) => self.move_file(VfsFilePath::parse(from)?, VfsFilePath::parse(to)?, mirror),
+            _ => Err(anyhow!(
+                "Can't move {:?} to {:?}, only dirs end in a slash",
+                from,
+                to
+            )),
+        }
+    }
+
+    /// `move_path` without leaving the parent dir
+    pub fn rename_path(&self, path: &str, new_name: &str, mirror: Option<&ManagedLibrary>) -> Result<usize> {
+        if path.ends_with('/') {
+            let dir = VfsDirPath::parse(path)?;
+            let parent = dir.parent().ok_or(anyhow!("The root can't be renamed"))?;
+            let renamed = parent.join(new_name)?;
+            self.move_dir(dir, renamed, mirror)
+        } else {
+            let file = VfsFilePath::parse(path)?;
+            let renamed = VfsFilePath::new(file.dir.clone(), new_name)?;
+            self.move_file(file, renamed, mirror)
+        }
+    }
+
+    fn move_file(&self, from: VfsFilePath, to: VfsFilePath, mirror: Option<&ManagedLibrary>) -> Result<usize> {
+        if mirror.is_some() && from.dir != to.dir {
+            return Err(anyhow!(
+                "Only names get mirrored on disk, so {:?} can't go to another dir with a mirror",
+                from.to_string()
+            ));
+        }
+        let library = mirror.cloned();
+        self.miko
+            .send_mutating_messenger(move |(_, conn)| move_file_rows(conn, &from, &to, library.as_ref()))
+    }
+
+    fn move_dir(&self, from: VfsDirPath, to: VfsDirPath, mirror: Option<&ManagedLibrary>) -> Result<usize> {
+        if mirror.is_some() {
+            return Err(anyhow!(
+                "Dirs are only in the vfs, so there's nothing on disk to mirror moving {:?}",
+                from.as_str()
+            ));
+        }
+        if from.is_root() || to.is_root() {
+            return Err(anyhow!("The root can't be moved, or replaced"));
+        }
+        if from.contains(&to) {
+            return Err(anyhow!("Can't move {:?} into itself", from.as_str()));
+        }
+        self.miko.send_mutating_messenger(move |(_, conn)| move_dir_rows(conn, &from, &to))
+    }
+
+    /// Makes a dir that stays around with nothing in it
+    pub fn make_dir(&self, dirpath: &str) -> Result<()> {
+        let dirpath = VfsDirPath::parse(dirpath)?;
+        if dirpath.is_root() {
+            return Err(anyhow!("The root is always there"));
+        }
+        self.miko.send_mutating_messenger(move |(_, conn)| {
+            if conn.prepare_cached(VFS_DIR_SHOWS_SQL)?.exists([dirpath.as_str()])? {
+                return Err(anyhow!("{:?} is already there", dirpath.as_str()));
+            }
+            conn.prepare_cached(MAKE_VFS_DIR_SQL)?.execute([dirpath.as_str()])?;
+            Ok(())
+        })
+    }
+
+    /// Sends a file, or a dir and everything under it, to the trash, the same way
+    /// `RollbackMode::Trash` does. Nothing on disk is touched. Gives back how many
+    /// files got trashed.
+    pub fn trash_path(&self, path: &str) -> Result<usize> {
+        if path.ends_with('/') {
+            let dir = VfsDirPath::parse(path)?;
+            if dir.is_root() {
+                return Err(anyhow!("The root can't be trashed"));
+            }
+            self.miko.send_mutating_messenger(move |(_, conn)| {
+                let tx = conn.transaction()?;
+                let files = tx.prepare_cached(TRASH_DIR_FILES_SQL)?.execute([dir.as_str()])?;
+                let dirs = tx.prepare_cached(REMOVE_VFS_DIRS_SQL)?.execute([dir.as_str()])?;
+                if files + dirs == 0 {
+                    return Err(anyhow!("There's nothing at {:?}", dir.as_str()));
+                }
+                tx.commit()?;
+                Ok(files)
+            })
+        } else {
+            let file = VfsFilePath::parse(path)?;
+            self.miko.send_mutating_messenger(move |(_, conn)| {
+                match conn
+                    .prepare_cached(TRASH_FILE_SQL)?
+                    .execute([file.dir.as_str(), file.name.as_str()])?
+                {
+                    0 => Err(anyhow!("There's no file at {:?}", file.to_string())),
+                    n => Ok(n),
+                }
+            })
+        }
+    }
+}
+
+fn move_file_rows(
+    conn: &mut Connection,
+    from: &VfsFilePath,
+    to: &VfsFilePath,
+    library: Option<&ManagedLibrary>,
+) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let record = tx
+        .prepare_cached(GET_FILE_AT_SQL)?
+        .query_row([from.dir.as_str(), from.name.as_str()], FileRecord::from_row)
+        .optional()?
+        .ok_or(anyhow!("There's no file at {:?}", from.to_string()))?;
+    if tx
+        .prepare_cached(FILE_SPOT_TAKEN_SQL)?
+        .exists([to.dir.as_str(), to.name.as_str(), record.file_uuid.as_str()])?
+    {
+        return Err(anyhow!("There's already a file at {:?}, or one in the trash", to.to_string()));
+    }
+    tx.prepare_cached(MOVE_FILE_SQL)?
+        .execute([record.file_uuid.as_str(), to.dir.as_str(), to.name.as_str()])?;
+    // `file_name` is what it's called on disk or in its archive as well, so the
+    // name can only change if the file itself gets renamed with it
+    let renamed = if to.name == record.file_name {
+        None
+    } else {
+        let library = library.ok_or_else(|| {
+            anyhow!("{:?} can only be renamed with the library mirroring it", from.to_string())
+        })?;
+        let renamed = library.rename_managed_file(&tx, &record, &to.name)?.ok_or_else(|| {
+            anyhow!("{:?} isn't one of the library's own copies, so it can't be renamed", from.to_string())
+        })?;
+        Some(renamed)
+    };
+    if let Err(e) = tx.commit() {
+        if let Some((old, new)) = renamed {
+            if let Err(e) = fs::rename(&new, &old) {
+                tracing::error!(file = %new.display(), "Couldn't put file back: {}", e);
+            }
+        }
+        return Err(e.into());
+    }
+    Ok(1)
+}
+
+fn move_dir_rows(conn: &mut Connection, from: &VfsDirPath, to: &VfsDirPath) -> Result<usize> {
+    let tx = conn.transaction()?;
+    if !tx.prepare_cached(VFS_DIR_TAKEN_SQL)?.exists([from.as_str()])? {
+        return Err(anyhow!("There's no dir at {:?}", from.as_str()));
+    }
+    if tx.prepare_cached(VFS_DIR_TAKEN_SQL)?.exists([to.as_str()])? {
+        return Err(anyhow!("There's already something at {:?}, or in the trash there", to.as_str()));
+    }
+    let moved = tx.prepare_cached(MOVE_DIR_FILES_SQL)?.execute([from.as_str(), to.as_str()])?;
+    tx.prepare_cached(MOVE_VFS_DIRS_SQL)?.execute([from.as_str(), to.as_str()])?;
+    tx.commit()?;
+    Ok(moved)
 }
 
 #[cfg(test)]
@@ -224,7 +579,7 @@ mod facadefs_tests {
     static TESTING_VALUES: &'static str = include_str!("./testing_data/sql/testing_values.sql");
     static INIT_DB_STR: &'static str = include_str!("./db/init_db.sql");
 
-    use proptest::strategy::W;
+    use proptest::prelude::*;
 
     use super::*;
 
@@ -345,4 +700,189 @@ mod facadefs_tests {
         assert_eq!(res1.len(), 1);
         Ok(())
     }
+
+    #[test]
+    fn tests_depth_limited_tree() -> Result<()> {
+        let (ffs, _d) = init("depth_limited_tree")?;
+        let top = ffs.get_dir_tree_to_depth("", Some(0))?;
+        assert!(top.files.is_empty());
+        assert_eq!(top.subdirs.len(), 4);
+        assert!(top.subdirs.values().all(|d| d.unexpanded && d.files.is_empty() && d.subdirs.is_empty()));
+        assert_eq!(top.subdirs.get("beta").unwrap().dirpath, "beta/");
+
+        let mut beta = ffs.get_dir_tree_to_depth("beta/", Some(1))?;
+        assert_eq!(beta.files.len(), 2);
+        let gamma = beta.subdirs.get("gamma").unwrap();
+        assert!(!gamma.unexpanded);
+        assert_eq!(gamma.files.len(), 2);
+        assert!(gamma.subdirs.get("theta").unwrap().unexpanded);
+
+        let theta = beta.subdirs.get_mut("gamma").unwrap().subdirs.get_mut("theta").unwrap();
+        ffs.expand_dir_tree(theta, None)?;
+        assert!(!theta.unexpanded);
+        assert!(theta.files.contains_key("intrepreterbook.md"));
+        assert_eq!(theta.dirpath, "beta/gamma/theta/");
+        Ok(())
+    }
+
+    #[test]
+    fn tests_moves_and_renames() -> Result<()> {
+        let (ffs, _d) = init("moves_and_renames")?;
+        // The name is the one on disk too, so it can't change without a mirror
+        assert!(ffs.rename_path("beta/sample1.mp3", "renamed.mp3", None).is_err());
+        assert_eq!(ffs.move_path("beta/sample1.mp3", "alpha/sample1.mp3", None)?, 1);
+        let beta: Vec<String> = ffs.get_files_at("beta/")?.into_iter().map(|f| f.file_name).collect();
+        assert!(!beta.contains(&"sample1.mp3".to_string()));
+        assert!(ffs.get_files_at("alpha/")?.iter().any(|f| f.file_name == "sample1.mp3"));
+        assert!(ffs.move_path("alpha/sample1.mp3", "beta/sample2.mp3", None).is_err());
+        assert!(ffs.move_path("alpha/sample1.mp3", "beta/", None).is_err());
+
+        assert_eq!(ffs.move_path("beta/gamma/", "alpha/gamma/", None)?, 3);
+        assert!(ffs.get_directories_at("alpha/")?.contains(&"alpha/gamma/".to_string()));
+        assert!(!ffs.get_directories_at("beta/")?.contains(&"beta/gamma/".to_string()));
+        assert_eq!(ffs.get_files_at("alpha/gamma/theta/")?.len(), 1);
+        assert!(ffs.move_path("alpha/", "alpha/gamma/alpha/", None).is_err());
+        assert!(ffs.move_path("alpha/gamma/", "pico8/", None).is_err());
+
+        assert_eq!(ffs.rename_path("alpha/gamma/", "delta", None)?, 3);
+        assert_eq!(ffs.get_files_at("alpha/delta/")?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn tests_make_dir_and_trash() -> Result<()> {
+        let (ffs, _d) = init("make_dir_and_trash")?;
+        ffs.make_dir("alpha/empty/")?;
+        assert!(ffs.make_dir("alpha/empty/").is_err());
+        assert!(ffs.make_dir("alpha/../").is_err());
+        assert!(ffs.get_directories_at("alpha/")?.contains(&"alpha/empty/".to_string()));
+        let alpha = ffs.get_dir_tree_at("alpha/")?;
+        assert!(alpha.subdirs.get("empty").unwrap().files.is_empty());
+        ffs.move_path("alpha/welcome.txt", "alpha/empty/welcome.txt", None)?;
+        assert_eq!(ffs.get_files_at("alpha/empty/")?.len(), 1);
+
+        assert_eq!(ffs.trash_path("pico8/celeste/")?, 2);
+        assert!(ffs.get_files_at("pico8/celeste/")?.is_empty());
+        assert!(!ffs.get_directories_at("pico8/")?.contains(&"pico8/celeste/".to_string()));
+        assert_eq!(names_of(ffs.glob("", "**/celeste*")?)?.len(), 0);
+        // Still in the trash, so the spot's still taken
+        assert!(ffs.move_path("pico8/hotwax.p8.png", "pico8/celeste/celeste.p8.png", None).is_err());
+
+        assert_eq!(ffs.trash_path("pico8/hotwax.p8.png")?, 1);
+        assert!(ffs.trash_path("pico8/hotwax.p8.png").is_err());
+        assert_eq!(ffs.trash_path("alpha/")?, 2);
+        assert!(ffs.get_dir_tree_at("alpha/")?.subdirs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn tests_renames_mirror_to_managed_files() -> Result<()> {
+        let (miko, _d): (SQMiko, ShrineDestroyer) = Miko::construct_connection_shrine(
+            "file:renames_mirror?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let library_dir = tempfile::tempdir()?;
+        let library = ManagedLibrary::create(&miko, library_dir.path().into(), "{name}.{ext}")?;
+        fs::write(library.root().join("a.txt"), "managed")?;
+        let root = library.root().to_string_lossy().to_string();
+        miko.send_mutating_messenger(move |(_, conn)| {
+            conn.execute(
+                "insert into Files values ('ABCDABCDABCDABCDABCDABCDABCDABCD', 'a.txt', 7, '', ?1, 'TXT', 'UTF8', NULL, FALSE, FALSE, 'lib/', NULL);",
+                [root],
+            )?;
+            Ok(())
+        })?;
+        let ffs = FacadeFS::new(miko);
+
+        ffs.rename_path("lib/a.txt", "b.txt", Some(&library))?;
+        assert!(library.root().join("b.txt").exists());
+        assert!(!library.root().join("a.txt").exists());
+        // Without the library, the record would end up naming a file that isn't there
+        assert!(ffs.rename_path("lib/b.txt", "c.txt", None).is_err());
+        assert!(library.root().join("b.txt").exists());
+        assert_eq!(ffs.get_files_at("lib/")?[0].file_name, "b.txt");
+        // Nothing but names can be mirrored
+        assert!(ffs.move_path("lib/b.txt", "elsewhere/b.txt", Some(&library)).is_err());
+        assert!(ffs.move_path("lib/", "elsewhere/", Some(&library)).is_err());
+        assert_eq!(ffs.get_files_at("lib/")?.len(), 1);
+        Ok(())
+    }
+
+    fn odd_name() -> impl Strategy<Value = String> {
+        // Few enough letters that names like `a_` and `ab` turn up side by side
+        r"[ab%_. \[*]{1,3}".prop_filter("Not a name", |n| n != "." && n != "..")
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn tests_listings_stay_in_their_own_dirs(names in prop::collection::hash_set(odd_name(), 1..6)) {
+            let (ffs, _d) = init(&format!("odd_names_{}", uuid::Uuid::new_v4().simple())).unwrap();
+            let parent = VfsDirPath::parse("odd names").unwrap();
+            let dirs: Vec<VfsDirPath> = names.iter().map(|n| parent.join(n).unwrap()).collect();
+            let rows: Vec<String> = dirs
+                .iter()
+                .flat_map(|d| [d.to_string(), d.join("inner").unwrap().to_string()])
+                .collect();
+            ffs.miko
+                .send_mutating_messenger(move |(_, conn)| {
+                    for vfs_path in rows {
+                        conn.execute(
+                            "insert into Files values (?1, 'f.txt', 0, '', '', 'TXT', 'UTF8', NULL, FALSE, FALSE, ?2, NULL);",
+                            params![uuid::Uuid::new_v4().simple().to_string(), vfs_path],
+                        )?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+
+            let mut listed = ffs.get_directories_at(parent.as_str()).unwrap();
+            listed.sort();
+            let mut expected: Vec<String> = dirs.iter().map(|d| d.to_string()).collect();
+            expected.sort();
+            prop_assert_eq!(listed, expected);
+            prop_assert_eq!(ffs.get_dir_tree_at(parent.as_str()).unwrap().subdirs.len(), dirs.len());
+            for dir in &dirs {
+                prop_assert_eq!(ffs.get_directories_at(dir.as_str()).unwrap(), vec![dir.join("inner").unwrap().to_string()]);
+                prop_assert_eq!(ffs.get_files_at(dir.as_str()).unwrap().len(), 1);
+                let tree = ffs.get_dir_tree_at(dir.as_str()).unwrap();
+                prop_assert_eq!(tree.files.len(), 1);
+                prop_assert_eq!(tree.subdirs.len(), 1);
+                prop_assert_eq!(ffs.glob(dir.as_str(), "**").unwrap().count(), 2);
+            }
+        }
+    }
+
+    fn names_of(stream: VfsFileStream) -> Result<Vec<String>> {
+        stream.map(|f| Ok(f?.file_name)).collect()
+    }
+
+    #[test]
+    fn tests_sql_glob_works() -> Result<()> {
+        let (ffs, _d) = init("tests_sql_glob")?;
+        assert_eq!(names_of(ffs.glob("", "**/*.p8.png")?)?.len(), 6);
+        // A lone star doesn't go into subdirs
+        assert_eq!(names_of(ffs.glob("pico8/", "*.p8.png")?)?.len(), 4);
+        assert_eq!(names_of(ffs.glob("beta/", "**/*.md")?)?, vec!["intrepreterbook.md"]);
+        assert_eq!(names_of(ffs.glob("beta/", "gamma/*")?)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn tests_sql_search_works() -> Result<()> {
+        let (ffs, _d) = init("tests_sql_search")?;
+        let celestes = names_of(ffs.search_names("", "celeste*")?)?;
+        assert_eq!(celestes, vec!["celeste.p8.png", "celeste_classic2.p8.png"]);
+        assert_eq!(names_of(ffs.files_with_extension("", "mp3", true)?)?.len(), 2);
+        assert_eq!(names_of(ffs.files_with_extension("beta/", "MP3", false)?)?.len(), 2);
+        assert_eq!(names_of(ffs.files_with_extension("pico8/", ".p8", false)?)?, vec!["zzzsplore.p8"]);
+        assert_eq!(names_of(ffs.files_with_extension("", "p8.png", true)?)?.len(), 6);
+
+        // Walking away from a stream shouldn't get in the way of the next query
+        let first = ffs.glob("", "**")?.next().unwrap()?;
+        assert!(!first.file_name.is_empty());
+        assert_eq!(ffs.get_files_at("pico8/")?.len(), 5);
+        Ok(())
+    }
 }
@@ -2,6 +2,7 @@ pub mod db;
 pub mod lua_api;
 pub mod miko;
 pub mod facadefs;
+pub mod vfs_path;
 use crate::db::init_db;
 use hypertext::{html_elements, maud, rsx, GlobalAttributes, Renderable};
 use std::fmt;
@@ -8,6 +8,9 @@ use mlua::{
 
 mod sqlite;
 mod plugin;
+mod object_creation;
+
+pub use object_creation::ObjectAdapters;
 
 
 pub fn demotest() -> LuaResult<()> {
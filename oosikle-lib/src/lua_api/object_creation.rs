@@ -0,0 +1,320 @@
+use crate::db::importer::{
+    load_media_categories, ArtworkRules, InboundFileRecordContainer, NewObject, RejectedObject, WatchImportSteps,
+};
+use crate::db::{AttrValue, FileArtworkRecord, FileRecord, ObjectAttr, ObjectExtraFileRecord, ObjectRecord};
+use crate::miko::Miko;
+use anyhow::{anyhow, Result};
+use mlua::{FromLua, Lua, LuaSerdeExt, Result as luaResult, Table, Value};
+use rusqlite::Connection;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::plugin::{AdapterKind, LuaObjectAdapter, LuaPluginParseResult};
+
+type SQMiko = Miko<(Connection, Connection)>;
+
+/// Points at another file, either by uuid or by name. Names are looked up
+/// among the files in the same VFS dir, from the same import.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct LuaFileLink {
+    file_uuid: Option<String>,
+    file_name: Option<String>,
+    role: Option<String>,
+    note: Option<String>,
+}
+
+/// What `create_from_file` hands back. Anything left out gets the same default
+/// the Objects table would give it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct LuaCreatedObject {
+    name: String,
+    artist: String,
+    genre: String,
+    album_name: String,
+    album_position: u32,
+    region: Option<String>,
+    language: Option<String>,
+    imprint: String,
+    /// RFC 3339, like `2015-08-05T00:00:00Z`
+    publish_timestamp: Option<String>,
+    website: String,
+    attributes: BTreeMap<String, AttrValue>,
+    artwork: Vec<LuaFileLink>,
+    extra_files: Vec<LuaFileLink>,
+}
+
+impl FromLua for LuaCreatedObject {
+    fn from_lua(value: Value, lua: &Lua) -> luaResult<Self> {
+        if let Value::Table(_) = value {
+            lua.from_value(value)
+        } else {
+            Err(mlua::Error::runtime("create_from_file should return a table, or nil for no object"))
+        }
+    }
+}
+
+impl LuaCreatedObject {
+    fn into_new_object(self, file: &FileRecord, plugin_package_name: &str, neighbors: &[FileRecord]) -> Result<NewObject> {
+        let object_uuid = file.file_uuid.clone();
+        let find_file = |link: &LuaFileLink| -> Result<String> {
+            if let Some(file_uuid) = &link.file_uuid {
+                return Ok(file_uuid.clone());
+            }
+            let name = link
+                .file_name
+                .as_ref()
+                .ok_or(anyhow!("Linked files need either a file_uuid or a file_name"))?;
+            neighbors
+                .iter()
+                .find(|n| n.file_vfs_path == file.file_vfs_path && n.file_name.eq_ignore_ascii_case(name))
+                .map(|n| n.file_uuid.clone())
+                .ok_or(anyhow!("There's no {} next to {}", name, file.file_name))
+        };
+        let artwork = self
+            .artwork
+            .iter()
+            .map(|link| {
+                Ok(FileArtworkRecord {
+                    file_uuid: object_uuid.clone(),
+                    artwork_file_uuid: find_file(link)?,
+                    artwork_role: link.role.clone().unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let extra_files = self
+            .extra_files
+            .iter()
+            .map(|link| {
+                Ok(ObjectExtraFileRecord {
+                    object_uuid: object_uuid.clone(),
+                    file_uuid: find_file(link)?,
+                    file_note: link.note.clone().unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let object_publish_timestamp = match &self.publish_timestamp {
+            Some(ts) => OffsetDateTime::parse(ts, &Rfc3339)?,
+            None => OffsetDateTime::UNIX_EPOCH,
+        };
+        Ok(NewObject {
+            object: ObjectRecord {
+                object_uuid: object_uuid.clone(),
+                object_name: self.name,
+                plugin_package_name: plugin_package_name.to_string(),
+                object_deleted: false,
+                object_genre: self.genre,
+                object_album_name: self.album_name,
+                object_album_position: self.album_position,
+                object_region: self.region.unwrap_or("w".into()),
+                object_language: self.language.unwrap_or("en".into()),
+                object_artist: self.artist,
+                object_imprint: self.imprint,
+                object_publish_timestamp,
+                object_website: self.website,
+            },
+            attributes: self
+                .attributes
+                .into_iter()
+                .map(|(attribute_name, attribute_value)| ObjectAttr {
+                    object_uuid: object_uuid.clone(),
+                    attribute_name,
+                    attribute_value,
+                })
+                .collect(),
+            artwork,
+            extra_files,
+        })
+    }
+}
+
+/// Until there's somewhere to keep settings, adapters get each setting's default
+fn default_settings(lua: &Lua, definition: &Table) -> luaResult<Table> {
+    let settings = lua.create_table()?;
+    for pair in definition.pairs::<Value, Table>() {
+        let (name, setting) = pair?;
+        settings.set(name, setting.get::<Value>("default")?)?;
+    }
+    Ok(settings)
+}
+
+/// The object adapters of every loaded plugin, each remembering which plugin it
+/// came from so the objects it makes can say who manages them.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectAdapters {
+    adapters: Vec<(String, LuaObjectAdapter)>,
+}
+
+impl ObjectAdapters {
+    pub fn from_plugins<'a>(plugins: impl IntoIterator<Item = &'a LuaPluginParseResult>) -> Self {
+        ObjectAdapters {
+            adapters: plugins
+                .into_iter()
+                .flat_map(|p| {
+                    p.object_adapters
+                        .iter()
+                        .flatten()
+                        .map(|a| (p.namespace.clone(), a.clone()))
+                })
+                .collect(),
+        }
+    }
+
+    /// An adapter for the exact media type beats one for the type's whole category
+    fn adapter_for(&self, media_type_id: &str, media_category_id: Option<&str>) -> Option<&(String, LuaObjectAdapter)> {
+        self.adapters
+            .iter()
+            .find(|(_, a)| matches!(&a.adapter_kind, AdapterKind::MediaType(t) if t.eq_ignore_ascii_case(media_type_id)))
+            .or_else(|| {
+                let category = media_category_id?;
+                self.adapters.iter().find(
+                    |(_, a)| matches!(&a.adapter_kind, AdapterKind::MediaCategory(c) if c.eq_ignore_ascii_case(category)),
+                )
+            })
+    }
+
+    /// Hands each file with a resolved media type to its adapter's `create_from_file`.
+    /// Files without a type or an adapter are skipped, and adapters that error out
+    /// or return something unusable end up in the rejected list.
+    pub fn create_objects(
+        &self,
+        lua: &Lua,
+        miko: &SQMiko,
+        records: &[FileRecord],
+    ) -> Result<(Vec<NewObject>, Vec<RejectedObject>)> {
+        let categories = miko.send_messenger(|(conn, _)| load_media_categories(conn))?;
+        let mut new_objects = vec![];
+        let mut rejected = vec![];
+        for record in records {
+            let Some(media_type_id) = record.media_type_id() else {
+                continue;
+            };
+            let category = categories.get(&media_type_id.to_uppercase()).map(|c| c.as_str());
+            let Some((plugin_package_name, adapter)) = self.adapter_for(media_type_id, category) else {
+                continue;
+            };
+            let file_table = lua.to_value(record)?;
+            let settings = default_settings(lua, &adapter.settings_definition)?;
+            let created = adapter
+                .create_from_file
+                .call::<Option<LuaCreatedObject>>((file_table, settings))
+                .map_err(anyhow::Error::from)
+                .and_then(|created| {
+                    created
+                        .map(|c| c.into_new_object(record, plugin_package_name, records))
+                        .transpose()
+                });
+            match created {
+                Ok(Some(new_object)) => new_objects.push(new_object),
+                Ok(None) => {}
+                Err(e) => rejected.push(RejectedObject {
+                    file_uuid: record.file_uuid.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        Ok((new_objects, rejected))
+    }
+
+    /// Makes objects for an import's files, folds in what any sidecars say about
+    /// them, links up artwork by `artwork_rules`, then commits it all together
+    pub fn import(
+        &self,
+        lua: &Lua,
+        miko: &SQMiko,
+        mut container: InboundFileRecordContainer,
+        artwork_rules: &ArtworkRules,
+    ) -> Result<Vec<RejectedObject>> {
+        let (mut new_objects, mut rejected) = self.create_objects(lua, miko, container.records())?;
+        container.apply_sidecars(&mut new_objects);
+        let categories = miko.send_messenger(|(conn, _)| load_media_categories(conn))?;
+        container.associate_artwork(artwork_rules, &categories);
+        rejected.extend(container.commit_with_objects(miko.clone(), new_objects)?);
+        Ok(rejected)
+    }
+
+    /// So watch folders make objects for their new files the same way `import` does
+    pub fn watch_steps(self, lua: Lua, artwork_rules: ArtworkRules) -> WatchImportSteps {
+        WatchImportSteps {
+            artwork_rules,
+            make_objects: Some(Arc::new(move |miko: &SQMiko, records: &[FileRecord]| {
+                self.create_objects(&lua, miko, records)
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod object_creation_tests {
+    use super::*;
+    use crate::db::importer::{make_import_id_with_time, DirImportManifest, ExtensionRegistry};
+    use crate::db::Fetchable1;
+    use crate::lua_api;
+    use crate::lua_api::plugin::discover_plugins;
+    use crate::miko::ShrineDestroyer;
+
+    const PLUGIN_DIR: &str = "src/testing_data/lua/plugins";
+    const IMPORT_PATH_STR: &str = "./src/testing_data/import_test";
+    static TESTING_VALUES: &'static str = include_str!("../testing_data/sql/testing_values.sql");
+    static INIT_DB_STR: &'static str = include_str!("../db/init_db.sql");
+
+    fn init(dbname: &str) -> Result<(SQMiko, ShrineDestroyer, Lua, ObjectAdapters)> {
+        let (miko, destroyer) = Miko::construct_connection_shrine(
+            format!("file:{}?mode=memory&cache=shared", dbname).into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let lua = lua_api::init(None)?;
+        let plugin = discover_plugins(PLUGIN_DIR)?
+            .into_iter()
+            .find(|p| p.full_name() == "doc_objects")
+            .expect("Testing plugin not found");
+        let parsed = plugin.parse(&lua, &miko)?;
+        Ok((miko, destroyer, lua, ObjectAdapters::from_plugins([&parsed])))
+    }
+
+    #[test]
+    fn imported_files_become_objects() -> Result<()> {
+        let (miko, _d, lua, adapters) = init("object_creation_imports")?;
+        let registry = miko.send_messenger(|(conn, _)| ExtensionRegistry::load(conn))?;
+        let mut container = DirImportManifest::create_from_dir_on_disk(IMPORT_PATH_STR.into())?
+            .construct_container(&make_import_id_with_time()?)?;
+        container.give_ids_to_records().resolve_media_types(&registry, true);
+        let records = container.records().to_vec();
+        let uuid_of = |name: &str| {
+            records
+                .iter()
+                .find(|r| r.file_name == name)
+                .map(|r| r.file_uuid.clone())
+                .unwrap()
+        };
+        let doc_uuid = uuid_of("thing3.foo.txt");
+        let notes_uuid = uuid_of("thing2.md");
+        let zip_uuid = uuid_of("archive_with_files.zip");
+
+        let rejected = adapters.import(&lua, &miko, container, &ArtworkRules::default())?;
+        // The archive adapter never names its objects
+        assert!(rejected.len() == 1);
+        assert!(rejected[0].file_uuid == zip_uuid);
+
+        let lookup_uuid = doc_uuid.clone();
+        let (object, attrs, extras) = miko.send_messenger(move |(conn, _)| {
+            let object = ObjectRecord::get_from_id(conn, &lookup_uuid)?.expect("No object was made");
+            let attrs = ObjectAttr::get_attributes_for_object_uuid(conn, &lookup_uuid)?;
+            let extras = object.get_extra_files(conn)?;
+            Ok((object, attrs, extras))
+        })?;
+        assert!(object.object_name == "thing3");
+        assert!(object.object_artist == "Anonymous");
+        assert!(object.plugin_package_name == "oosikle.testing.doc_objects");
+        assert!(attrs.iter().any(|a| a.attribute_name == "size"));
+        assert!(extras.len() == 1);
+        assert!(extras[0].file_uuid == notes_uuid);
+
+        let zip_object = miko.send_messenger(move |(conn, _)| Ok(ObjectRecord::check_exists(conn, &zip_uuid)?))?;
+        assert!(!zip_object);
+        Ok(())
+    }
+}
@@ -180,7 +180,7 @@ impl FromLua for LuaPluginParseResult {
 
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct UnparsedLuaPlugin {
+pub(super) struct UnparsedLuaPlugin {
     name: String,
     namespace: String,
     entry_point: PathBuf,
@@ -217,7 +217,7 @@ impl UnparsedLuaPlugin {
         }
     }
 
-    fn parse(&self, lua: &Lua, miko: &Miko<(Connection, Connection)>) -> Result<LuaPluginParseResult> {
+    pub(super) fn parse(&self, lua: &Lua, miko: &Miko<(Connection, Connection)>) -> Result<LuaPluginParseResult> {
         const PLUGIN_PRELOAD_FN: &str = include_str!("./plugin_dec_pre_load.lua");
         let plugin_wrap_fn = lua.load(PLUGIN_PRELOAD_FN).eval::<Function>()?;
         let plugin_fn = lua.load(self.script_contents()).eval::<Function>()?;
@@ -259,7 +259,7 @@ impl UnparsedLuaPlugin {
     }
 }
 
-fn discover_plugins(plugin_root: &str) -> Result<Vec<UnparsedLuaPlugin>> {
+pub(super) fn discover_plugins(plugin_root: &str) -> Result<Vec<UnparsedLuaPlugin>> {
     let plugin_root = canonicalize(plugin_root)?;
     Ok(SearchBuilder::default()
         .location(&plugin_root)
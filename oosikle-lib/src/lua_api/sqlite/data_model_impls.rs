@@ -1,6 +1,7 @@
 use crate::db;
 use crate::db::*;
-use crate::lua_api::sqlite::SQLua;
+use crate::db::changes::ChangeBatch;
+use crate::lua_api::sqlite::{ChangeSubscription, SQLua};
 use anyhow::Result;
 use exemplar::Model;
 use hypertext::html_elements::object;
@@ -32,9 +33,9 @@ fn make_upsert_name(thestring: &str) -> String {
 
 macro_rules! mut_method_upsert_record {
     ($methods:ident, $type:path) => {
-        $methods.add_method_mut(make_upsert_name(stringify!($type)), |_, t, rec: $type| {
+        $methods.add_method(make_upsert_name(stringify!($type)), |_, t, rec: $type| {
             let reccopy = rec.clone();
-            t.0.send_messenger(move |(_, conn)| {
+            t.with_writer(move |conn| {
                 reccopy.insert_or(conn, exemplar::OnConflict::Replace)?;
                 Ok(reccopy)
             })?;
@@ -75,7 +76,12 @@ impl UserData for SQLua {
     fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {}
 
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method_mut("query", SQLua::query);
+        // Shared borrows only, since these get called from inside DB:transaction
+        methods.add_method("query", SQLua::query);
+        methods.add_method("transaction", SQLua::transaction);
+        methods.add_method("on_change", SQLua::on_change);
+        methods.add_method("file_digests", SQLua::file_digests);
+        methods.add_method("find_files_with_digest", SQLua::find_files_with_digest);
         mut_method_upsert_record!(methods,
             MediaCategoryRecord,
             MediaTypeRecord,
@@ -83,6 +89,7 @@ impl UserData for SQLua {
             MediaTypeForFileExtensionsRecord,
             FileRecord,
             FileArtworkRecord,
+            FileDigestRecord,
             ObjectAttr,
             ObjectExtraFileRecord,
             ObjectRecord,
@@ -94,6 +101,12 @@ impl UserData for SQLua {
     }
 }
 
+impl UserData for ChangeSubscription {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("unsubscribe", ChangeSubscription::unsubscribe);
+    }
+}
+
 make_sql_lua_boilerplate![
     MediaCategoryRecord,
     MediaTypeRecord,
@@ -101,6 +114,7 @@ make_sql_lua_boilerplate![
     MediaTypeForFileExtensionsRecord,
     FileRecord,
     FileArtworkRecord,
+    FileDigestRecord,
     ObjectAttr,
     ObjectExtraFileRecord,
     ObjectRecord,
@@ -108,6 +122,7 @@ make_sql_lua_boilerplate![
     PageOfObjectsInCollection,
     CollectionRecord,
     DeviceRecord,
-    DeviceSyncListRecord
+    DeviceSyncListRecord,
+    ChangeBatch
 ];
 
@@ -1,12 +1,15 @@
 use crate::db::*;
+use crate::db::changes::{ChangeBatch, ChangeFeed};
+use crate::db::diagnostics;
+use crate::db::transaction::WriterSession;
 use crate::miko;
 use crate::{db, miko::Miko};
 use anyhow::Result;
 use exemplar::Model;
 use hypertext::html_elements::object;
 use mlua::{
-    chunk, ExternalResult, FromLua, IntoLua, Lua, LuaSerdeExt, Result as luaResult, Table,
-    UserData, Value,
+    chunk, ExternalResult, FromLua, Function, IntoLua, Lua, LuaSerdeExt, MultiValue,
+    Result as luaResult, Table, UserData, Value,
 };
 use rusqlite::{
     fallible_streaming_iterator::FallibleStreamingIterator,
@@ -16,13 +19,36 @@ use rusqlite::{
     Result as rResult, Row, ToSql,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt, iter::zip, path::PathBuf};
+use std::{fmt, iter::zip, path::PathBuf, sync::mpsc, sync::Mutex, thread};
+use uuid::Uuid;
 
 mod data_model_impls;
 
 type SQMiko = Miko<(Connection, Connection)>;
 #[derive(Debug)]
-pub struct SQLua(Miko<(Connection, Connection)>);
+pub struct SQLua {
+    miko: Miko<(Connection, Connection)>,
+    /// The open `DB:transaction` session, if there is one. While it's set,
+    /// everything the DB global does goes through the session.
+    session: Mutex<Option<WriterSession>>,
+    changes: Option<ChangeFeed>,
+}
+
+/// What `DB:on_change` hands back. `handle:unsubscribe()` stops the callback for
+/// good. Letting the handle get collected doesn't, so callbacks can be set and forgotten.
+#[derive(Debug)]
+pub struct ChangeSubscription {
+    /// `None` tells the callback's thread to stop
+    batches: mpsc::Sender<Option<ChangeBatch>>,
+}
+
+impl ChangeSubscription {
+    pub fn unsubscribe(_lua: &Lua, this: &ChangeSubscription, _: ()) -> luaResult<()> {
+        // Already stopped if the thread's gone
+        let _ = this.batches.send(None);
+        Ok(())
+    }
+}
 
 impl SQMiko {
     pub fn construct_connection_shrine(
@@ -30,7 +56,7 @@ impl SQMiko {
         init_script: &str,
     ) -> Result<(SQMiko, miko::ShrineDestroyer)> {
         let string_script = init_script.to_string();
-        Ok(Miko::build_shrine("sqlite_prime", move || {
+        Ok(Miko::build_shrine_with_aftercare("sqlite_prime", move || {
             let writer_conn = Connection::open(&db_loc)?;
             let read_only_conn = Connection::open_with_flags(
                 &db_loc,
@@ -41,18 +67,154 @@ impl SQMiko {
             let _ = &writer_conn
                 .execute_batch(&string_script)
                 .map_err(mlua::Error::external)?;
+            db::add_missing_columns(&writer_conn)?;
             let _ = &read_only_conn.execute("PRAGMA query_only=true;", ())?;
+            crate::facadefs::register_vfs_functions(&writer_conn)?;
+            crate::facadefs::register_vfs_functions(&read_only_conn)?;
+            diagnostics::watch_for_slow_statements(&writer_conn);
+            diagnostics::watch_for_slow_statements(&read_only_conn);
             Ok((read_only_conn, writer_conn))
-        })?)
+        }, |(read_only_conn, _)| diagnostics::explain_slow_statements(read_only_conn))?)
     }
 }
 
 impl SQLua {
     pub fn add_to_lua(sql_miko: SQMiko, lua: &Lua) -> Result<()> {
-        let this = SQLua(sql_miko);
+        let this = SQLua {
+            miko: sql_miko,
+            session: Mutex::new(None),
+            changes: None,
+        };
         lua.globals().set("DB", this)?;
         Ok(())
     }
+
+    /// Like `add_to_lua`, but also lets scripts use `DB:on_change`.
+    pub fn add_to_lua_with_changes(sql_miko: SQMiko, changes: ChangeFeed, lua: &Lua) -> Result<()> {
+        let this = SQLua {
+            miko: sql_miko,
+            session: Mutex::new(None),
+            changes: Some(changes),
+        };
+        lua.globals().set("DB", this)?;
+        Ok(())
+    }
+
+    /// Runs `messenger` on the writer, inside the open transaction if there is one.
+    pub fn with_writer<R: Send + 'static>(
+        &self,
+        messenger: impl FnOnce(&Connection) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let session = self.session.lock().expect("The session slot was poisoned");
+        match session.as_ref() {
+            Some(session) => session.run(messenger),
+            None => self.miko.send_messenger(move |(_, conn)| messenger(conn)),
+        }
+    }
+
+    /// Runs `messenger` on the reader, or inside the open transaction if there is one
+    /// so it sees what the transaction wrote.
+    fn with_reader<R: Send + 'static>(
+        &self,
+        messenger: impl FnOnce(&Connection) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let session = self.session.lock().expect("The session slot was poisoned");
+        match session.as_ref() {
+            Some(session) => session.run(messenger),
+            None => self.miko.send_messenger(move |(conn, _)| messenger(conn)),
+        }
+    }
+
+    /// `DB:file_digests(file_uuid)`. A table of algorithm to digest, like `{crc32 = "414fa339"}`.
+    pub fn file_digests(lua: &Lua, this: &SQLua, file_uuid: String) -> luaResult<Table> {
+        let digests = this
+            .with_reader(move |conn| Ok(FileDigestRecord::get_digests_for_file(conn, &file_uuid)?))
+            .into_lua_err()?;
+        let t = lua.create_table()?;
+        for digest in digests {
+            t.set(digest.digest_algorithm, digest.digest_value)?;
+        }
+        Ok(t)
+    }
+
+    /// `DB:find_files_with_digest(algorithm, value)`. Every file with that digest.
+    pub fn find_files_with_digest(
+        _lua: &Lua,
+        this: &SQLua,
+        (algorithm, value): (String, String),
+    ) -> luaResult<Vec<FileRecord>> {
+        this.with_reader(move |conn| Ok(FileDigestRecord::find_files_with_digest(conn, &algorithm, &value)?))
+            .into_lua_err()
+    }
+
+    /// `DB:on_change(fn)`. `fn` is called with every committed `ChangeBatch`, from a
+    /// thread of its own, until the `ChangeSubscription` it returns is unsubscribed.
+    pub fn on_change(_lua: &Lua, this: &SQLua, func: Function) -> luaResult<ChangeSubscription> {
+        let Some(changes) = &this.changes else {
+            return Err(mlua::Error::external("This DB wasn't given a change feed"));
+        };
+        let (tx, rx) = mpsc::channel::<Option<ChangeBatch>>();
+        let forward = tx.clone();
+        changes.subscribe_with(move |batch| forward.send(Some(batch)).is_ok());
+        thread::Builder::new()
+            .name("oosikle_lua_on_change".into())
+            .spawn(move || {
+                // Dropping `rx` on the way out is what takes us off the feed
+                while let Ok(Some(batch)) = rx.recv() {
+                    if let Err(e) = func.call::<()>(batch) {
+                        tracing::warn!(error = ?e, "A change callback raised an error");
+                    }
+                }
+            })
+            .into_lua_err()?;
+        Ok(ChangeSubscription { batches: tx })
+    }
+
+    /// `DB:transaction(fn)`. Commits if `fn` returns normally and rolls back if it
+    /// errors. Nested calls become savepoints inside the outer transaction.
+    pub fn transaction(_lua: &Lua, this: &SQLua, func: Function) -> luaResult<MultiValue> {
+        let mut session = this.session.lock().expect("The session slot was poisoned");
+        if let Some(outer) = session.as_ref() {
+            let savepoint = format!("lua_{}", Uuid::now_v7().simple());
+            outer.savepoint(&savepoint).into_lua_err()?;
+            drop(session);
+            let res = func.call::<MultiValue>(());
+            let session = this.session.lock().expect("The session slot was poisoned");
+            let Some(outer) = session.as_ref() else {
+                return Err(mlua::Error::external("The outer transaction ended while a nested one was running"));
+            };
+            if let Err(e) = res {
+                // Whatever went wrong in `fn` matters more than the rollback failing too
+                let undone = outer
+                    .rollback_to_savepoint(&savepoint)
+                    .and_then(|_| outer.release_savepoint(&savepoint));
+                if let Err(rollback_error) = undone {
+                    tracing::error!(error = ?rollback_error, "Couldn't roll back to a savepoint");
+                }
+                return Err(e);
+            }
+            outer.release_savepoint(&savepoint).into_lua_err()?;
+            return res;
+        }
+        *session = Some(WriterSession::begin(&this.miko).into_lua_err()?);
+        drop(session);
+        let res = func.call::<MultiValue>(());
+        let Some(finished) = this.session.lock().expect("The session slot was poisoned").take() else {
+            return Err(mlua::Error::external("The transaction ended while it was running"));
+        };
+        match res {
+            Ok(values) => {
+                finished.commit().into_lua_err()?;
+                Ok(values)
+            }
+            Err(e) => {
+                if let Err(rollback_error) = finished.rollback() {
+                    tracing::error!(error = ?rollback_error, "Couldn't roll back a transaction");
+                }
+                Err(e)
+            }
+        }
+    }
 }
 
 fn value_to_lua(lua: &Lua, value: ValueRef) -> luaResult<Value> {
@@ -154,6 +316,44 @@ impl FromLua for LiberatedColumn {
     }
 }
 
+fn collect_query_rows(
+    stmt: &mut CachedStatement,
+    params: Option<Vec<LiberatedColumn>>,
+) -> rResult<(Vec<String>, Vec<Vec<LiberatedColumn>>)> {
+    let headers: Vec<String> = (stmt)
+        .column_names()
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    /*
+    for (ind, thingy) in params.into_iter().enumerate() {
+        print!("binding param {:?} at position {:?}", &thingy, &ind);
+        stmt.raw_bind_parameter(ind + 1, thingy)?;
+    } */
+    let the_params = params_from_iter(match params {
+        Some(s) => s,
+        None => vec![]
+    }.into_iter());
+    let query_result = match (*stmt).query(the_params) {
+        Ok(mut s) => {
+            let mut ret: Vec<Vec<LiberatedColumn>> = Vec::new();
+            while let Some(row) = s.next()? {
+                let mut retrow: Vec<LiberatedColumn> = Vec::new();
+                for header in &headers {
+                    retrow.push(row.get::<&str, LiberatedColumn>(header.as_str())?);
+                }
+                ret.push(retrow);
+            }
+            ret
+        }
+        Err(_e) => {
+            vec![]
+        }
+    };
+    //let mut query_result = stmt.raw_query();
+    Ok((headers, query_result))
+}
+
 impl SQLua {
     /*
     pub fn get_ext_attributes_for_object(
@@ -180,7 +380,7 @@ impl SQLua {
 
     pub fn query(
         lua: &Lua,
-        this: &mut SQLua,
+        this: &SQLua,
         (sqlstr, params): (String, Option<Vec<LiberatedColumn>>),
     ) -> luaResult<Table> {
         /*
@@ -189,48 +389,30 @@ impl SQLua {
             .map(|v| v.to_sql().expect("Falat error parsing lua"))
             .collect::<Vec<rValue>>();
         */
-        let (headers, rows) = match this.0.send_mutating_messenger(move |(read_conn, _)| {
-            /*
-            let mut p1: Vec<&dyn ToSql> = vec![];
-            for n in &params {
-                p1.push(n);
-            }
-            let pp: &[&dyn ToSql] = p1.as_slice();  */
-            let trans = &mut read_conn.transaction()?;
-            let stmt = &mut trans.prepare_cached(&sqlstr)?;
-            let headers: Vec<String> = (stmt)
-                .column_names()
-                .iter()
-                .map(|s| s.to_string())
-                .collect();
-            /*
-            for (ind, thingy) in params.into_iter().enumerate() {
-                print!("binding param {:?} at position {:?}", &thingy, &ind);
-                stmt.raw_bind_parameter(ind + 1, thingy)?;
-            } */
-            let the_params = params_from_iter(match params {
-                Some(s) => s,
-                None => vec![]
-            }.into_iter());
-            let query_result = match (*stmt).query(the_params) {
-                Ok(mut s) => {
-                    let mut ret: Vec<Vec<LiberatedColumn>> = Vec::new();
-                    while let Some(row) = s.next()? {
-                        let mut retrow: Vec<LiberatedColumn> = Vec::new();
-                        for header in &headers {
-                            retrow.push(row.get::<&str, LiberatedColumn>(header.as_str())?);
-                        }
-                        ret.push(retrow);
-                    }
-                    ret
+        let session = this.session.lock().expect("The session slot was poisoned");
+        let query_res = match session.as_ref() {
+            // The session is holding the shrine, so read through it (and see its own writes)
+            Some(session) => session.run(move |conn| {
+                let stmt = &mut conn.prepare_cached(&sqlstr)?;
+                if !stmt.readonly() {
+                    return Err(anyhow::anyhow!("DB:query only runs statements that don't write"));
                 }
-                Err(_e) => {
-                    vec![]
+                Ok(collect_query_rows(stmt, params)?)
+            }),
+            None => this.miko.send_mutating_messenger(move |(read_conn, _)| {
+                /*
+                let mut p1: Vec<&dyn ToSql> = vec![];
+                for n in &params {
+                    p1.push(n);
                 }
-            };
-            //let mut query_result = stmt.raw_query();
-            Ok((headers, query_result))
-        }) {
+                let pp: &[&dyn ToSql] = p1.as_slice();  */
+                let trans = &mut read_conn.transaction()?;
+                let stmt = &mut trans.prepare_cached(&sqlstr)?;
+                Ok(collect_query_rows(stmt, params)?)
+            }),
+        };
+        drop(session);
+        let (headers, rows) = match query_res {
             Ok(thing) => thing,
             Err(e) => {
                 println!("query generated an error! It is {:?}", e);
@@ -387,4 +569,79 @@ mod read_from_lua_tests {
         println!("doesn't write in query test end");
         Ok(())
     }
+
+    #[test]
+    fn transaction_commits_every_upsert() -> Result<()> {
+        let (lua, des) = init("lua_transaction_commits")?;
+        let res = lua
+            .load("SQLuaTransactionCommits([[foob]], [[foob_key]])")
+            .eval::<String>()?;
+        assert!(res == "foob");
+        des.invoke();
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_rolls_back_on_error() -> Result<()> {
+        let (lua, des) = init("lua_transaction_rolls_back")?;
+        let res = lua
+            .load("SQLuaTransactionRollsBack([[foob]], [[foob_key]])")
+            .eval::<i64>()?;
+        assert!(res == 0);
+        des.invoke();
+        Ok(())
+    }
+
+    #[test]
+    fn nested_transaction_is_a_savepoint() -> Result<()> {
+        let (lua, des) = init("lua_transaction_nested")?;
+        let res = lua
+            .load("SQLuaNestedTransactionRollsBackInner([[outer]], [[inner]])")
+            .eval::<i64>()?;
+        assert!(res == 1);
+        des.invoke();
+        Ok(())
+    }
+
+    #[test]
+    fn files_can_be_found_by_digest() -> Result<()> {
+        let (lua, des) = init("lua_file_digests")?;
+        let res = lua
+            .load("SQLuaFindsFilesByDigest([[DEADBEEFDEADBEEFDEADBEEFDEADBEEF]], [[414fa339]])")
+            .eval::<String>()?;
+        assert!(res == "welcome.txt:414fa339");
+        des.invoke();
+        Ok(())
+    }
+
+    #[test]
+    fn on_change_callback_hears_upserts() -> Result<()> {
+        let lua = lua_api::init(None).expect("Lua failed to initialize");
+        lua.load(TESTING_LUA).exec()?;
+        let (miko, des) = Miko::construct_connection_shrine(
+            "file:lua_on_change?mode=memory&cache=shared".into(),
+            &(INIT_DB_STR.to_string() + TESTING_VALUES),
+        )?;
+        let feed = ChangeFeed::attach(&miko)?;
+        SQLua::add_to_lua_with_changes(miko, feed, &lua)?;
+        lua.load("SQLuaWatchesChanges()").exec()?;
+        lua.load("SQLuaAddsMediaCategory([[foob]], [[foob_key]])").exec()?;
+        let mut heard = String::new();
+        for _ in 0..50 {
+            heard = lua.load("ChangedTables[1] or ''").eval::<String>()?;
+            if !heard.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert!(heard == "MediaCategories");
+
+        lua.load("SQLuaStopsWatchingChanges()").exec()?;
+        lua.load("SQLuaAddsMediaCategory([[barb]], [[barb_key]])").exec()?;
+        // Well past when the batch would have been published
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(lua.load("#ChangedTables").eval::<i64>()? == 0);
+        des.invoke();
+        Ok(())
+    }
 }
@@ -1,23 +1,58 @@
 use anyhow::{Error, Result};
 use mlua::Thread;
 use std::mem::replace;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::Instant;
 use uuid;
 
+mod stats;
+pub use stats::{MessengerStats, ShrineStatsSnapshot};
+use stats::ShrineStats;
+
 pub type RawMessenger<T> = Option<Box<dyn FnOnce(&mut T) -> Result<()> + Send + 'static>>;
 type ShrineDestroyingFunction = Box<dyn FnOnce() -> Result<()> + 'static>;
 
+struct Envelope<T> {
+    fn_package: RawMessenger<T>,
+    label: Option<Arc<str>>,
+    queued_at: Instant,
+}
+
+/// How many interactive messengers the shrine will run back-to-back while
+/// background work is waiting, before it lets one background messenger through.
+const INTERACTIVE_BURST_LIMIT: usize = 8;
+
+/// Which queue a messenger waits in. The shrine always prefers `Interactive`,
+/// but `Background` work is never starved outright.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Lane {
+    #[default]
+    Interactive,
+    Background,
+}
+
 #[derive(Debug)]
 pub struct Miko<T> {
-    chan: mpsc::Sender<RawMessenger<T>>,
+    interactive: mpsc::Sender<Envelope<T>>,
+    background: mpsc::Sender<Envelope<T>>,
+    // One ring per queued messenger, so the shrine can block on a single channel
+    doorbell: mpsc::Sender<()>,
+    lane: Lane,
+    label: Option<Arc<str>>,
+    stats: Arc<ShrineStats>,
 }
 
 
 impl<T> Clone for Miko<T> {
     fn clone(&self) -> Self {
         Miko {
-            chan: self.chan.clone()
+            interactive: self.interactive.clone(),
+            background: self.background.clone(),
+            doorbell: self.doorbell.clone(),
+            lane: self.lane,
+            label: self.label.clone(),
+            stats: self.stats.clone(),
         }
     }
     
@@ -25,6 +60,44 @@ impl<T> Clone for Miko<T> {
 
 pub struct ShrineDestroyer(Option<ShrineDestroyingFunction>, thread::Thread);
 
+struct ShrineQueues<T> {
+    interactive: mpsc::Receiver<Envelope<T>>,
+    background: mpsc::Receiver<Envelope<T>>,
+    interactive_streak: usize,
+    stats: Arc<ShrineStats>,
+}
+
+impl<T> ShrineQueues<T> {
+    /// Only called after a doorbell ring, so at least one lane has something in it.
+    fn next_messenger(&mut self) -> Option<Envelope<T>> {
+        let background_first = self.interactive_streak >= INTERACTIVE_BURST_LIMIT;
+        let (first, second) = if background_first {
+            (&self.background, &self.interactive)
+        } else {
+            (&self.interactive, &self.background)
+        };
+        let (fn_package, from_first) = match first.try_recv() {
+            Ok(p) => (p, true),
+            Err(_) => (second.try_recv().ok()?, false),
+        };
+        if from_first != background_first {
+            self.interactive_streak += 1;
+            self.stats.dequeued(Lane::Interactive);
+        } else {
+            self.interactive_streak = 0;
+            self.stats.dequeued(Lane::Background);
+        }
+        Some(fn_package)
+    }
+
+    /// For shutting down, so nothing interactive that was already queued gets dropped
+    fn next_interactive(&mut self) -> Option<Envelope<T>> {
+        let envelope = self.interactive.try_recv().ok()?;
+        self.stats.dequeued(Lane::Interactive);
+        Some(envelope)
+    }
+}
+
 impl<T> Miko<T>
 where
     T: 'static,
@@ -33,47 +106,151 @@ where
         label: &str,
         kami_summoner: impl FnOnce() -> Result<T> + Send + 'static,
     ) -> Result<(Miko<T>, ShrineDestroyer)> {
-        let (chan, rx) = mpsc::channel::<RawMessenger<T>>();
+        Self::build_shrine_with_aftercare(label, kami_summoner, |_| {})
+    }
+
+    /// Like `build_shrine`, but `aftercare` runs on the kami after every messenger.
+    pub fn build_shrine_with_aftercare(
+        label: &str,
+        kami_summoner: impl FnOnce() -> Result<T> + Send + 'static,
+        mut aftercare: impl FnMut(&mut T) + Send + 'static,
+    ) -> Result<(Miko<T>, ShrineDestroyer)> {
+        let (interactive, interactive_rx) = mpsc::channel::<Envelope<T>>();
+        let (background, background_rx) = mpsc::channel::<Envelope<T>>();
+        let (doorbell, doorbell_rx) = mpsc::channel::<()>();
+        let stats = Arc::new(ShrineStats::new(label));
+        let shrine_label = label.to_string();
         let b =
             thread::Builder::new().name(format!("miko_shrine_{}_{}", label, uuid::Uuid::new_v4()));
 
+        let shrine_stats = stats.clone();
         let shrine_handle: thread::JoinHandle<()> = b.spawn(move || {
             let mut kami = kami_summoner().expect("Failure getting the value");
-            println!("Setting up thread {:?}", thread::current().name());
-            for fn_package in rx {
-                if let Some(the_fn) = fn_package {
-                    match the_fn(&mut kami) {
-                        Ok(_) => {
-                            println!("A function completed successfully");
-                            continue;
-                        }
-                        Err(n) => {
-                            println!("There has been an error: {:?}", n);
-                            continue;
-                        },
-                    };
-                } else {
-                    break;
+            tracing::debug!(thread = ?thread::current().name(), "Setting up shrine");
+            let mut queues = ShrineQueues {
+                interactive: interactive_rx,
+                background: background_rx,
+                interactive_streak: 0,
+                stats: shrine_stats.clone(),
+            };
+            let mut run = |kami: &mut T, envelope: Envelope<T>| {
+                let Some(the_fn) = envelope.fn_package else {
+                    return;
+                };
+                let waited = envelope.queued_at.elapsed();
+                let label = envelope.label.as_deref();
+                let span = tracing::debug_span!("messenger", shrine = %shrine_label, label);
+                let _entered = span.enter();
+                let started = Instant::now();
+                let res = the_fn(kami);
+                let ran = started.elapsed();
+                aftercare(kami);
+                shrine_stats.record(label, waited, ran, res.is_ok());
+                match res {
+                    Ok(_) => tracing::trace!(?waited, ?ran, "Messenger completed"),
+                    Err(n) => tracing::warn!(error = ?n, ?waited, ?ran, "Messenger failed"),
+                };
+            };
+            for () in doorbell_rx {
+                let Some(envelope) = queues.next_messenger() else {
+                    continue;
                 };
+                if envelope.fn_package.is_none() {
+                    // The burst limit can let shutdown past interactive work, so that goes first
+                    while let Some(envelope) = queues.next_interactive() {
+                        run(&mut kami, envelope);
+                    }
+                    break;
+                }
+                run(&mut kami, envelope);
             }
         })?;
-        let chanclone = chan.clone();
+        let miko = Miko {
+            interactive,
+            background,
+            doorbell,
+            lane: Lane::Interactive,
+            label: None,
+            stats,
+        };
+        // Shutdown waits in the background lane, so queued interactive work still finishes
+        let closer = miko.with_lane(Lane::Background);
         let shrine = shrine_handle.thread().clone();
         Ok((
-            Miko { chan },
+            miko,
             ShrineDestroyer(Some(Box::new(move || {
-                chanclone.send(None).unwrap();
+                closer.send_to_lane(None)?;
                 shrine_handle.join().unwrap();
                 Ok(())
             })), shrine),
         ))
     }
 
+    /// A handle to the same shrine whose messengers all go through `lane`.
+    pub fn with_lane(&self, lane: Lane) -> Miko<T> {
+        Miko {
+            lane,
+            ..self.clone()
+        }
+    }
+
+    pub fn background(&self) -> Miko<T> {
+        self.with_lane(Lane::Background)
+    }
+
+    pub fn lane(&self) -> Lane {
+        self.lane
+    }
+
+    /// A handle to the same shrine whose messengers are counted under `label` in the stats.
+    pub fn with_label(&self, label: &str) -> Miko<T> {
+        Miko {
+            label: Some(Arc::from(label)),
+            ..self.clone()
+        }
+    }
+
+    pub fn stats(&self) -> ShrineStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    fn send_to_lane(&self, fn_package: RawMessenger<T>) -> Result<()> {
+        let chan = match self.lane {
+            Lane::Interactive => &self.interactive,
+            Lane::Background => &self.background,
+        };
+        // Counted before sending, so the shrine never sees the queue go below zero
+        self.stats.queued(self.lane);
+        let sent = chan.send(Envelope {
+            fn_package,
+            label: self.label.clone(),
+            queued_at: Instant::now(),
+        });
+        if sent.is_err() {
+            self.stats.dequeued(self.lane);
+            return Err(anyhow::anyhow!("There was an error: the shrine has closed"));
+        }
+        if let Err(e) = self.doorbell.send(()) {
+            // The envelope made it into the lane, but nothing will ever take it out
+            self.stats.dequeued(self.lane);
+            return Err(anyhow::anyhow!("The shrine isn't listening: {:?}", e));
+        }
+        Ok(())
+    }
+
+    pub fn send_raw_messenger_in_lane(
+        &self,
+        lane: Lane,
+        the_fn: impl FnOnce(&mut T) -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        self.with_lane(lane).send_raw_messenger(the_fn)
+    }
+
     pub fn send_raw_messenger(
         &self,
         the_fn: impl FnOnce(&mut T) -> Result<()> + Send + 'static,
     ) -> Result<()> {
-        let res = self.chan.send(Some(Box::new(the_fn)));
+        let res = self.send_to_lane(Some(Box::new(the_fn)));
         match res {
             Ok(_) => Ok(()),
             Err(e) => {
@@ -159,4 +336,78 @@ mod miko_tests {
         assert!(res == test_message.to_string());
         Ok(())
     }
+
+    // Parks the shrine until the returned sender is used, so work can pile up behind it
+    fn block_shrine(miko: &Miko<Vec<String>>) -> Result<mpsc::Sender<()>> {
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let (parked_tx, parked_rx) = mpsc::channel::<()>();
+        miko.send_raw_messenger_in_lane(Lane::Background, move |_| {
+            parked_tx.send(())?;
+            gate_rx.recv()?;
+            Ok(())
+        })?;
+        parked_rx.recv()?;
+        Ok(gate_tx)
+    }
+
+    fn push_label(miko: &Miko<Vec<String>>, lane: Lane, label: String) -> Result<()> {
+        miko.send_raw_messenger_in_lane(lane, move |log| {
+            log.push(label);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn interactive_lane_jumps_the_queue() -> Result<()> {
+        let (miko, _thing) = Miko::build_shrine("lanes1", || Ok(Vec::<String>::new()))?;
+        let gate = block_shrine(&miko)?;
+        for n in 0..3 {
+            push_label(&miko, Lane::Background, format!("bg{}", n))?;
+        }
+        for n in 0..3 {
+            push_label(&miko, Lane::Interactive, format!("ui{}", n))?;
+        }
+        gate.send(())?;
+        let log = miko.background().send_messenger(|log| Ok(log.clone()))?;
+        assert_eq!(log, vec!["ui0", "ui1", "ui2", "bg0", "bg1", "bg2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn background_lane_is_not_starved() -> Result<()> {
+        let (miko, _thing) = Miko::build_shrine("lanes2", || Ok(Vec::<String>::new()))?;
+        let gate = block_shrine(&miko)?;
+        push_label(&miko, Lane::Background, "bg".into())?;
+        for n in 0..(INTERACTIVE_BURST_LIMIT * 2) {
+            push_label(&miko, Lane::Interactive, format!("ui{}", n))?;
+        }
+        gate.send(())?;
+        let log = miko.background().send_messenger(|log| Ok(log.clone()))?;
+        let bg_position = log.iter().position(|l| l == "bg").expect("Background work never ran");
+        assert_eq!(bg_position, INTERACTIVE_BURST_LIMIT);
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_waits_for_interactive_work() -> Result<()> {
+        let (miko, destroyer) = Miko::build_shrine("lanes3", || Ok(Vec::<String>::new()))?;
+        let ran = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let gate = block_shrine(&miko)?;
+        let queued = INTERACTIVE_BURST_LIMIT * 2;
+        for _ in 0..queued {
+            let ran = ran.clone();
+            miko.send_raw_messenger(move |_| {
+                ran.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            })?;
+        }
+        let closing = thread::spawn(move || destroyer.invoke());
+        while miko.stats().queued_background == 0 {
+            thread::yield_now();
+        }
+        gate.send(())?;
+        closing.join().unwrap();
+        assert_eq!(ran.load(std::sync::atomic::Ordering::SeqCst), queued);
+        Ok(())
+    }
 }
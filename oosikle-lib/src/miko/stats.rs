@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::Lane;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessengerStats {
+    pub completed: u64,
+    pub failed: u64,
+    /// Time spent queued before the shrine picked the messenger up
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    /// Time spent running inside the shrine
+    pub total_exec: Duration,
+    pub max_exec: Duration,
+}
+
+impl MessengerStats {
+    fn record(&mut self, waited: Duration, ran: Duration, succeeded: bool) {
+        if succeeded {
+            self.completed += 1;
+        } else {
+            self.failed += 1;
+        }
+        self.total_wait += waited;
+        self.max_wait = self.max_wait.max(waited);
+        self.total_exec += ran;
+        self.max_exec = self.max_exec.max(ran);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.completed + self.failed
+    }
+
+    pub fn mean_wait(&self) -> Duration {
+        self.total_wait
+            .checked_div(self.count() as u32)
+            .unwrap_or_default()
+    }
+
+    pub fn mean_exec(&self) -> Duration {
+        self.total_exec
+            .checked_div(self.count() as u32)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShrineStatsSnapshot {
+    pub shrine: String,
+    pub queued_interactive: usize,
+    pub queued_background: usize,
+    pub overall: MessengerStats,
+    /// Only messengers sent through a labelled Miko show up here
+    pub by_label: BTreeMap<String, MessengerStats>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct ShrineStats {
+    shrine: String,
+    queued_interactive: AtomicUsize,
+    queued_background: AtomicUsize,
+    finished: Mutex<(MessengerStats, BTreeMap<String, MessengerStats>)>,
+}
+
+impl ShrineStats {
+    pub(super) fn new(shrine: &str) -> Self {
+        ShrineStats {
+            shrine: shrine.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn queue_for(&self, lane: Lane) -> &AtomicUsize {
+        match lane {
+            Lane::Interactive => &self.queued_interactive,
+            Lane::Background => &self.queued_background,
+        }
+    }
+
+    pub(super) fn queued(&self, lane: Lane) {
+        self.queue_for(lane).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn dequeued(&self, lane: Lane) {
+        self.queue_for(lane).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record(&self, label: Option<&str>, waited: Duration, ran: Duration, succeeded: bool) {
+        let mut finished = self.finished.lock().expect("Shrine stats were poisoned");
+        finished.0.record(waited, ran, succeeded);
+        if let Some(label) = label {
+            finished
+                .1
+                .entry(label.to_string())
+                .or_default()
+                .record(waited, ran, succeeded);
+        }
+    }
+
+    pub(super) fn snapshot(&self) -> ShrineStatsSnapshot {
+        let finished = self.finished.lock().expect("Shrine stats were poisoned");
+        ShrineStatsSnapshot {
+            shrine: self.shrine.clone(),
+            queued_interactive: self.queued_interactive.load(Ordering::Relaxed),
+            queued_background: self.queued_background.load(Ordering::Relaxed),
+            overall: finished.0.clone(),
+            by_label: finished.1.clone(),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+return function()
+    return {
+        namespace = "oosikle.testing.doc_objects",
+
+        authors = { "Person1" },
+        version = 1,
+        date = "2025-06-10",
+
+        object_adapters = {
+            {
+                media_type = "plaintext",
+                create_from_file = function(file_table, settings)
+                    return {
+                        name = string.match(file_table.file_name, "^[^.]+"),
+                        artist = settings.default_author,
+                        attributes = {
+                            size = file_table.file_size_bytes,
+                        },
+                        extra_files = {
+                            { file_name = "thing2.md", note = "notes" },
+                        },
+                    }
+                end,
+                import_file = function(file_path, settings) end,
+                settings = {
+                    default_author = { type = "string", default = "Anonymous" },
+                },
+            },
+            {
+                media_category = "archive",
+                create_from_file = function(file_table, settings)
+                    -- No name, so this one should never make it in
+                    return { artist = "Nobody" }
+                end,
+                import_file = function(file_path, settings) end,
+                settings = {},
+            },
+        },
+    }
+end
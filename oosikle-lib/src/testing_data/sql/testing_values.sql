@@ -124,7 +124,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'alpha/'
+        'alpha/',
+        NULL
     );
 insert into FileBlobs
 values (
@@ -143,7 +144,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'alpha/only_one_file/'
+        'alpha/only_one_file/',
+        NULL
     );
 insert into Files
 values (
@@ -157,7 +159,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'pico8/celeste/'
+        'pico8/celeste/',
+        NULL
     );
 insert into Objects
 values (
@@ -187,7 +190,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'pico8/'
+        'pico8/',
+        NULL
     );
 insert into Objects
 values (
@@ -217,7 +221,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'pico8/'
+        'pico8/',
+        NULL
     );
 insert into Objects
 values (
@@ -247,7 +252,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'pico8/celeste/'
+        'pico8/celeste/',
+        NULL
     );
 insert into Objects
 values (
@@ -277,7 +283,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'pico8/'
+        'pico8/',
+        NULL
     );
 insert into Objects
 values (
@@ -307,7 +314,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'pico8/'
+        'pico8/',
+        NULL
     );
 insert into Objects
 values (
@@ -337,7 +345,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'pico8/'
+        'pico8/',
+        NULL
     );
 insert into Files
 values (
@@ -351,7 +360,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'mastersystem/'
+        'mastersystem/',
+        NULL
     );
 insert into Files
 values (
@@ -365,7 +375,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'mastersystem/'
+        'mastersystem/',
+        NULL
     );
 insert into Files
 values (
@@ -379,7 +390,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'beta/'
+        'beta/',
+        NULL
     );
 insert into Files
 values (
@@ -393,7 +405,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'beta/'
+        'beta/',
+        NULL
     );
 insert into Files
 values (
@@ -407,7 +420,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'beta/gamma/'
+        'beta/gamma/',
+        NULL
     );
 insert into Files
 values (
@@ -421,7 +435,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'beta/gamma/'
+        'beta/gamma/',
+        NULL
     );
 insert into Files
 values (
@@ -435,7 +450,8 @@ values (
         NULL,
         FALSE,
         FALSE,
-        'beta/gamma/theta/'
+        'beta/gamma/theta/',
+        NULL
     );
 insert into Objects
 values (
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A dir in the VFS the way `Files.file_vfs_path` holds it: blank for the root,
+/// and otherwise names that each end in a slash. Anything that makes it through
+/// `parse` can go straight into a prefix query, since nothing in it is special
+/// to SQLite.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct VfsDirPath(String);
+
+impl VfsDirPath {
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    /// Doesn't mind a missing slash at the end or an extra one at the start, but
+    /// any name in it has to pass `check_name`
+    pub fn parse(path: &str) -> Result<Self> {
+        let names = path.strip_prefix('/').unwrap_or(path);
+        let names = names.strip_suffix('/').unwrap_or(names);
+        if names.is_empty() {
+            return Ok(Self::root());
+        }
+        for name in names.split('/') {
+            Self::check_name(name)?;
+        }
+        Ok(Self(format!("{}/", names)))
+    }
+
+    /// What a single file or dir can be called
+    pub fn check_name(name: &str) -> Result<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+            return Err(anyhow!("{:?} can't be the name of a file or dir", name));
+        }
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.split_terminator('/')
+    }
+
+    /// How many dirs down from the root this is, which is also how many slashes it has
+    pub fn depth(&self) -> usize {
+        self.names().count()
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.names().last()
+    }
+
+    pub fn parent(&self) -> Option<Self> {
+        let name = self.name()?;
+        Some(Self(self.0[..self.0.len() - name.len() - 1].to_string()))
+    }
+
+    pub fn join(&self, name: &str) -> Result<Self> {
+        Self::check_name(name)?;
+        Ok(Self(format!("{}{}/", self.0, name)))
+    }
+
+    /// Whether `other` is this dir or anywhere under it
+    pub fn contains(&self, other: &VfsDirPath) -> bool {
+        other.0.starts_with(&self.0)
+    }
+}
+
+impl fmt::Display for VfsDirPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for VfsDirPath {
+    type Error = anyhow::Error;
+
+    fn try_from(path: String) -> Result<Self> {
+        Self::parse(&path)
+    }
+}
+
+impl From<VfsDirPath> for String {
+    fn from(path: VfsDirPath) -> Self {
+        path.0
+    }
+}
+
+/// A file in the VFS, split the way `Files` stores it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VfsFilePath {
+    pub dir: VfsDirPath,
+    pub name: String,
+}
+
+impl VfsFilePath {
+    pub fn new(dir: VfsDirPath, name: &str) -> Result<Self> {
+        VfsDirPath::check_name(name)?;
+        Ok(Self {
+            dir,
+            name: name.to_string(),
+        })
+    }
+
+    /// Anything ending in a slash is a dir, so it won't parse as a file
+    pub fn parse(path: &str) -> Result<Self> {
+        let (dir, name) = match path.rfind('/') {
+            Some(i) => path.split_at(i + 1),
+            None => ("", path),
+        };
+        Self::new(VfsDirPath::parse(dir)?, name)
+    }
+}
+
+impl fmt::Display for VfsFilePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.dir, self.name)
+    }
+}
+
+#[cfg(test)]
+mod vfs_path_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn name() -> impl Strategy<Value = String> {
+        r"[^/\x00]{1,12}".prop_filter("Not a name", |n| n != "." && n != "..")
+    }
+
+    #[test]
+    fn paths_get_normalized() -> Result<()> {
+        assert!(VfsDirPath::parse("")?.is_root());
+        assert!(VfsDirPath::parse("/")?.is_root());
+        assert_eq!(VfsDirPath::parse("/beta/gamma")?.as_str(), "beta/gamma/");
+        assert!(VfsDirPath::parse("beta//gamma/").is_err());
+        assert!(VfsDirPath::parse("beta/../gamma/").is_err());
+        assert!(VfsFilePath::parse("beta/").is_err());
+        let file = VfsFilePath::parse("beta/gamma/abook1.m4b")?;
+        assert_eq!(file.dir.as_str(), "beta/gamma/");
+        assert_eq!(file.dir.parent(), Some(VfsDirPath::parse("beta")?));
+        assert_eq!(file.to_string(), "beta/gamma/abook1.m4b");
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn parsing_keeps_every_name(names in prop::collection::vec(name(), 0..6)) {
+            let dir = VfsDirPath::parse(&names.join("/")).unwrap();
+            prop_assert_eq!(dir.names().collect::<Vec<_>>(), names.iter().map(|n| n.as_str()).collect::<Vec<_>>());
+            prop_assert_eq!(dir.depth(), names.len());
+            prop_assert_eq!(VfsDirPath::parse(dir.as_str()).unwrap(), dir.clone());
+            if let Some(last) = names.last() {
+                let parent = dir.parent().unwrap();
+                prop_assert_eq!(parent.join(last).unwrap(), dir.clone());
+                prop_assert!(parent.contains(&dir));
+            }
+        }
+    }
+}
@@ -1,3 +1,158 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::Result;
+use oosikle_lib::db::changes::{ChangeBatch, ChangeFeed};
+use oosikle_lib::db::DB_INIT_SQL;
+use oosikle_lib::miko::Miko;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use std::{env, fs, thread};
+
+/// Where the webview in oosikle-bodgeri expects to find us
+const LISTEN_ON: &str = "127.0.0.1:8080";
+const WWW_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/www");
+/// How long an event stream can go quiet before we check the client is still there
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+/// Pushes every change batch to the client as a server-sent event, until it hangs up.
+/// Quiet streams get a comment now and then, so a client that's gone is noticed
+/// even if nothing changes.
+fn stream_changes(stream: &mut TcpStream, feed: &ChangeFeed) -> Result<()> {
+    let batches = feed.subscribe();
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
+    )?;
+    stream.flush()?;
+    loop {
+        match batches.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(batch) => write_change_event(stream, &batch)?,
+            Err(RecvTimeoutError::Timeout) => write_keepalive(stream)?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+fn write_keepalive(stream: &mut impl Write) -> Result<()> {
+    // Lines starting with a colon are comments, which EventSource ignores
+    stream.write_all(b":\n\n")?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn write_change_event(stream: &mut impl Write, batch: &ChangeBatch) -> Result<()> {
+    // serde_json never puts a raw newline in its output, so one data line is enough
+    write!(stream, "event: change\ndata: {}\n\n", serde_json::to_string(batch)?)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn serve_file(stream: &mut TcpStream, url_path: &str) -> Result<()> {
+    let relative = url_path.trim_start_matches('/');
+    let relative = if relative.is_empty() { "index.html" } else { relative };
+    let relative = Path::new(relative);
+    if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return respond(stream, "404 Not Found", "text/plain", b"Not found");
+    }
+    let path = PathBuf::from(WWW_DIR).join(relative);
+    match fs::read(&path) {
+        Ok(body) => respond(stream, "200 OK", content_type(&path), &body),
+        Err(_) => respond(stream, "404 Not Found", "text/plain", b"Not found"),
+    }
+}
+
+fn handle(mut stream: TcpStream, feed: ChangeFeed) -> Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return respond(&mut stream, "400 Bad Request", "text/plain", b"Bad request");
+    };
+    if method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"GET only");
+    }
+    let url_path = target.split('?').next().unwrap_or(target);
+    match url_path {
+        "/changes" => stream_changes(&mut stream, &feed),
+        _ => serve_file(&mut stream, url_path),
+    }
+}
+
+fn main() -> Result<()> {
+    let db_loc = env::args().nth(1).unwrap_or_else(|| "oosikle.sqlite3".into());
+    let (miko, _destroyer) = Miko::construct_connection_shrine(db_loc.into(), DB_INIT_SQL)?;
+    let feed = ChangeFeed::attach(&miko)?;
+    let listener = TcpListener::bind(LISTEN_ON)?;
+    println!("Listening on http://{}", LISTEN_ON);
+    for stream in listener.incoming() {
+        // Usually the client giving up before we got to it, which shouldn't stop everyone else
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Couldn't accept a connection: {}", e);
+                continue;
+            }
+        };
+        let feed = feed.clone();
+        thread::spawn(move || {
+            // Clients hanging up mid-stream is how event streams normally end
+            let _ = handle(stream, feed);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use oosikle_lib::db::changes::{ChangeOp, TableChange};
+
+    #[test]
+    fn change_events_are_one_sse_message() -> Result<()> {
+        let batch = ChangeBatch {
+            changes: vec![TableChange {
+                table: "Collections".into(),
+                op: ChangeOp::Update,
+                key_columns: vec!["collection_uuid".into()],
+                keys: vec![vec!["BADC0FFEE0DDF00DBADC0FFEE0DDF00D".into()]],
+            }],
+        };
+        let mut out = vec![];
+        write_change_event(&mut out, &batch)?;
+        let out = String::from_utf8(out)?;
+        assert!(out.starts_with("event: change\ndata: {"));
+        assert!(out.ends_with("}\n\n"));
+        assert_eq!(out.lines().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn keepalives_are_comments() -> Result<()> {
+        let mut out = vec![];
+        write_keepalive(&mut out)?;
+        assert_eq!(out, b":\n\n");
+        Ok(())
+    }
 }
@@ -0,0 +1,6 @@
+// Library changes get pushed from /changes. Anything that shows library data can
+// refresh itself with hx-trigger="oosikle:change from:body".
+const changes = new EventSource("/changes");
+changes.addEventListener("change", (event) => {
+    document.body.dispatchEvent(new CustomEvent("oosikle:change", { detail: JSON.parse(event.data) }));
+});